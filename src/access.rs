@@ -0,0 +1,137 @@
+//! Declarations of the keys a system reads and writes.
+//!
+//! Schedulers can compare the declared accesses of two systems to decide
+//! whether they may run at the same time.
+
+use std::collections::HashSet;
+use std::intrinsics::TypeId;
+
+use super::{Assoc, Res, ResMut};
+
+/// The set of keys read and written by a system.
+#[deriving(Clone)]
+pub struct Access {
+    reads: HashSet<TypeId>,
+    writes: HashSet<TypeId>
+}
+
+/// A type which declares accesses, such as a `Res`, a `ResMut`, or a tuple
+/// of up to eight of them.
+pub trait Declare {
+    /// Add the accesses of the type to an Access.
+    fn declare(_: Option<Self>, access: &mut Access);
+}
+
+impl Access {
+    /// Create an Access which reads and writes nothing.
+    pub fn new() -> Access {
+        Access {
+            reads: HashSet::new(),
+            writes: HashSet::new()
+        }
+    }
+
+    /// Create an Access from the borrows a system fetches, such as a
+    /// `(Res<A, uint>, ResMut<B, String>)`.
+    pub fn of<D: Declare>() -> Access {
+        let mut access = Access::new();
+        Declare::declare(None::<D>, &mut access);
+        access
+    }
+
+    /// Declare a read of a key.
+    pub fn read<K: Assoc<V>, V: 'static>(mut self) -> Access {
+        self.reads.insert(TypeId::of::<K>());
+        self
+    }
+
+    /// Declare a write of a key.
+    pub fn write<K: Assoc<V>, V: 'static>(mut self) -> Access {
+        self.writes.insert(TypeId::of::<K>());
+        self
+    }
+
+    /// Check if a key is read, or written, by this Access.
+    pub fn reads<K: Assoc<V>, V: 'static>(&self) -> bool {
+        let id = TypeId::of::<K>();
+        self.reads.contains(&id) || self.writes.contains(&id)
+    }
+
+    /// Check if a key is written by this Access.
+    pub fn writes<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.writes.contains(&TypeId::of::<K>())
+    }
+
+    /// Check if two accesses conflict, which is when either writes a key
+    /// the other reads or writes.
+    ///
+    /// Systems whose accesses do not conflict can safely run in parallel.
+    pub fn conflicts_with(&self, other: &Access) -> bool {
+        self.writes.iter().any(|id| other.reads.contains(id) || other.writes.contains(id))
+            || other.writes.iter().any(|id| self.reads.contains(id))
+    }
+}
+
+impl<'a, K: Assoc<V>, V: 'static> Declare for Res<'a, K, V> {
+    fn declare(_: Option<Res<'a, K, V>>, access: &mut Access) {
+        access.reads.insert(TypeId::of::<K>());
+    }
+}
+
+impl<'a, K: Assoc<V>, V: 'static> Declare for ResMut<'a, K, V> {
+    fn declare(_: Option<ResMut<'a, K, V>>, access: &mut Access) {
+        access.writes.insert(TypeId::of::<K>());
+    }
+}
+
+macro_rules! tuple_declare {
+    ($($access:ident),+) => (
+        impl<$($access: Declare),+> Declare for ($($access,)+) {
+            fn declare(_: Option<($($access,)+)>, access: &mut Access) {
+                $(Declare::declare(None::<$access>, access);)+
+            }
+        }
+    )
+}
+
+tuple_declare!(A)
+tuple_declare!(A, B)
+tuple_declare!(A, B, C)
+tuple_declare!(A, B, C, D)
+tuple_declare!(A, B, C, D, E)
+tuple_declare!(A, B, C, D, E, F)
+tuple_declare!(A, B, C, D, E, F, G)
+tuple_declare!(A, B, C, D, E, F, G, H)
+
+#[cfg(test)]
+mod test {
+    use super::Access;
+    use {Assoc, Res, ResMut};
+
+    struct Time;
+
+    struct Score;
+
+    impl Assoc<uint> for Time {}
+    impl Assoc<uint> for Score {}
+
+    #[test] fn test_conflicts() {
+        let readers = Access::new().read::<Time, uint>();
+        assert!(!readers.conflicts_with(&readers));
+
+        let writer = Access::new().read::<Time, uint>().write::<Score, uint>();
+        assert!(!readers.conflicts_with(&writer));
+        assert!(writer.conflicts_with(&writer));
+
+        let clock = Access::new().write::<Time, uint>();
+        assert!(clock.conflicts_with(&readers));
+        assert!(readers.conflicts_with(&clock));
+    }
+
+    #[test] fn test_of_fetched_borrows() {
+        let access = Access::of::<(Res<Time, uint>, ResMut<Score, uint>)>();
+        assert!(access.reads::<Time, uint>());
+        assert!(!access.writes::<Time, uint>());
+        assert!(access.writes::<Score, uint>());
+    }
+}
@@ -0,0 +1,183 @@
+//! A TypeMap identifying keys by the address of a static, instead of by
+//! hashing their TypeId.
+//!
+//! Keys are declared with the `static_key!` macro, which gives each of them
+//! a `KeyAddress` static. Entries are kept in a small vector and found by
+//! comparing addresses, which is faster than hashing for the handful of
+//! entries this map is meant for.
+//!
+//! Nothing stops two keys from sharing an address, through a hand-written
+//! `StaticKey` impl or statics the linker merges, so entries also hold the
+//! TypeId of their value, and a key only finds values of its own value
+//! type.
+
+use std::any::Any;
+use std::intrinsics::TypeId;
+
+use downcast::{UncheckedAnyDowncast, UncheckedAnyMutDowncast};
+
+use super::Assoc;
+
+/// A static whose address identifies a key.
+pub struct KeyAddress {
+    /// The path of the key type.
+    pub name: &'static str
+}
+
+/// A key with a `KeyAddress`, usually implemented through `static_key!`.
+pub trait StaticKey<V: 'static>: Assoc<V> {
+    /// Get the key's address, which should not be shared with other keys.
+    /// Keys sharing it see each other's values if they have the same value
+    /// type.
+    fn address(_: Option<Self>) -> &'static KeyAddress;
+}
+
+/// A TypeMap whose keys are identified by their `KeyAddress`.
+pub struct AddressTypeMap {
+    // The address of each entry's key and the TypeId of its value.
+    entries: Vec<(uint, TypeId, Box<Any + 'static>)>
+}
+
+impl AddressTypeMap {
+    /// Create a new, empty AddressTypeMap.
+    pub fn new() -> AddressTypeMap {
+        AddressTypeMap { entries: Vec::new() }
+    }
+
+    /// Insert a value into the map with a specified key type.
+    pub fn insert<K: StaticKey<V>, V: 'static>(&mut self, val: V) -> bool {
+        let val = box val as Box<Any + 'static>;
+        match self.position::<K, V>() {
+            Some(index) => {
+                let (_, _, ref mut slot) = self.entries[index];
+                *slot = val;
+                false
+            },
+            None => {
+                self.entries.push((address::<K, V>(), TypeId::of::<V>(), val));
+                true
+            }
+        }
+    }
+
+    /// Find a value in the map and get a reference to it.
+    pub fn find<K: StaticKey<V>, V: 'static>(&self) -> Option<&V> {
+        match self.position::<K, V>() {
+            Some(index) => {
+                let (_, _, ref val) = self.entries[index];
+                Some(unsafe { val.downcast_ref_unchecked::<V>() })
+            },
+            None => None
+        }
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    pub fn find_mut<K: StaticKey<V>, V: 'static>(&mut self) -> Option<&mut V> {
+        match self.position::<K, V>() {
+            Some(index) => {
+                let (_, _, ref mut val) = self.entries[index];
+                Some(unsafe { val.downcast_mut_unchecked::<V>() })
+            },
+            None => None
+        }
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: StaticKey<V>, V: 'static>(&self) -> bool {
+        self.position::<K, V>().is_some()
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: StaticKey<V>, V: 'static>(&mut self) -> bool {
+        match self.position::<K, V>() {
+            Some(index) => { self.entries.swap_remove(index); true },
+            None => false
+        }
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove all entries from the map.
+    pub fn clear(&mut self) {
+        self.entries.clear()
+    }
+
+    // Values are only downcast to V if the TypeId of V matches, so keys
+    // sharing an address cannot see values of another type.
+    fn position<K: StaticKey<V>, V: 'static>(&self) -> Option<uint> {
+        let (address, value) = (address::<K, V>(), TypeId::of::<V>());
+        self.entries.iter().position(|&(other, other_value, _)| other == address && other_value == value)
+    }
+}
+
+fn address<K: StaticKey<V>, V: 'static>() -> uint {
+    StaticKey::address(None::<K>) as *const KeyAddress as uint
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AddressTypeMap, KeyAddress, StaticKey};
+    use Assoc;
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct OtherKey;
+
+    #[deriving(Show, PartialEq)]
+    struct SharedKey;
+
+    #[deriving(Show, PartialEq)]
+    struct CollidingKey;
+
+    static_key!(Key => uint)
+    static_key!(OtherKey => uint)
+
+    static SHARED: KeyAddress = KeyAddress { name: "shared" };
+
+    impl Assoc<&'static str> for SharedKey {}
+
+    impl StaticKey<&'static str> for SharedKey {
+        fn address(_: Option<SharedKey>) -> &'static KeyAddress { &SHARED }
+    }
+
+    impl Assoc<uint> for CollidingKey {}
+
+    impl StaticKey<uint> for CollidingKey {
+        fn address(_: Option<CollidingKey>) -> &'static KeyAddress { &SHARED }
+    }
+
+    #[test] fn test_address_keys() {
+        let mut map = AddressTypeMap::new();
+        assert!(map.insert::<Key, uint>(1));
+        assert!(map.insert::<OtherKey, uint>(2));
+        assert!(!map.insert::<Key, uint>(3));
+        assert_eq!(*map.find::<Key, uint>().unwrap(), 3);
+        *map.find_mut::<OtherKey, uint>().unwrap() += 1;
+        assert_eq!(*map.find::<OtherKey, uint>().unwrap(), 3);
+
+        assert!(map.remove::<Key, uint>());
+        assert!(!map.contains::<Key, uint>());
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test] fn test_shared_address() {
+        let mut map = AddressTypeMap::new();
+        map.insert::<SharedKey, &'static str>("shared");
+        assert!(!map.contains::<CollidingKey, uint>());
+        assert!(map.insert::<CollidingKey, uint>(1));
+        assert_eq!(*map.find::<SharedKey, &'static str>().unwrap(), "shared");
+        assert_eq!(*map.find::<CollidingKey, uint>().unwrap(), 1);
+    }
+}
@@ -0,0 +1,126 @@
+//! A TypeMap backed by a BTreeMap, for deterministic iteration order.
+
+use std::any::Any;
+use std::intrinsics::TypeId;
+use std::vec;
+
+use storage::BTreeStorage;
+
+use super::{TypeMap, Assoc};
+
+/// A TypeMap which iterates over its entries in the order of the names of
+/// their key types.
+///
+/// The order only depends on the set of keys in the map, not on insertion
+/// order, hashing or TypeIds, so it is the same on every run and in every
+/// build of a program.
+pub struct BTreeTypeMap {
+    map: TypeMap<BTreeStorage>
+}
+
+/// An iterator over the entries of a BTreeTypeMap.
+pub struct Entries<'a> {
+    inner: vec::MoveItems<(TypeId, &'a (Any + 'static))>
+}
+
+impl BTreeTypeMap {
+    /// Create a new, empty BTreeTypeMap.
+    pub fn new() -> BTreeTypeMap {
+        BTreeTypeMap { map: TypeMap::with_storage(BTreeStorage::new()) }
+    }
+
+    /// Insert a value into the map with a specified key type.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        self.map.insert::<K, V>(val)
+    }
+
+    /// Find a value in the map and get a reference to it.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        self.map.find::<K, V>()
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    pub fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> {
+        self.map.find_mut::<K, V>()
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.map.contains::<K, V>()
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        self.map.remove::<K, V>()
+    }
+
+    /// Iterate over the keys and values of the map, in order of key type
+    /// name.
+    pub fn iter<'a>(&'a self) -> Entries<'a> {
+        Entries { inner: self.map.iter_ordered() }
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> uint {
+        self.map.len()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Remove all entries from the map.
+    pub fn clear(&mut self) {
+        self.map.clear()
+    }
+}
+
+impl<'a> Iterator<(TypeId, &'a (Any + 'static))> for Entries<'a> {
+    fn next(&mut self) -> Option<(TypeId, &'a (Any + 'static))> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::intrinsics::TypeId;
+    use super::BTreeTypeMap;
+    use Assoc;
+
+    struct First;
+    struct Second;
+    struct Third;
+
+    impl Assoc<uint> for First {}
+    impl Assoc<uint> for Second {}
+    impl Assoc<uint> for Third {}
+
+    fn ids(map: &BTreeTypeMap) -> Vec<TypeId> {
+        map.iter().map(|(id, _)| id).collect()
+    }
+
+    #[test] fn test_deterministic_order() {
+        let mut forward = BTreeTypeMap::new();
+        forward.insert::<First, uint>(1);
+        forward.insert::<Second, uint>(2);
+        forward.insert::<Third, uint>(3);
+
+        let mut backward = BTreeTypeMap::new();
+        backward.insert::<Third, uint>(3);
+        backward.insert::<Second, uint>(2);
+        backward.insert::<First, uint>(1);
+
+        assert_eq!(ids(&forward), ids(&backward));
+        assert_eq!(ids(&forward), vec![TypeId::of::<First>(), TypeId::of::<Second>(), TypeId::of::<Third>()]);
+        assert_eq!(*forward.find::<Second, uint>().unwrap(), 2);
+        assert!(forward.remove::<Second, uint>());
+        assert_eq!(forward.len(), 2);
+    }
+}
@@ -0,0 +1,109 @@
+//! Groups of keys which can be counted, iterated and cleared together.
+//!
+//! A category is any marker type. Keys are added to categories with
+//! `TypeMap::set_category`, and a key can be in several categories.
+
+use std::any::Any;
+use std::collections::{hashmap, HashMap};
+use std::intrinsics::TypeId;
+use std::slice;
+
+use super::{TypeMap, Assoc, Storage};
+
+/// An iterator over the entries of a category in a TypeMap.
+pub struct CategoryEntries<'a, S: 'a> {
+    keys: slice::Items<'a, TypeId>,
+    data: &'a S
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Add a key to the category `C`.
+    pub fn set_category<K: Assoc<V>, V: 'static, C: 'static>(&mut self) {
+        let id = self.slot::<K>();
+        let keys = match self.categories.entry(TypeId::of::<C>()) {
+            hashmap::Occupied(e) => e.into_mut(),
+            hashmap::Vacant(e) => e.set(Vec::new())
+        };
+        if !keys.contains(&id) { keys.push(id) }
+    }
+
+    /// Get the number of values stored for keys in the category `C`.
+    pub fn len_of<C: 'static>(&self) -> uint {
+        self.category::<C>().iter().filter(|id| self.data.contains(*id)).count()
+    }
+
+    /// Remove the values of all keys in the category `C`, leaving the
+    /// others. Frozen values are not removed.
+    ///
+    /// Returns the number of values removed.
+    pub fn clear_category<C: 'static>(&mut self) -> uint {
+        let keys = self.category::<C>().to_vec();
+        let mut removed = 0;
+        for id in keys.iter() {
+            if self.evict_id(*id).is_some() { removed += 1 }
+        }
+        self.debug_check();
+        removed
+    }
+
+    /// Iterate over the keys and values stored for keys in the category `C`.
+    pub fn iter_category<'a, C: 'static>(&'a self) -> CategoryEntries<'a, S> {
+        CategoryEntries { keys: self.category::<C>().iter(), data: &self.data }
+    }
+
+    fn category<C: 'static>(&self) -> &[TypeId] {
+        match self.categories.find(&TypeId::of::<C>()) {
+            Some(keys) => keys.as_slice(),
+            None => &[]
+        }
+    }
+}
+
+impl<'a, S: Storage> Iterator<(TypeId, &'a (Any + 'static))> for CategoryEntries<'a, S> {
+    fn next(&mut self) -> Option<(TypeId, &'a (Any + 'static))> {
+        for id in self.keys.by_ref() {
+            match self.data.find(id) {
+                Some(val) => return Some((*id, &**val)),
+                None => ()
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::intrinsics::TypeId;
+    use {TypeMap, Assoc};
+
+    struct Metrics;
+
+    struct Requests;
+
+    struct Errors;
+
+    struct Session;
+
+    impl Assoc<uint> for Requests {}
+    impl Assoc<uint> for Errors {}
+    impl Assoc<uint> for Session {}
+
+    #[test] fn test_categories() {
+        let mut map = TypeMap::new();
+        map.set_category::<Requests, uint, Metrics>();
+        map.set_category::<Errors, uint, Metrics>();
+        map.set_category::<Errors, uint, Metrics>();
+        map.insert::<Requests, uint>(10);
+        map.insert::<Session, uint>(1);
+        assert_eq!(map.len_of::<Metrics>(), 1);
+
+        map.insert::<Errors, uint>(2);
+        let ids: Vec<TypeId> = map.iter_category::<Metrics>().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![TypeId::of::<Requests>(), TypeId::of::<Errors>()]);
+
+        assert_eq!(map.clear_category::<Metrics>(), 2);
+        assert_eq!(map.len_of::<Metrics>(), 0);
+        assert!(map.contains::<Session, uint>());
+        assert_eq!(map.iter_category::<Session>().count(), 0);
+    }
+}
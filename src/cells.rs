@@ -0,0 +1,104 @@
+//! A map of `Copy` values which can be read and written through a shared
+//! reference.
+//!
+//! Each value is kept in its own `Cell`, so flags and counters can be
+//! updated by any code holding a `&CellMap`, with no borrows to track.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::intrinsics::TypeId;
+
+use downcast::UncheckedAnyDowncast;
+
+use super::Assoc;
+
+/// A map of `Copy` values, each in its own `Cell`.
+pub struct CellMap {
+    data: HashMap<TypeId, Box<Any + 'static>>
+}
+
+impl CellMap {
+    /// Create a new, empty CellMap.
+    pub fn new() -> CellMap {
+        CellMap { data: HashMap::new() }
+    }
+
+    /// Insert a value into the map with a specified key type.
+    pub fn insert<K: Assoc<V>, V: Copy + 'static>(&mut self, val: V) -> bool {
+        self.data.insert(TypeId::of::<K>(), box Cell::new(val) as Box<Any + 'static>)
+    }
+
+    /// Get a copy of the value of a key.
+    pub fn get_copy<K: Assoc<V>, V: Copy + 'static>(&self) -> Option<V> {
+        self.cell::<K, V>().map(|cell| cell.get())
+    }
+
+    /// Set the value of a key which already has one.
+    ///
+    /// This only needs a shared reference, since it cannot add an entry.
+    /// Returns `false`, storing nothing, if the key has no value.
+    pub fn set<K: Assoc<V>, V: Copy + 'static>(&self, val: V) -> bool {
+        match self.cell::<K, V>() {
+            Some(cell) => { cell.set(val); true },
+            None => false
+        }
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: Assoc<V>, V: Copy + 'static>(&self) -> bool {
+        self.data.contains_key(&TypeId::of::<K>())
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: Assoc<V>, V: Copy + 'static>(&mut self) -> bool {
+        self.data.remove(&TypeId::of::<K>())
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> uint {
+        self.data.len()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn cell<K: Assoc<V>, V: Copy + 'static>(&self) -> Option<&Cell<V>> {
+        self.data.find(&TypeId::of::<K>()).map(|val| unsafe {
+            val.downcast_ref_unchecked::<Cell<V>>()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CellMap;
+    use Assoc;
+
+    struct Requests;
+
+    struct Verbose;
+
+    impl Assoc<uint> for Requests {}
+    impl Assoc<bool> for Verbose {}
+
+    fn handle(map: &CellMap) {
+        let requests = map.get_copy::<Requests, uint>().unwrap();
+        map.set::<Requests, uint>(requests + 1);
+    }
+
+    #[test] fn test_shared_updates() {
+        let mut map = CellMap::new();
+        map.insert::<Requests, uint>(0);
+        handle(&map);
+        handle(&map);
+        assert_eq!(map.get_copy::<Requests, uint>(), Some(2));
+
+        assert!(!map.set::<Verbose, bool>(true));
+        assert_eq!(map.get_copy::<Verbose, bool>(), None);
+    }
+}
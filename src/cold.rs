@@ -0,0 +1,226 @@
+//! A TypeMap which compresses large values that are not being used.
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::intrinsics::TypeId;
+
+use downcast::UncheckedAnyDowncast;
+
+use super::{TypeMap, Assoc};
+
+/// How to measure, compress and decompress the values of a key.
+pub struct Codec<V> {
+    /// Values at least this large, as measured by `size`, are compressed
+    /// when they are cold.
+    pub threshold: uint,
+    /// Measure a value.
+    pub size: fn(&V) -> uint,
+    /// Compress a value.
+    pub encode: fn(&V) -> Vec<u8>,
+    /// Decompress a value compressed by `encode`.
+    pub decode: fn(&[u8]) -> V
+}
+
+/// A TypeMap which keeps large, cold values in compressed form.
+///
+/// A value is cold if it was not accessed since the previous call to
+/// `compress_cold`. Cold values of keys with a registered codec which are
+/// at least as large as the codec's threshold are compressed by
+/// `compress_cold`, and decompressed by the next lookup, which is why
+/// lookups need a mutable reference.
+pub struct ColdTypeMap {
+    map: TypeMap,
+    codecs: HashMap<TypeId, Erased>,
+    compressed: HashMap<TypeId, Vec<u8>>,
+    accessed: HashSet<TypeId>
+}
+
+struct Erased {
+    // The `Codec<V>` registered for the key.
+    codec: Box<Any + 'static>,
+    compress: fn(&Box<Any + 'static>, &Box<Any + 'static>) -> Option<Vec<u8>>,
+    decompress: fn(&Box<Any + 'static>, &[u8]) -> Box<Any + 'static>
+}
+
+impl ColdTypeMap {
+    /// Create a new, empty ColdTypeMap.
+    pub fn new() -> ColdTypeMap {
+        ColdTypeMap {
+            map: TypeMap::new(),
+            codecs: HashMap::new(),
+            compressed: HashMap::new(),
+            accessed: HashSet::new()
+        }
+    }
+
+    /// Register the codec used to compress the values of a key.
+    pub fn register_codec<K: Assoc<V>, V: 'static>(&mut self, codec: Codec<V>) {
+        self.codecs.insert(TypeId::of::<K>(), Erased {
+            codec: box codec as Box<Any + 'static>,
+            compress: compress::<V> as fn(&Box<Any + 'static>, &Box<Any + 'static>) -> Option<Vec<u8>>,
+            decompress: decompress::<V> as fn(&Box<Any + 'static>, &[u8]) -> Box<Any + 'static>
+        });
+    }
+
+    /// Insert a value into the map with a specified key type.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        let id = TypeId::of::<K>();
+        let was_compressed = self.compressed.remove(&id);
+        self.accessed.insert(id);
+        self.map.insert::<K, V>(val) && !was_compressed
+    }
+
+    /// Find a value in the map and get a reference to it, decompressing it
+    /// if it is compressed.
+    pub fn find<K: Assoc<V>, V: 'static>(&mut self) -> Option<&V> {
+        self.thaw(TypeId::of::<K>());
+        self.map.find::<K, V>()
+    }
+
+    /// Find a value in the map and get a mutable reference to it,
+    /// decompressing it if it is compressed.
+    pub fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> {
+        self.thaw(TypeId::of::<K>());
+        self.map.find_mut::<K, V>()
+    }
+
+    /// Check if a key has an associated value stored in the map, compressed
+    /// or not.
+    ///
+    /// This does not count as an access.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.is_compressed::<K, V>() || self.map.contains::<K, V>()
+    }
+
+    /// Check if the value of a key is currently compressed.
+    pub fn is_compressed<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.compressed.contains_key(&TypeId::of::<K>())
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        let id = TypeId::of::<K>();
+        self.accessed.remove(&id);
+        self.compressed.remove(&id) | self.map.remove::<K, V>()
+    }
+
+    /// Compress the values which were not accessed since the previous call,
+    /// if their key has a codec and they are large enough.
+    ///
+    /// Returns the number of values compressed.
+    pub fn compress_cold(&mut self) -> uint {
+        let mut frozen = Vec::new();
+        for (id, erased) in self.codecs.iter() {
+            if self.accessed.contains(id) { continue }
+            match unsafe { self.map.data() }.find(id) {
+                Some(val) => match (erased.compress)(&erased.codec, val) {
+                    Some(bytes) => frozen.push((*id, bytes)),
+                    None => ()
+                },
+                None => ()
+            }
+        }
+
+        for &(id, _) in frozen.iter() {
+            unsafe { self.map.data_mut().remove(&id); }
+        }
+        let count = frozen.len();
+        self.compressed.extend(frozen.into_iter());
+        self.accessed.clear();
+        count
+    }
+
+    /// Get the total size of the compressed values.
+    pub fn compressed_bytes(&self) -> uint {
+        self.compressed.values().fold(0, |total, bytes| total + bytes.len())
+    }
+
+    /// Get the number of values stored in the map, compressed or not.
+    pub fn len(&self) -> uint {
+        self.map.len() + self.compressed.len()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all entries from the map.
+    pub fn clear(&mut self) {
+        self.compressed.clear();
+        self.accessed.clear();
+        self.map.clear()
+    }
+
+    fn thaw(&mut self, id: TypeId) {
+        self.accessed.insert(id);
+        let bytes = match self.compressed.pop(&id) {
+            Some(bytes) => bytes,
+            None => return
+        };
+        let erased = self.codecs.find(&id).unwrap();
+        let val = (erased.decompress)(&erased.codec, bytes.as_slice());
+        unsafe { self.map.data_mut().insert(id, val); }
+    }
+}
+
+fn compress<V: 'static>(codec: &Box<Any + 'static>, val: &Box<Any + 'static>) -> Option<Vec<u8>> {
+    let (codec, val) = unsafe {
+        ((&**codec).downcast_ref_unchecked::<Codec<V>>(), (&**val).downcast_ref_unchecked::<V>())
+    };
+    if (codec.size)(val) >= codec.threshold { Some((codec.encode)(val)) } else { None }
+}
+
+fn decompress<V: 'static>(codec: &Box<Any + 'static>, bytes: &[u8]) -> Box<Any + 'static> {
+    let codec = unsafe { (&**codec).downcast_ref_unchecked::<Codec<V>>() };
+    box (codec.decode)(bytes) as Box<Any + 'static>
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ColdTypeMap, Codec};
+    use Assoc;
+
+    struct Blob;
+
+    struct Small;
+
+    impl Assoc<Vec<u8>> for Blob {}
+    impl Assoc<Vec<u8>> for Small {}
+
+    // Run-length encode runs of one byte value.
+    fn encode(val: &Vec<u8>) -> Vec<u8> {
+        vec![val.len() as u8, val[0]]
+    }
+
+    fn decode(bytes: &[u8]) -> Vec<u8> {
+        Vec::from_elem(bytes[0] as uint, bytes[1])
+    }
+
+    fn size(val: &Vec<u8>) -> uint { val.len() }
+
+    fn codec() -> Codec<Vec<u8>> {
+        Codec { threshold: 10, size: size, encode: encode, decode: decode }
+    }
+
+    #[test] fn test_compress_cold() {
+        let mut map = ColdTypeMap::new();
+        map.register_codec::<Blob, Vec<u8>>(codec());
+        map.register_codec::<Small, Vec<u8>>(codec());
+        map.insert::<Blob, Vec<u8>>(Vec::from_elem(100, 7));
+        map.insert::<Small, Vec<u8>>(vec![1]);
+
+        assert_eq!(map.compress_cold(), 0);
+        assert_eq!(map.compress_cold(), 1);
+        assert!(map.is_compressed::<Blob, Vec<u8>>());
+        assert!(!map.is_compressed::<Small, Vec<u8>>());
+        assert_eq!(map.compressed_bytes(), 2);
+        assert_eq!(map.len(), 2);
+
+        assert_eq!(*map.find::<Blob, Vec<u8>>().unwrap(), Vec::from_elem(100, 7));
+        assert!(!map.is_compressed::<Blob, Vec<u8>>());
+        assert_eq!(map.compress_cold(), 0);
+    }
+}
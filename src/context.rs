@@ -0,0 +1,130 @@
+//! Errors carrying a TypeMap of typed context.
+//!
+//! Failure sites attach context to an error with `ResultContext::context`
+//! or `ErrorContext::with`, and reporting sites retrieve it by key with
+//! `ErrorContext::find`, or by type through the `Provider` protocol.
+
+use std::fmt;
+
+use super::{TypeMap, Assoc};
+use provide::{Provider, Demand};
+
+/// An error together with a TypeMap of context about it.
+///
+/// TypeMap is not Send, so an ErrorContext does not implement `Error`
+/// itself; the wrapped error is available through `error`.
+pub struct ErrorContext<E> {
+    error: E,
+    context: TypeMap
+}
+
+impl<E> ErrorContext<E> {
+    /// Wrap an error, with no context yet.
+    pub fn new(error: E) -> ErrorContext<E> {
+        ErrorContext { error: error, context: TypeMap::new() }
+    }
+
+    /// Attach context to the error, replacing any previous value of the key.
+    pub fn with<K: Assoc<V>, V: 'static>(mut self, val: V) -> ErrorContext<E> {
+        self.context.insert::<K, V>(val);
+        self
+    }
+
+    /// Attach context to the error, returning `false` if the key already had
+    /// a value, which is replaced.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        self.context.insert::<K, V>(val)
+    }
+
+    /// Find the context stored for a key.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        self.context.find::<K, V>()
+    }
+
+    /// Get the wrapped error.
+    pub fn error(&self) -> &E {
+        &self.error
+    }
+
+    /// Get the map of context.
+    pub fn context(&self) -> &TypeMap {
+        &self.context
+    }
+
+    /// Get the map of context mutably.
+    pub fn context_mut(&mut self) -> &mut TypeMap {
+        &mut self.context
+    }
+
+    /// Unwrap the error, dropping the context.
+    pub fn into_inner(self) -> E {
+        self.error
+    }
+}
+
+impl<E: fmt::Show> fmt::Show for ErrorContext<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} context entries)", self.error, self.context.len())
+    }
+}
+
+/// An ErrorContext provides a reference to its error, then to its context.
+impl<E: 'static> Provider for ErrorContext<E> {
+    fn provide<'a>(&'a self, demand: &mut Demand<'a>) {
+        demand.provide_ref(&self.error);
+        self.context.provide(demand);
+    }
+}
+
+/// Attaching context to the error of a Result.
+pub trait ResultContext<T, E> {
+    /// Wrap the error, if any, in an ErrorContext holding `val` as the
+    /// value of `K`.
+    fn context<K: Assoc<V>, V: 'static>(self, val: V) -> Result<T, ErrorContext<E>>;
+
+    /// Like `context`, but computes the value only if there is an error.
+    fn with_context<K: Assoc<V>, V: 'static>(self, f: || -> V) -> Result<T, ErrorContext<E>>;
+}
+
+impl<T, E> ResultContext<T, E> for Result<T, E> {
+    fn context<K: Assoc<V>, V: 'static>(self, val: V) -> Result<T, ErrorContext<E>> {
+        self.map_err(|error| ErrorContext::new(error).with::<K, V>(val))
+    }
+
+    fn with_context<K: Assoc<V>, V: 'static>(self, f: || -> V) -> Result<T, ErrorContext<E>> {
+        match self {
+            Ok(val) => Ok(val),
+            Err(error) => Err(ErrorContext::new(error).with::<K, V>(f()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ErrorContext, ResultContext};
+    use provide::request_ref;
+    use Assoc;
+
+    struct Path;
+
+    struct Attempt;
+
+    impl Assoc<String> for Path {}
+    impl Assoc<uint> for Attempt {}
+
+    fn open(path: &str) -> Result<(), ErrorContext<&'static str>> {
+        let result: Result<(), &'static str> = Err("not found");
+        result.context::<Path, String>(path.to_string())
+    }
+
+    #[test] fn test_error_context() {
+        let err = open("/etc/typemap").unwrap_err().with::<Attempt, uint>(3);
+
+        assert_eq!(*err.error(), "not found");
+        assert_eq!(err.find::<Path, String>(), Some(&"/etc/typemap".to_string()));
+        assert_eq!(err.find::<Attempt, uint>(), Some(&3));
+        assert_eq!(request_ref::<uint, ErrorContext<&'static str>>(&err), Some(&3));
+        assert_eq!(err.to_string(), "not found (2 context entries)".to_string());
+        assert_eq!(err.into_inner(), "not found");
+    }
+}
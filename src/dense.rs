@@ -0,0 +1,214 @@
+//! A TypeMap storing values in a vector indexed by pre-registered keys.
+//!
+//! Keys are registered once with a `DenseRegistry`, which hands out a small
+//! `DenseKey` handle for each of them. Lookups through a handle are a
+//! bounds-checked vector index rather than a hash probe.
+
+use std::any::Any;
+use std::collections::{hashmap, HashMap};
+use std::intrinsics::TypeId;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, Relaxed};
+
+use downcast::{UncheckedAnyDowncast, UncheckedAnyMutDowncast};
+
+use super::Assoc;
+
+static NEXT_REGISTRY: AtomicUint = INIT_ATOMIC_UINT;
+
+/// A registry assigning a dense index to each key type.
+pub struct DenseRegistry {
+    id: uint,
+    indices: HashMap<TypeId, uint>
+}
+
+/// A handle to a key registered with a DenseRegistry.
+///
+/// A handle can only be used with maps built by the same registry.
+pub struct DenseKey<K, V> {
+    registry: uint,
+    index: uint
+}
+
+/// A map storing one value for each key registered with a DenseRegistry.
+pub struct DenseTypeMap {
+    registry: uint,
+    values: Vec<Option<Box<Any + 'static>>>,
+    len: uint
+}
+
+impl DenseRegistry {
+    /// Create a new registry with no keys.
+    pub fn new() -> DenseRegistry {
+        DenseRegistry {
+            id: NEXT_REGISTRY.fetch_add(1, Relaxed),
+            indices: HashMap::new()
+        }
+    }
+
+    /// Register a key, getting a handle to use to access its values.
+    ///
+    /// Registering the same key again returns a handle to the same index.
+    pub fn register<K: Assoc<V>, V: 'static>(&mut self) -> DenseKey<K, V> {
+        let next = self.indices.len();
+        let index = match self.indices.entry(TypeId::of::<K>()) {
+            hashmap::Occupied(e) => *e.get(),
+            hashmap::Vacant(e) => *e.set(next)
+        };
+        DenseKey { registry: self.id, index: index }
+    }
+
+    /// Get the number of registered keys.
+    pub fn len(&self) -> uint {
+        self.indices.len()
+    }
+
+    /// Create an empty map with a slot for every registered key.
+    ///
+    /// Keys registered after the map is built can still be used with it.
+    pub fn build(&self) -> DenseTypeMap {
+        DenseTypeMap {
+            registry: self.id,
+            values: Vec::from_fn(self.len(), |_| None),
+            len: 0
+        }
+    }
+}
+
+impl<K, V> Clone for DenseKey<K, V> {
+    fn clone(&self) -> DenseKey<K, V> {
+        DenseKey { registry: self.registry, index: self.index }
+    }
+}
+
+impl<K, V> DenseKey<K, V> {
+    /// Get the index of the key's slot.
+    pub fn index(&self) -> uint {
+        self.index
+    }
+}
+
+impl DenseTypeMap {
+    /// Insert a value into the map with a registered key.
+    ///
+    /// Panics if the key was registered with a different registry.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, key: &DenseKey<K, V>, val: V) -> bool {
+        self.check(key);
+        if key.index >= self.values.len() {
+            let missing = key.index + 1 - self.values.len();
+            self.values.grow_fn(missing, |_| None);
+        }
+
+        let slot = &mut self.values[key.index];
+        let new = slot.is_none();
+        if new { self.len += 1 }
+        *slot = Some(box val as Box<Any>);
+        new
+    }
+
+    /// Find a value in the map and get a reference to it.
+    ///
+    /// Panics if the key was registered with a different registry.
+    pub fn find<K: Assoc<V>, V: 'static>(&self, key: &DenseKey<K, V>) -> Option<&V> {
+        self.check(key);
+        match self.values.get(key.index) {
+            Some(&Some(ref v)) => Some(unsafe { v.downcast_ref_unchecked::<V>() }),
+            _ => None
+        }
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    ///
+    /// Panics if the key was registered with a different registry.
+    pub fn find_mut<K: Assoc<V>, V: 'static>(&mut self, key: &DenseKey<K, V>) -> Option<&mut V> {
+        self.check(key);
+        match self.values.get_mut(key.index) {
+            Some(slot) => slot.as_mut().map(|v| unsafe { v.downcast_mut_unchecked::<V>() }),
+            None => None
+        }
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    ///
+    /// Panics if the key was registered with a different registry.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self, key: &DenseKey<K, V>) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed. Panics if the key was
+    /// registered with a different registry.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self, key: &DenseKey<K, V>) -> bool {
+        self.check(key);
+        let removed = match self.values.get_mut(key.index) {
+            Some(slot) => slot.take().is_some(),
+            None => false
+        };
+        if removed { self.len -= 1 }
+        removed
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> uint {
+        self.len
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Remove all values from the map, keeping a slot for every key.
+    pub fn clear(&mut self) {
+        for slot in self.values.iter_mut() { *slot = None }
+        self.len = 0;
+    }
+
+    fn check<K, V>(&self, key: &DenseKey<K, V>) {
+        assert!(key.registry == self.registry,
+                "DenseKey used with a map built by a different registry");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DenseRegistry;
+    use Assoc;
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct Late;
+
+    #[deriving(Show, PartialEq)]
+    struct Value;
+
+    impl Assoc<Value> for Key {}
+    impl Assoc<uint> for Late {}
+
+    #[test] fn test_dense() {
+        let mut registry = DenseRegistry::new();
+        let key = registry.register::<Key, Value>();
+        assert_eq!(registry.register::<Key, Value>().index(), key.index());
+
+        let mut map = registry.build();
+        assert!(map.insert(&key, Value));
+        assert_eq!(*map.find(&key).unwrap(), Value);
+
+        let late = registry.register::<Late, uint>();
+        assert!(map.find(&late).is_none());
+        map.insert(&late, 1);
+        assert_eq!(map.len(), 2);
+
+        assert!(map.remove(&key));
+        assert!(!map.contains(&key));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test] #[should_fail] fn test_foreign_key() {
+        let mut registry = DenseRegistry::new();
+        let key = registry.register::<Key, Value>();
+        DenseRegistry::new().build().find(&key);
+    }
+}
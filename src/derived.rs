@@ -0,0 +1,135 @@
+//! Derived keys, whose values are computed from the values of other keys
+//! and cached until one of those changes.
+//!
+//! A derived value is dropped when one of its inputs is inserted, borrowed
+//! mutably or removed, by any method of the map, and recomputed by the next
+//! `find_derived`. Dropping it counts as a removal of the derived key, in
+//! statistics and modified entries alike, except that the value of a
+//! frozen derived key is kept.
+
+use std::any::Any;
+use std::collections::hashmap;
+use std::intrinsics::TypeId;
+
+use downcast::UncheckedAnyDowncast;
+
+use super::{TypeMap, Assoc, Storage};
+
+impl<S: Storage + 'static> TypeMap<S> {
+    /// Make `K` a derived key, computed by `compute` from the values of the
+    /// key types in `inputs`.
+    ///
+    /// Any value already stored for `K` is dropped, so the next lookup
+    /// computes it.
+    pub fn derive<K: Assoc<V>, V: 'static>(&mut self, inputs: &[TypeId], compute: fn(&TypeMap<S>) -> V) {
+        let id = self.slot::<K>();
+        self.derivations.insert(id, box compute as Box<Any + 'static>);
+        for input in inputs.iter() {
+            let input = self.resolve(*input);
+            let dependents = match self.dependents.entry(input) {
+                hashmap::Occupied(e) => e.into_mut(),
+                hashmap::Vacant(e) => e.set(Vec::new())
+            };
+            if !dependents.contains(&id) { dependents.push(id) }
+        }
+        self.remove::<K, V>();
+    }
+
+    /// Find the value of a derived key, computing it if it is not cached.
+    ///
+    /// Returns `None` only if `K` is not a derived key and has no value.
+    pub fn find_derived<K: Assoc<V>, V: 'static>(&mut self) -> Option<&V> {
+        let id = self.slot::<K>();
+        if !self.data.contains(&id) {
+            let compute = match self.derivations.find(&id) {
+                Some(compute) => *unsafe { (&**compute).downcast_ref_unchecked::<fn(&TypeMap<S>) -> V>() },
+                None => return None
+            };
+            let val = compute(&*self);
+            self.insert::<K, V>(val);
+        }
+        self.find::<K, V>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::intrinsics::TypeId;
+    use {TypeMap, Assoc};
+
+    struct Price;
+
+    struct Quantity;
+
+    struct Total;
+
+    struct Label;
+
+    impl Assoc<uint> for Price {}
+    impl Assoc<uint> for Quantity {}
+    impl Assoc<uint> for Total {}
+    impl Assoc<String> for Label {}
+
+    fn total(map: &TypeMap) -> uint {
+        *map.find::<Price, uint>().unwrap_or(&0) * *map.find::<Quantity, uint>().unwrap_or(&0)
+    }
+
+    fn label(map: &TypeMap) -> String {
+        format!("total {}", map.find::<Total, uint>())
+    }
+
+    #[test] fn test_derived() {
+        let mut map = TypeMap::new();
+        map.insert::<Price, uint>(3);
+        map.insert::<Quantity, uint>(2);
+        map.derive::<Total, uint>(&[TypeId::of::<Price>(), TypeId::of::<Quantity>()], total);
+        map.derive::<Label, String>(&[TypeId::of::<Total>()], label);
+        assert!(!map.contains::<Total, uint>());
+
+        assert_eq!(*map.find_derived::<Total, uint>().unwrap(), 6);
+        assert_eq!(map.find_derived::<Label, String>().unwrap().as_slice(), "total Some(6)");
+
+        *map.find_mut::<Quantity, uint>().unwrap() = 4;
+        assert!(!map.contains::<Total, uint>());
+        assert!(!map.contains::<Label, String>());
+        assert_eq!(*map.find_derived::<Total, uint>().unwrap(), 12);
+
+        map.remove::<Price, uint>();
+        assert_eq!(*map.find_derived::<Total, uint>().unwrap(), 0);
+        assert_eq!(map.find_derived::<Price, uint>(), None);
+    }
+
+    #[test] fn test_bulk_changes_invalidate() {
+        let mut map = TypeMap::new();
+        map.insert::<Price, uint>(3);
+        map.insert::<Quantity, uint>(2);
+        map.derive::<Total, uint>(&[TypeId::of::<Price>(), TypeId::of::<Quantity>()], total);
+        assert_eq!(*map.find_derived::<Total, uint>().unwrap(), 6);
+
+        let _: Result<(), ()> = map.scope(|m| { m.insert::<Price, uint>(4); Ok(()) });
+        assert_eq!(*map.find_derived::<Total, uint>().unwrap(), 8);
+
+        map.set_frame_scoped::<Quantity, uint>();
+        map.end_frame();
+        assert_eq!(*map.find_derived::<Total, uint>().unwrap(), 0);
+
+        {
+            let mut scoped = map.insert_scoped::<Quantity, uint>(5);
+            assert_eq!(*scoped.find_derived::<Total, uint>().unwrap(), 20);
+        }
+        assert_eq!(*map.find_derived::<Total, uint>().unwrap(), 0);
+    }
+
+    #[test] fn test_invalidation_bookkeeping() {
+        let mut map = TypeMap::new();
+        map.enable_stats();
+        map.insert::<Price, uint>(3);
+        map.derive::<Total, uint>(&[TypeId::of::<Price>()], total);
+        map.find_derived::<Total, uint>();
+        let removals = map.stats().unwrap().find(&TypeId::of::<Total>()).unwrap().removals;
+
+        map.insert::<Price, uint>(4);
+        assert_eq!(map.stats().unwrap().find(&TypeId::of::<Total>()).unwrap().removals, removals + 1);
+        assert!(!map.contains::<Total, uint>());
+    }
+}
@@ -0,0 +1,169 @@
+//! Tracking of the entries modified since the last checkpoint.
+//!
+//! Tracking is off until `track_dirty` is called, so maps which never ask
+//! for their modified entries do not pay for recording them. Write-behind
+//! persistence and replication start it themselves when they first run.
+//! Entries modified before tracking starts are not dirty, but the first
+//! `flush_dirty` and `take_changes` pass on every entry stored at that
+//! point; removals made before then are not passed on.
+//!
+//! Write-behind persistence (see `flush_dirty`) and replication (see
+//! `take_changes`) each keep their own record of the entries modified
+//! since they last ran, so they can be used together, and checkpoints do
+//! not affect them.
+//!
+//! An entry becomes dirty when a value is inserted for it, it is borrowed
+//! mutably, whether or not the value is then changed, or its value is
+//! removed, through any method of the map, including transactions, scoped
+//! inserts and bulk removals. Removed entries are not visited by
+//! `iter_dirty`, but `flush_dirty` and `take_changes` pass them on as
+//! removals.
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::intrinsics::TypeId;
+use std::vec;
+
+use super::{TypeMap, Assoc, Storage};
+
+// The entries modified since each consumer of them last ran.
+pub struct Tracking {
+    pub dirty: HashSet<TypeId>,
+    pub unsent: HashSet<TypeId>,
+    pub unflushed: HashSet<TypeId>
+}
+
+impl Tracking {
+    pub fn mark(&mut self, id: TypeId) {
+        self.dirty.insert(id);
+        self.unsent.insert(id);
+        self.unflushed.insert(id);
+    }
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Start tracking the entries modified from now on.
+    ///
+    /// Does nothing if the map is already tracking them.
+    pub fn track_dirty(&mut self) {
+        if self.tracking.is_some() { return }
+        let stored: HashSet<TypeId> = self.data.entries().map(|(id, _)| id).collect();
+        self.tracking = Some(box Tracking {
+            dirty: HashSet::new(),
+            unsent: stored.clone(),
+            unflushed: stored
+        });
+    }
+
+    /// Check if the entry of a key was modified since the last checkpoint.
+    ///
+    /// Always false if the map is not tracking modified entries.
+    pub fn is_dirty<K: Assoc<V>, V: 'static>(&self) -> bool {
+        match self.tracking {
+            Some(ref tracking) => tracking.dirty.contains(&self.slot::<K>()) && self.contains::<K, V>(),
+            None => false
+        }
+    }
+
+    /// Get the entries modified since the last checkpoint, in no
+    /// particular order.
+    pub fn iter_dirty<'a>(&'a self) -> vec::MoveItems<(TypeId, &'a (Any + 'static))> {
+        let mut entries = Vec::new();
+        match self.tracking {
+            Some(ref tracking) => for id in tracking.dirty.iter() {
+                match self.data.find(id) {
+                    Some(val) => entries.push((*id, &**val)),
+                    None => ()
+                }
+            },
+            None => ()
+        }
+        entries.into_iter()
+    }
+
+    /// Mark every entry as clean.
+    pub fn checkpoint(&mut self) {
+        match self.tracking {
+            Some(ref mut tracking) => tracking.dirty.clear(),
+            None => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::intrinsics::TypeId;
+    use {TypeMap, Assoc};
+
+    struct Session;
+
+    struct Config;
+
+    impl Assoc<uint> for Session {}
+    impl Assoc<uint> for Config {}
+
+    #[test] fn test_dirty_tracking() {
+        let mut map = TypeMap::new();
+        map.track_dirty();
+        map.insert::<Session, uint>(1);
+        map.insert::<Config, uint>(2);
+        assert_eq!(map.iter_dirty().count(), 2);
+
+        map.checkpoint();
+        assert_eq!(map.iter_dirty().count(), 0);
+        map.find::<Config, uint>();
+        *map.find_mut::<Session, uint>().unwrap() += 1;
+        assert!(map.is_dirty::<Session, uint>());
+        assert!(!map.is_dirty::<Config, uint>());
+
+        let ids: Vec<TypeId> = map.iter_dirty().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![TypeId::of::<Session>()]);
+
+        map.checkpoint();
+        map.remove::<Config, uint>();
+        assert_eq!(map.iter_dirty().count(), 0);
+    }
+
+    #[test] fn test_untracked() {
+        let mut map = TypeMap::new();
+        map.insert::<Session, uint>(1);
+        assert!(!map.is_dirty::<Session, uint>());
+
+        map.track_dirty();
+        assert_eq!(map.iter_dirty().count(), 0);
+        map.insert::<Config, uint>(2);
+        assert!(map.is_dirty::<Config, uint>());
+        assert!(!map.is_dirty::<Session, uint>());
+    }
+
+    #[test] fn test_transaction_marks_dirty() {
+        let mut map = TypeMap::new();
+        map.track_dirty();
+        map.insert::<Config, uint>(2);
+        map.checkpoint();
+        let _: Result<(), ()> = map.scope(|m| {
+            m.insert::<Session, uint>(1);
+            Ok(())
+        });
+        assert!(map.is_dirty::<Session, uint>());
+
+        map.checkpoint();
+        let _: Result<(), ()> = map.scope(|m| {
+            m.remove::<Config, uint>();
+            Err(())
+        });
+        assert!(map.is_dirty::<Config, uint>());
+    }
+
+    #[test] fn test_scoped_insert_marks_dirty() {
+        let mut map = TypeMap::new();
+        map.track_dirty();
+        map.insert::<Session, uint>(1);
+        {
+            let mut scoped = map.insert_scoped::<Session, uint>(2);
+            scoped.checkpoint();
+        }
+        assert!(map.is_dirty::<Session, uint>());
+        assert_eq!(*map.find::<Session, uint>().unwrap(), 1);
+    }
+}
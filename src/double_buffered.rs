@@ -0,0 +1,76 @@
+//! A pair of TypeMaps for computing the next state from the current one.
+
+use std::mem;
+
+use super::TypeMap;
+
+/// A read-only current map and a writable next map, which trade places
+/// when `swap_buffers` is called.
+///
+/// Simulation steps can read the current state while writing the next, so
+/// neither needs to be cloned.
+pub struct DoubleBuffered {
+    current: TypeMap,
+    next: TypeMap
+}
+
+impl DoubleBuffered {
+    /// Create a DoubleBuffered with two empty maps.
+    pub fn new() -> DoubleBuffered {
+        DoubleBuffered {
+            current: TypeMap::new(),
+            next: TypeMap::new()
+        }
+    }
+
+    /// Get the current map.
+    pub fn current(&self) -> &TypeMap {
+        &self.current
+    }
+
+    /// Get the next map, to write to.
+    pub fn next(&mut self) -> &mut TypeMap {
+        &mut self.next
+    }
+
+    /// Get the current map to read from and the next map to write to at the
+    /// same time.
+    pub fn split(&mut self) -> (&TypeMap, &mut TypeMap) {
+        (&self.current, &mut self.next)
+    }
+
+    /// Make the next map current.
+    ///
+    /// The old current map becomes the next map without being cleared, so
+    /// keys which are not written again keep their values from two swaps
+    /// ago.
+    pub fn swap_buffers(&mut self) {
+        mem::swap(&mut self.current, &mut self.next)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DoubleBuffered;
+    use Assoc;
+
+    struct Position;
+
+    impl Assoc<int> for Position {}
+
+    #[test] fn test_swap_buffers() {
+        let mut buffers = DoubleBuffered::new();
+        buffers.next().insert::<Position, int>(0);
+        buffers.swap_buffers();
+
+        for _ in range(0u, 3) {
+            {
+                let (current, next) = buffers.split();
+                let position = *current.find::<Position, int>().unwrap();
+                next.insert::<Position, int>(position + 1);
+            }
+            buffers.swap_buffers();
+        }
+        assert_eq!(*buffers.current().find::<Position, int>().unwrap(), 3);
+    }
+}
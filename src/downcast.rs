@@ -0,0 +1,66 @@
+//! Downcasts of `Any` trait objects which skip the type check.
+//!
+//! A TypeMap already knows the type of every value from its key, so the
+//! TypeId comparison made by the checked downcasts in `std::any` is wasted.
+//! All the unsafe casts of trait objects in this crate live here.
+
+use std::any::Any;
+use std::mem;
+use std::raw::TraitObject;
+
+/// Unchecked downcasts of shared references to `Any`.
+pub trait UncheckedAnyDowncast<'a> {
+    /// Get a reference to the value, which must be of type `T`.
+    unsafe fn downcast_ref_unchecked<T: 'static>(self) -> &'a T;
+}
+
+/// Unchecked downcasts of mutable references to `Any`.
+pub trait UncheckedAnyMutDowncast<'a> {
+    /// Get a mutable reference to the value, which must be of type `T`.
+    unsafe fn downcast_mut_unchecked<T: 'static>(self) -> &'a mut T;
+}
+
+/// Unchecked downcasts of boxed `Any` values.
+pub trait UncheckedBoxAnyDowncast {
+    /// Get the boxed value, which must be of type `T`.
+    unsafe fn downcast_unchecked<T: 'static>(self) -> Box<T>;
+}
+
+impl<'a> UncheckedAnyDowncast<'a> for &'a (Any + 'static) {
+    #[inline]
+    unsafe fn downcast_ref_unchecked<T: 'static>(self) -> &'a T {
+        let object: TraitObject = mem::transmute(self);
+        mem::transmute(object.data)
+    }
+}
+
+impl<'a> UncheckedAnyMutDowncast<'a> for &'a mut (Any + 'static) {
+    #[inline]
+    unsafe fn downcast_mut_unchecked<T: 'static>(self) -> &'a mut T {
+        let object: TraitObject = mem::transmute(self);
+        mem::transmute(object.data)
+    }
+}
+
+impl UncheckedBoxAnyDowncast for Box<Any + 'static> {
+    #[inline]
+    unsafe fn downcast_unchecked<T: 'static>(self) -> Box<T> {
+        let object: TraitObject = mem::transmute(self);
+        mem::transmute(object.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::Any;
+    use super::{UncheckedAnyDowncast, UncheckedAnyMutDowncast, UncheckedBoxAnyDowncast};
+
+    #[test] fn test_downcasts() {
+        let mut val = box 5u as Box<Any + 'static>;
+        unsafe {
+            assert_eq!(*val.downcast_ref_unchecked::<uint>(), 5);
+            *val.downcast_mut_unchecked::<uint>() += 1;
+            assert_eq!(*val.downcast_unchecked::<uint>(), 6);
+        }
+    }
+}
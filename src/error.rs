@@ -0,0 +1,51 @@
+//! The error type of the fallible TypeMap methods.
+
+use std::error::Error;
+use std::fmt;
+
+/// An error from one of the `try_` methods of a TypeMap.
+///
+/// Keys and values are identified by the names of their types.
+#[deriving(Clone, PartialEq)]
+pub enum TypeMapError {
+    /// The key, whose value type is given second, has no value.
+    MissingKey(&'static str, &'static str),
+    /// The key, whose value type is given second, already has a value.
+    KeyOccupied(&'static str, &'static str),
+    /// The key's value was requested as the second type, but is of the
+    /// third.
+    TypeMismatch(&'static str, &'static str, &'static str),
+    /// The map already holds as many values as it can.
+    CapacityExceeded(uint),
+    /// The key, whose value type is given second, is frozen.
+    Frozen(&'static str, &'static str)
+}
+
+impl fmt::Show for TypeMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MissingKey(key, value) => write!(f, "missing key {} (value {})", key, value),
+            KeyOccupied(key, value) => write!(f, "key {} (value {}) is already occupied", key, value),
+            TypeMismatch(key, expected, found) =>
+                write!(f, "key {} holds a {}, not a {}", key, found, expected),
+            CapacityExceeded(capacity) => write!(f, "capacity of {} entries exceeded", capacity),
+            Frozen(key, value) => write!(f, "key {} (value {}) is frozen", key, value)
+        }
+    }
+}
+
+impl Error for TypeMapError {
+    fn description(&self) -> &str {
+        match *self {
+            MissingKey(..) => "key is missing from the TypeMap",
+            KeyOccupied(..) => "key is already occupied in the TypeMap",
+            TypeMismatch(..) => "value is not of the requested type",
+            CapacityExceeded(..) => "TypeMap capacity exceeded",
+            Frozen(..) => "key is frozen in the TypeMap"
+        }
+    }
+
+    fn detail(&self) -> Option<String> {
+        Some(self.to_string())
+    }
+}
@@ -0,0 +1,51 @@
+//! Per-key callbacks receiving the values evicted by the bounded and
+//! expiring TypeMap variants.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::intrinsics::TypeId;
+
+use downcast::{UncheckedAnyDowncast, UncheckedBoxAnyDowncast};
+
+/// Typed eviction callbacks, keyed by the TypeId of their key.
+pub struct EvictionCallbacks {
+    callbacks: HashMap<TypeId, Callback>
+}
+
+struct Callback {
+    // The `fn(V)` registered for the key.
+    callback: Box<Any + 'static>,
+    // Downcasts the callback and the value, then calls one with the other.
+    run: fn(&Box<Any + 'static>, Box<Any + 'static>)
+}
+
+impl EvictionCallbacks {
+    pub fn new() -> EvictionCallbacks {
+        EvictionCallbacks { callbacks: HashMap::new() }
+    }
+
+    /// Set the callback for a key whose values are of type `V`.
+    pub fn set<V: 'static>(&mut self, id: TypeId, callback: fn(V)) {
+        self.callbacks.insert(id, Callback {
+            callback: box callback as Box<Any + 'static>,
+            run: run::<V> as fn(&Box<Any + 'static>, Box<Any + 'static>)
+        });
+    }
+
+    /// Pass an evicted value to the callback for its key.
+    ///
+    /// The value is handed back if the key has no callback.
+    pub fn run(&self, id: &TypeId, val: Box<Any + 'static>) -> Option<Box<Any + 'static>> {
+        match self.callbacks.find(id) {
+            Some(callback) => { (callback.run)(&callback.callback, val); None },
+            None => Some(val)
+        }
+    }
+}
+
+fn run<V: 'static>(callback: &Box<Any + 'static>, val: Box<Any + 'static>) {
+    unsafe {
+        let callback = (&**callback).downcast_ref_unchecked::<fn(V)>();
+        (*callback)(*val.downcast_unchecked::<V>())
+    }
+}
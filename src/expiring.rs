@@ -0,0 +1,190 @@
+//! A TypeMap whose entries can expire after a time-to-live.
+
+use std::collections::HashMap;
+use std::intrinsics::TypeId;
+use std::time::Duration;
+use std::{i64, u64};
+
+use time;
+
+use evict::EvictionCallbacks;
+
+use super::{TypeMap, Assoc};
+
+/// A TypeMap whose entries can be given a time-to-live.
+///
+/// Expired entries are never returned, and are dropped by `purge_expired`,
+/// which first passes them to the callback registered for their key.
+pub struct ExpiringTypeMap {
+    map: TypeMap,
+    deadlines: HashMap<TypeId, u64>,
+    callbacks: EvictionCallbacks
+}
+
+impl ExpiringTypeMap {
+    /// Create a new, empty ExpiringTypeMap.
+    pub fn new() -> ExpiringTypeMap {
+        ExpiringTypeMap {
+            map: TypeMap::new(),
+            deadlines: HashMap::new(),
+            callbacks: EvictionCallbacks::new()
+        }
+    }
+
+    /// Set a callback to be run with the expired values of a key when they
+    /// are purged, so that resources they hold can be released or logged.
+    pub fn on_evict<K: Assoc<V>, V: 'static>(&mut self, callback: fn(V)) {
+        self.callbacks.set::<V>(TypeId::of::<K>(), callback);
+    }
+
+    /// Insert a value which never expires.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        self.deadlines.remove(&TypeId::of::<K>());
+        self.map.insert::<K, V>(val)
+    }
+
+    /// Insert a value which expires once `ttl` has elapsed.
+    pub fn insert_with_ttl<K: Assoc<V>, V: 'static>(&mut self, val: V, ttl: Duration) -> bool {
+        let ttl = match ttl.num_nanoseconds().unwrap_or(i64::MAX) {
+            ns if ns < 0 => 0,
+            ns => ns as u64
+        };
+        let now = time::precise_time_ns();
+        let deadline = if ttl > u64::MAX - now { u64::MAX } else { now + ttl };
+        self.deadlines.insert(TypeId::of::<K>(), deadline);
+        self.map.insert::<K, V>(val)
+    }
+
+    /// Find an unexpired value in the map and get a reference to it.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        if self.is_expired(&TypeId::of::<K>()) { return None }
+        self.map.find::<K, V>()
+    }
+
+    /// Find an unexpired value in the map and get a mutable reference to it.
+    pub fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> {
+        if self.is_expired(&TypeId::of::<K>()) { return None }
+        self.map.find_mut::<K, V>()
+    }
+
+    /// Check if a key has an unexpired value stored in the map.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        !self.is_expired(&TypeId::of::<K>()) && self.map.contains::<K, V>()
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed, expired or not.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        self.deadlines.remove(&TypeId::of::<K>());
+        self.map.remove::<K, V>()
+    }
+
+    /// Drop all expired entries from the map, running their eviction
+    /// callbacks.
+    ///
+    /// Returns the number of entries which were dropped.
+    pub fn purge_expired(&mut self) -> uint {
+        let now = time::precise_time_ns();
+        let expired: Vec<TypeId> = self.deadlines.iter()
+            .filter(|&(_, &deadline)| deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+
+        let mut purged = 0;
+        for id in expired.iter() {
+            self.deadlines.remove(id);
+            match self.map.evict_id(*id) {
+                Some(val) => { self.callbacks.run(id, val); purged += 1 },
+                None => ()
+            }
+        }
+        purged
+    }
+
+    /// Get the number of values stored in the map, including expired
+    /// values which have not been purged yet.
+    pub fn len(&self) -> uint {
+        self.map.len()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Remove all entries from the map.
+    pub fn clear(&mut self) {
+        self.deadlines.clear();
+        self.map.clear()
+    }
+
+    fn is_expired(&self, id: &TypeId) -> bool {
+        match self.deadlines.find(id) {
+            Some(&deadline) => deadline <= time::precise_time_ns(),
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::ExpiringTypeMap;
+    use Assoc;
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct Value;
+
+    impl Assoc<Value> for Key {}
+
+    #[test] fn test_ttl() {
+        let mut map = ExpiringTypeMap::new();
+        map.insert_with_ttl::<Key, Value>(Value, Duration::hours(1));
+        assert_eq!(*map.find::<Key, Value>().unwrap(), Value);
+        assert_eq!(map.purge_expired(), 0);
+
+        map.insert_with_ttl::<Key, Value>(Value, Duration::zero());
+        assert!(!map.contains::<Key, Value>());
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.purge_expired(), 1);
+        assert!(map.is_empty());
+    }
+
+    #[test] fn test_eviction_callback() {
+        use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+
+        static PURGED: AtomicUint = INIT_ATOMIC_UINT;
+
+        fn purged(_: Value) { PURGED.fetch_add(1, SeqCst); }
+
+        let mut map = ExpiringTypeMap::new();
+        map.on_evict::<Key, Value>(purged);
+        map.insert_with_ttl::<Key, Value>(Value, Duration::zero());
+        map.purge_expired();
+        assert_eq!(PURGED.load(SeqCst), 1);
+
+        map.insert_with_ttl::<Key, Value>(Value, Duration::zero());
+        map.remove::<Key, Value>();
+        assert_eq!(PURGED.load(SeqCst), 1);
+    }
+
+    #[test] fn test_insert_clears_ttl() {
+        let mut map = ExpiringTypeMap::new();
+        map.insert_with_ttl::<Key, Value>(Value, Duration::zero());
+        map.insert::<Key, Value>(Value);
+        assert!(map.contains::<Key, Value>());
+        assert_eq!(map.purge_expired(), 0);
+    }
+
+    #[test] fn test_purge_frozen() {
+        let mut map = ExpiringTypeMap::new();
+        map.insert_with_ttl::<Key, Value>(Value, Duration::zero());
+        map.map.freeze::<Key, Value>();
+        assert_eq!(map.purge_expired(), 0);
+        assert_eq!(map.len(), 1);
+    }
+}
@@ -0,0 +1,88 @@
+//! A map of request extensions keyed by value type, with the API used by
+//! HTTP libraries for their `Extensions` types.
+
+use super::{TypeMap, Assoc, Occupied, Vacant};
+
+/// A map holding at most one value of each type, keyed by the type itself.
+///
+/// This gives libraries which expose an `Extensions` map with `insert`,
+/// `get` and `remove` methods a drop-in implementation built on TypeMap.
+pub struct Extensions {
+    map: TypeMap
+}
+
+// The key under which values of type `T` are stored.
+struct Ext<T>;
+
+impl<T: 'static> Assoc<T> for Ext<T> {}
+
+impl Extensions {
+    /// Create a new, empty Extensions map.
+    pub fn new() -> Extensions {
+        Extensions { map: TypeMap::new() }
+    }
+
+    /// Insert a value, returning the previous value of the same type.
+    pub fn insert<T: 'static>(&mut self, val: T) -> Option<T> {
+        match self.map.entry::<Ext<T>, T>() {
+            Occupied(mut e) => Some(e.set(val)),
+            Vacant(e) => { e.set(val); None }
+        }
+    }
+
+    /// Get a reference to the value of a type.
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map.find::<Ext<T>, T>()
+    }
+
+    /// Get a mutable reference to the value of a type.
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map.find_mut::<Ext<T>, T>()
+    }
+
+    /// Check if a value of a type is stored.
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.map.contains::<Ext<T>, T>()
+    }
+
+    /// Remove the value of a type, returning it.
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map.remove_box::<Ext<T>, T>().map(|val| *val)
+    }
+
+    /// Get the number of values stored.
+    pub fn len(&self) -> uint {
+        self.map.len()
+    }
+
+    /// Return true if no values are stored.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Remove all values.
+    pub fn clear(&mut self) {
+        self.map.clear()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Extensions;
+
+    #[deriving(Show, PartialEq)]
+    struct RequestId(uint);
+
+    #[test] fn test_extensions() {
+        let mut ext = Extensions::new();
+        assert_eq!(ext.insert(RequestId(1)), None);
+        assert_eq!(ext.insert(RequestId(2)), Some(RequestId(1)));
+        ext.insert(5u);
+
+        assert_eq!(ext.get::<RequestId>(), Some(&RequestId(2)));
+        *ext.get_mut::<uint>().unwrap() += 1;
+        assert_eq!(ext.remove::<uint>(), Some(6));
+        assert!(!ext.contains::<uint>());
+        assert_eq!(ext.len(), 1);
+    }
+}
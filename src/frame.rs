@@ -0,0 +1,53 @@
+//! Entries which only live until the end of the current frame or request.
+
+use std::intrinsics::TypeId;
+
+use super::{TypeMap, Assoc, Storage};
+
+impl<S: Storage> TypeMap<S> {
+    /// Mark a key as frame-scoped, so its value is removed by `end_frame`.
+    pub fn set_frame_scoped<K: Assoc<V>, V: 'static>(&mut self) {
+        let id = self.slot::<K>();
+        self.frame_scoped.insert(id);
+    }
+
+    /// Remove the values of all frame-scoped keys, leaving the others.
+    /// Frozen values are not removed.
+    ///
+    /// Returns the number of values removed.
+    pub fn end_frame(&mut self) -> uint {
+        let scoped: Vec<TypeId> = self.frame_scoped.iter().map(|&id| id).collect();
+        let mut removed = 0;
+        for id in scoped.into_iter() {
+            if self.evict_id(id).is_some() { removed += 1 }
+        }
+        self.debug_check();
+        removed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {TypeMap, Assoc};
+
+    struct Scratch;
+
+    struct Config;
+
+    impl Assoc<Vec<uint>> for Scratch {}
+    impl Assoc<uint> for Config {}
+
+    #[test] fn test_end_frame() {
+        let mut map = TypeMap::new();
+        map.set_frame_scoped::<Scratch, Vec<uint>>();
+        map.insert::<Config, uint>(1);
+
+        for frame in range(0u, 3) {
+            assert!(!map.contains::<Scratch, Vec<uint>>());
+            map.insert::<Scratch, Vec<uint>>(vec![frame]);
+            assert_eq!(map.end_frame(), 1);
+        }
+        assert_eq!(map.end_frame(), 0);
+        assert_eq!(*map.find::<Config, uint>().unwrap(), 1);
+    }
+}
@@ -0,0 +1,176 @@
+//! Read-only entries, protecting values set early, like a request's
+//! security context, from being changed by later code.
+
+use super::{TypeMap, Assoc, Storage};
+
+impl<S: Storage> TypeMap<S> {
+    /// Make the entry of a key read-only.
+    ///
+    /// Afterwards `find_mut` returns `None` and `remove` removes nothing for
+    /// the key, their `try_` versions fail with `Frozen`, and `insert` and
+    /// `entry` panic. The same goes for transactions. Bulk removals such as
+    /// `end_frame`, `sweep`, `clear_category` and `clear` leave the value in
+    /// place, and nothing else which changes entries, like `rekey`,
+    /// `rollback` or `apply_changes`, changes it either. A freeze cannot be
+    /// lifted.
+    ///
+    /// Returns `false`, freezing nothing, if the key has no value.
+    pub fn freeze<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        let id = self.slot::<K>();
+        if !self.data.contains(&id) { return false }
+        self.frozen.insert(id);
+        true
+    }
+
+    /// Check if the entry of a key is frozen.
+    pub fn is_frozen<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.is_frozen_id(&self.slot::<K>())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {TypeMap, Assoc, Frozen, Migration, KeyRegistry, StableKey};
+
+    struct Principal;
+
+    struct Theme;
+
+    struct Role;
+
+    struct Group;
+
+    impl Assoc<&'static str> for Principal {}
+    impl Assoc<&'static str> for Theme {}
+    impl Assoc<&'static str> for Group {}
+    impl Assoc<uint> for Role {}
+
+    impl StableKey<uint> for Role {
+        fn stable_id(_: Option<Role>) -> &'static str { "test.role" }
+    }
+
+    fn frozen() -> TypeMap {
+        let mut map = TypeMap::new();
+        map.insert::<Principal, &'static str>("admin");
+        map.freeze::<Principal, &'static str>();
+        map
+    }
+
+    #[test] fn test_freeze() {
+        let mut map = TypeMap::new();
+        assert!(!map.freeze::<Principal, &'static str>());
+        map.insert::<Principal, &'static str>("admin");
+        map.insert::<Theme, &'static str>("dark");
+        assert!(map.freeze::<Principal, &'static str>());
+        assert!(map.is_frozen::<Principal, &'static str>());
+
+        assert!(map.find_mut::<Principal, &'static str>().is_none());
+        assert!(!map.remove::<Principal, &'static str>());
+        match map.try_remove::<Principal, &'static str>() {
+            Err(Frozen(..)) => (),
+            _ => panic!("Removed a frozen value.")
+        }
+        assert!(map.try_insert::<Principal, &'static str>("guest").is_err());
+        assert_eq!(map.apply::<Principal, &'static str, ()>(|p| *p = "guest"), None);
+        match map.try_apply::<Principal, &'static str, ()>(|p| *p = "guest") {
+            Err(Frozen(..)) => (),
+            _ => panic!("Changed a frozen value.")
+        }
+        assert_eq!(*map.find::<Principal, &'static str>().unwrap(), "admin");
+        assert!(map.remove::<Theme, &'static str>());
+
+        map.clear();
+        assert!(map.is_frozen::<Principal, &'static str>());
+        assert_eq!(*map.find::<Principal, &'static str>().unwrap(), "admin");
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test] #[should_fail] fn test_expect_find_mut_frozen() {
+        let mut map = frozen();
+        map.expect_find_mut::<Principal, &'static str>();
+    }
+
+    #[test] fn test_bulk_removals_keep_frozen() {
+        let mut map = frozen();
+        map.set_frame_scoped::<Principal, &'static str>();
+        assert_eq!(map.end_frame(), 0);
+
+        map.mark_for_removal::<Principal, &'static str>();
+        assert_eq!(map.sweep(), 0);
+
+        map.set_category::<Principal, &'static str, Theme>();
+        assert_eq!(map.clear_category::<Theme>(), 0);
+        assert!(map.contains::<Principal, &'static str>());
+    }
+
+    #[test] fn test_transaction_keeps_frozen() {
+        let mut map = frozen();
+        let result: Result<(), ()> = map.scope(|m| {
+            assert!(m.find_mut::<Principal, &'static str>().is_none());
+            assert!(!m.remove::<Principal, &'static str>());
+            Err(())
+        });
+        assert!(result.is_err());
+        assert_eq!(*map.find::<Principal, &'static str>().unwrap(), "admin");
+    }
+
+    #[test] #[should_fail] fn test_transaction_insert_frozen() {
+        let mut map = frozen();
+        let _: Result<(), ()> = map.scope(|m| {
+            m.insert::<Principal, &'static str>("guest");
+            Ok(())
+        });
+    }
+
+    #[test] fn test_moves_keep_frozen() {
+        let mut map = frozen();
+        map.insert::<Group, &'static str>("staff");
+        assert!(!map.rekey::<Principal, Group, &'static str>());
+        assert!(!map.rekey::<Group, Principal, &'static str>());
+        let migration = Migration::new().rename::<Group, Principal, &'static str>();
+        assert_eq!(migration.apply(&mut map), 0);
+        assert_eq!(*map.find::<Principal, &'static str>().unwrap(), "admin");
+        assert_eq!(*map.find::<Group, &'static str>().unwrap(), "staff");
+    }
+
+    #[test] #[should_fail] fn test_alias_frozen() {
+        let mut map = frozen();
+        map.insert::<Group, &'static str>("staff");
+        map.alias::<Group, Principal, &'static str>();
+    }
+
+    #[test] fn test_rollback_frozen() {
+        let mut map = TypeMap::new();
+        map.keep_history::<Principal, &'static str>(1);
+        map.insert::<Principal, &'static str>("guest");
+        map.insert::<Principal, &'static str>("admin");
+        map.freeze::<Principal, &'static str>();
+        assert!(!map.rollback::<Principal, &'static str>(1));
+        assert_eq!(*map.find::<Principal, &'static str>().unwrap(), "admin");
+    }
+
+    #[test] fn test_apply_changes_frozen() {
+        let mut registry = KeyRegistry::new();
+        registry.register_replicated::<Role, uint>();
+
+        let mut source = TypeMap::new();
+        source.insert::<Role, uint>(2);
+        let mut replica = TypeMap::new();
+        replica.insert::<Role, uint>(1);
+        replica.freeze::<Role, uint>();
+
+        let set = source.take_changes(&registry);
+        assert_eq!(replica.apply_changes(&registry, set.as_slice()), Ok(0));
+        source.remove::<Role, uint>();
+        let remove = source.take_changes(&registry);
+        assert_eq!(replica.apply_changes(&registry, remove.as_slice()), Ok(0));
+        assert_eq!(*replica.find::<Role, uint>().unwrap(), 1);
+    }
+
+    #[test] #[should_fail] fn test_insert_frozen() {
+        let mut map = TypeMap::new();
+        map.insert::<Principal, &'static str>("admin");
+        map.freeze::<Principal, &'static str>();
+        map.insert::<Principal, &'static str>("guest");
+    }
+}
@@ -0,0 +1,109 @@
+//! Opt-in history of the previous values of individual keys, so changes to
+//! them can be undone without snapshotting the whole map.
+
+use std::any::Any;
+use std::collections::hashmap;
+
+use downcast::UncheckedAnyDowncast;
+
+use super::{TypeMap, Assoc, Storage};
+
+/// The previous values of a key, oldest first.
+pub struct History {
+    limit: uint,
+    values: Vec<Box<Any + 'static>>
+}
+
+impl History {
+    /// Keep a replaced value, dropping the oldest one if the history is full.
+    pub fn push(&mut self, val: Box<Any + 'static>) {
+        if self.limit == 0 { return }
+        if self.values.len() == self.limit { self.values.remove(0); }
+        self.values.push(val);
+    }
+
+    /// Drop all kept values.
+    pub fn clear(&mut self) {
+        self.values.clear()
+    }
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Keep up to `limit` previous values of a key.
+    ///
+    /// Values are kept when they are replaced by `insert`, not when they
+    /// are changed in place through `find_mut`. Lowering the limit drops
+    /// the oldest kept values, and clearing the map drops all of them.
+    pub fn keep_history<K: Assoc<V>, V: 'static>(&mut self, limit: uint) {
+        let id = self.slot::<K>();
+        match self.history.entry(id) {
+            hashmap::Occupied(e) => {
+                let history = e.into_mut();
+                while history.values.len() > limit { history.values.remove(0); }
+                history.limit = limit;
+            },
+            hashmap::Vacant(e) => { e.set(History { limit: limit, values: Vec::new() }); }
+        }
+    }
+
+    /// Get the previous values of a key, most recent first.
+    pub fn history<K: Assoc<V>, V: 'static>(&self) -> Vec<&V> {
+        match self.history.find(&self.slot::<K>()) {
+            Some(history) => history.values.iter().rev().map(|val| unsafe {
+                (&**val).downcast_ref_unchecked::<V>()
+            }).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Restore the value a key had `steps` replacements ago, dropping the
+    /// current value and the more recent previous ones.
+    ///
+    /// Returns `false`, changing nothing, if fewer than `steps` previous
+    /// values are kept, if `steps` is 0, or if the key is frozen or has no
+    /// value and the map is full.
+    pub fn rollback<K: Assoc<V>, V: 'static>(&mut self, steps: uint) -> bool {
+        let id = self.slot::<K>();
+        if !self.can_store(&id) { return false }
+        let val = match self.history.find_mut(&id) {
+            Some(history) if steps > 0 && steps <= history.values.len() => {
+                let len = history.values.len();
+                history.values.truncate(len - steps + 1);
+                history.values.pop().unwrap()
+            },
+            _ => return false
+        };
+        match self.store_id(id, val) {
+            Ok(_) => self.expected.record::<V>(id),
+            Err(_) => unreachable!()
+        }
+        self.debug_check();
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {TypeMap, Assoc};
+
+    struct Title;
+
+    impl Assoc<&'static str> for Title {}
+
+    #[test] fn test_history() {
+        let mut map = TypeMap::new();
+        map.keep_history::<Title, &'static str>(2);
+        assert!(!map.rollback::<Title, &'static str>(1));
+
+        map.insert::<Title, &'static str>("one");
+        map.insert::<Title, &'static str>("two");
+        map.insert::<Title, &'static str>("three");
+        map.insert::<Title, &'static str>("four");
+        assert_eq!(map.history::<Title, &'static str>(), vec![&"three", &"two"]);
+
+        assert!(!map.rollback::<Title, &'static str>(3));
+        assert!(map.rollback::<Title, &'static str>(2));
+        assert_eq!(*map.find::<Title, &'static str>().unwrap(), "two");
+        assert!(map.history::<Title, &'static str>().is_empty());
+    }
+}
@@ -0,0 +1,74 @@
+//! Recording of the value type stored for each key, enabled by the
+//! `invariants` feature, so `TypeMap::check_invariants` can verify entries.
+
+use std::intrinsics::TypeId;
+#[cfg(feature = "invariants")]
+use std::collections::HashMap;
+
+use storage::Storage;
+
+/// The value type each key was last inserted with.
+#[cfg(feature = "invariants")]
+pub struct Expectations {
+    values: HashMap<TypeId, (TypeId, &'static str)>
+}
+
+/// Value types are not recorded without the `invariants` feature.
+#[cfg(not(feature = "invariants"))]
+pub struct Expectations;
+
+#[cfg(feature = "invariants")]
+impl Expectations {
+    pub fn new() -> Expectations {
+        Expectations { values: HashMap::new() }
+    }
+
+    pub fn record<V: 'static>(&mut self, id: TypeId) {
+        self.values.insert(id, (TypeId::of::<V>(), ::type_name::<V>()));
+    }
+
+    // Record a value type known only at runtime, by its TypeId and name.
+    pub fn record_type(&mut self, id: TypeId, value: TypeId, name: &'static str) {
+        self.values.insert(id, (value, name));
+    }
+
+    pub fn forget(&mut self, id: &TypeId) {
+        self.values.remove(id);
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    /// Panic if a stored value does not have the type it was recorded with.
+    pub fn check<S: Storage>(&self, data: &S) {
+        for (id, &(value, name)) in self.values.iter() {
+            match data.find(id) {
+                Some(val) if (**val).get_type_id() != value =>
+                    panic!("TypeMap invariant violated: key {} does not hold a {}", id, name),
+                _ => ()
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "invariants"))]
+impl Expectations {
+    #[inline(always)]
+    pub fn new() -> Expectations { Expectations }
+
+    #[inline(always)]
+    pub fn record<V: 'static>(&mut self, _: TypeId) {}
+
+    #[inline(always)]
+    pub fn record_type(&mut self, _: TypeId, _: TypeId, _: &'static str) {}
+
+    #[inline(always)]
+    pub fn forget(&mut self, _: &TypeId) {}
+
+    #[inline(always)]
+    pub fn clear(&mut self) {}
+
+    #[inline(always)]
+    pub fn check<S: Storage>(&self, _: &S) {}
+}
@@ -1,25 +1,156 @@
 #![license = "MIT"]
+#![feature(default_type_params, macro_rules, phase, unsafe_destructor)]
 #![deny(missing_docs)]
 #![deny(warnings)]
 
 //! A type-based key value store where one value type is allowed for each key.
 
 extern crate alloc;
-extern crate "unsafe-any" as uany;
+#[cfg(feature = "trace")]
+#[phase(plugin, link)]
+extern crate log;
+extern crate serialize;
+extern crate time;
 
 use std::any::Any;
-use std::intrinsics::TypeId;
-use std::collections::{hashmap, HashMap};
+use std::cell::RefCell;
+use std::intrinsics::{mod, TypeId};
+use std::collections::{hashmap, HashMap, HashSet};
+use std::{cmp, mem, vec};
+
+use serialize::json::Json;
 
 // These traits are faster when we know the type is correct already.
-use uany::{UncheckedAnyDowncast, UncheckedAnyMutDowncast, UncheckedBoxAnyDowncast};
+use downcast::{UncheckedAnyDowncast, UncheckedAnyMutDowncast, UncheckedBoxAnyDowncast};
+
+use dirty::Tracking;
+use history::History;
+use invariants::Expectations;
+use provenance::Provenance;
+use recycle::FreeList;
+use trace::Trace;
+
+pub use access::{Access, Declare};
+pub use address::{AddressTypeMap, KeyAddress, StaticKey};
+pub use btree::BTreeTypeMap;
+pub use category::CategoryEntries;
+pub use cells::CellMap;
+pub use cold::{ColdTypeMap, Codec};
+pub use context::{ErrorContext, ResultContext};
+pub use dense::{DenseRegistry, DenseKey, DenseTypeMap};
+pub use error::{TypeMapError, MissingKey, KeyOccupied, TypeMismatch, CapacityExceeded, Frozen};
+pub use double_buffered::DoubleBuffered;
+pub use expiring::ExpiringTypeMap;
+#[cfg(feature = "extensions")]
+pub use extensions::Extensions;
+pub use lru::LruTypeMap;
+pub use migrate::Migration;
+pub use noop::NoopTypeMap;
+pub use ordered::OrderedTypeMap;
+pub use path::{MapPath, MapPathMut};
+pub use persist::Sink;
+pub use pool::{TypeMapPool, PooledTypeMap};
+pub use provenance::Location;
+pub use provide::{Provider, Demand, request_ref, request_value};
+pub use prototype::PrototypeMap;
+pub use recording::{RecordingTypeMap, Operation, Op};
+pub use registry::{KeyRegistry, KeyInfo, ErasedKey, StableKey};
+pub use replicate::ReplicationError;
+pub use resource::{ResourceMap, Res, ResMut, Fetch, BorrowError};
+pub use restricted::{RestrictedTypeMap, Allowed};
+pub use schema::{Schema, MissingKeys};
+pub use scoped::ScopedInsert;
+pub use shared::{SharedTypeMap, Lock};
+pub use static_map::{StaticTypeMap, Field, FreezeError};
+pub use stats::{KeyStats, StatsReport, TableStats, UnusedKey};
+pub use storage::{Storage, BTreeStorage, VecStorage, ArrayStorage, NoopStorage};
+pub use subset::{KeyGroup, GetAll};
+pub use transaction::Transaction;
+pub use view::ViewCast;
+pub use watch::{WatchedTypeMap, Change};
+
+#[macro_escape]
+mod macros;
+
+pub mod access;
+pub mod address;
+pub mod btree;
+pub mod category;
+pub mod cells;
+pub mod cold;
+pub mod context;
+pub mod dense;
+pub mod double_buffered;
+pub mod error;
+pub mod expiring;
+#[cfg(feature = "extensions")]
+pub mod extensions;
+pub mod lru;
+pub mod migrate;
+pub mod noop;
+pub mod ordered;
+pub mod path;
+pub mod persist;
+pub mod pool;
+pub mod provide;
+pub mod prototype;
+pub mod recording;
+pub mod registry;
+pub mod replicate;
+pub mod resource;
+pub mod restricted;
+pub mod schema;
+pub mod scoped;
+pub mod shared;
+pub mod static_map;
+pub mod stats;
+pub mod storage;
+pub mod subset;
+pub mod transaction;
+pub mod view;
+pub mod watch;
+
+mod derived;
+mod dirty;
+mod downcast;
+mod evict;
+mod frame;
+mod freeze;
+mod history;
+mod invariants;
+mod provenance;
+mod recycle;
+mod sweep;
+mod trace;
 
 /// A map keyed by types.
 ///
 /// Can contain one value of any type for each key type, as defined
 /// by the Assoc trait.
-pub struct TypeMap {
-    data: HashMap<TypeId, Box<Any + 'static>>
+///
+/// Entries are stored in a HashMap by default; see the `storage` module
+/// for other representations.
+pub struct TypeMap<S = HashMap<TypeId, Box<Any + 'static>>> {
+    data: S,
+    stats: Option<RefCell<StatsReport>>,
+    provenance: Provenance,
+    expected: Expectations,
+    free: FreeList,
+    priorities: HashMap<TypeId, int>,
+    frame_scoped: HashSet<TypeId>,
+    categories: HashMap<TypeId, Vec<TypeId>>,
+    aliases: HashMap<TypeId, TypeId>,
+    resizes: uint,
+    marked: RefCell<HashSet<TypeId>>,
+    iteration: HashMap<TypeId, int>,
+    names: HashMap<TypeId, &'static str>,
+    tracking: Option<Box<Tracking>>,
+    derivations: HashMap<TypeId, Box<Any + 'static>>,
+    dependents: HashMap<TypeId, Vec<TypeId>>,
+    history: HashMap<TypeId, History>,
+    max_entries: Option<uint>,
+    views: HashMap<(TypeId, TypeId), ViewCast>,
+    frozen: HashSet<TypeId>
 }
 
 /// This trait defines the relationship between keys and values in a TypeMap.
@@ -27,58 +158,455 @@ pub struct TypeMap {
 /// It is implemented for Keys, with a phantom type parameter for values.
 pub trait Assoc<Value: 'static>: 'static {}
 
+/// The basic operations of a TypeMap, for code which works with any map,
+/// such as a `TypeMap` or a `NoopTypeMap` compiling it out.
+pub trait TypeMapLike {
+    /// Insert a value into the map with a specified key type.
+    fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool;
+
+    /// Find a value in the map and get a reference to it.
+    fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V>;
+
+    /// Find a value in the map and get a mutable reference to it.
+    fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V>;
+
+    /// Check if a key has an associated value stored in the map.
+    fn contains<K: Assoc<V>, V: 'static>(&self) -> bool;
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed.
+    fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool;
+
+    /// Get the number of values stored in the map.
+    fn len(&self) -> uint;
+
+    /// Return true if the map contains no values.
+    fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
 impl TypeMap {
     /// Create a new, empty TypeMap.
     pub fn new() -> TypeMap {
+        TypeMap::with_storage(HashMap::new())
+    }
+
+    /// Create a new, empty TypeMap which records access statistics.
+    pub fn with_stats() -> TypeMap {
+        let mut map = TypeMap::new();
+        map.enable_stats();
+        map
+    }
+
+    /// Create a new, empty TypeMap holding at most `max` entries.
+    ///
+    /// See `set_max_entries`.
+    pub fn with_max_entries(max: uint) -> TypeMap {
+        let mut map = TypeMap::new();
+        map.set_max_entries(Some(max));
+        map
+    }
+
+    /// Create a TypeMap from raw entries, as returned by `into_inner`.
+    ///
+    /// This is unsafe because the entries are not checked: each box must
+    /// contain a value of the type associated with the key type of its
+    /// TypeId. Later entries replace earlier ones with the same key.
+    pub unsafe fn from_parts(entries: Vec<(TypeId, Box<Any + 'static>)>) -> TypeMap {
+        TypeMap::with_storage(entries.into_iter().collect())
+    }
+
+    /// Consume the map, returning its raw entries in no particular order.
+    ///
+    /// Drop priorities are not applied, since no values are dropped.
+    pub fn into_inner(mut self) -> Vec<(TypeId, Box<Any + 'static>)> {
+        mem::replace(&mut self.data, HashMap::new()).into_iter().collect()
+    }
+
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Create a new TypeMap using the given, empty storage.
+    pub fn with_storage(storage: S) -> TypeMap<S> {
         TypeMap {
-            data: HashMap::new()
+            data: storage,
+            stats: None,
+            provenance: Provenance::new(),
+            expected: Expectations::new(),
+            free: FreeList::new(),
+            priorities: HashMap::new(),
+            frame_scoped: HashSet::new(),
+            categories: HashMap::new(),
+            aliases: HashMap::new(),
+            resizes: 0,
+            marked: RefCell::new(HashSet::new()),
+            iteration: HashMap::new(),
+            names: HashMap::new(),
+            tracking: None,
+            derivations: HashMap::new(),
+            dependents: HashMap::new(),
+            history: HashMap::new(),
+            max_entries: None,
+            views: HashMap::new(),
+            frozen: HashSet::new()
+        }
+    }
+
+    /// Get the given key's corresponding entry in the map for in-place manipulation.
+    pub fn entry<'a, K: Assoc<V>, V: 'static>(&'a mut self) -> Entry<'a, K, V, S> {
+        let _trace = Trace::start::<K>("entry");
+        let id = self.slot::<K>();
+        if self.is_frozen_id(&id) { panic!("cannot get an entry for frozen key {}", type_name::<K>()) }
+        self.name_slot(id, TypeId::of::<K>(), type_name::<K>());
+        let occupied = self.data.contains(&id);
+        self.record::<K>(|stats| stats.lookup(occupied));
+        self.touch(id);
+        if occupied {
+            Occupied(OccupiedEntry { map: self, id: id })
+        } else {
+            Vacant(VacantEntry { map: self, id: id })
+        }
+    }
+
+    /// Get handles to the entries of two distinct keys at once.
+    ///
+    /// Panics if both keys store their values in the same slot, being the
+    /// same key or aliases of each other.
+    pub fn entries<'a, K1: Assoc<V1>, V1: 'static, K2: Assoc<V2>, V2: 'static>(&'a mut self)
+                                                                              -> Entries<'a, K1, V1, K2, V2, S> {
+        if self.slot::<K1>() == self.slot::<K2>() {
+            panic!("cannot get entries for {} and {}, which share a slot", type_name::<K1>(), type_name::<K2>())
+        }
+        Entries { map: self }
+    }
+
+    /// Get the entries of the map, in the order its storage keeps them.
+    pub fn iter<'a>(&'a self) -> vec::MoveItems<(TypeId, &'a (Any + 'static))> {
+        self.data.entries()
+    }
+
+    /// Get the entries of the map in increasing order of iteration
+    /// priority.
+    ///
+    /// Keys without a priority have priority 0. Entries with the same
+    /// priority are ordered by the name of their key type, so the order is
+    /// the same in every build, which makes it usable for golden files.
+    /// Entries whose key type the map cannot name, because their value was
+    /// only ever stored through an alias, come last, ordered by TypeId,
+    /// which is only the same within a build.
+    pub fn iter_ordered<'a>(&'a self) -> vec::MoveItems<(TypeId, &'a (Any + 'static))> {
+        let mut entries: Vec<(int, Option<&'static str>, u64, TypeId, &'a (Any + 'static))> = self.data.entries().map(|(id, val)| {
+            let priority = self.iteration.find(&id).map(|&p| p).unwrap_or(0);
+            (priority, self.names.find(&id).map(|&name| name), id.hash(), id, val)
+        }).collect();
+        entries.sort_by(|&(a, a_name, a_hash, _, _), &(b, b_name, b_hash, _, _)| {
+            (a, a_name.is_none(), a_name, a_hash).cmp(&(b, b_name.is_none(), b_name, b_hash))
+        });
+        entries.into_iter().map(|(_, _, _, id, val)| (id, val)).collect::<Vec<_>>().into_iter()
+    }
+
+    /// Read the underlying storage.
+    pub unsafe fn data(&self) -> &S { &self.data }
+
+    /// Get a mutable reference to the underlying storage.
+    ///
+    /// Every entry is treated as modified, since any of them may be.
+    pub unsafe fn data_mut(&mut self) -> &mut S {
+        let ids: Vec<TypeId> = self.data.entries().map(|(id, _)| id).collect();
+        for id in ids.into_iter() { self.touch(id) }
+        &mut self.data
+    }
+
+    /// Limit the number of entries the map will hold, or lift the limit.
+    ///
+    /// Inserting a value for a new key into a full map panics, and
+    /// `try_insert` fails with `CapacityExceeded`; nothing is evicted.
+    /// Replacing existing values is always allowed. Lowering the limit
+    /// below the number of entries does not remove any.
+    pub fn set_max_entries(&mut self, max: Option<uint>) {
+        self.max_entries = max;
+    }
+
+    /// Start recording hits, misses, inserts and removals for each key.
+    ///
+    /// Does nothing if statistics are already being recorded.
+    pub fn enable_stats(&mut self) {
+        if self.stats.is_none() {
+            self.stats = Some(RefCell::new(HashMap::new()));
+        }
+    }
+
+    /// Get a report of the access statistics recorded so far.
+    ///
+    /// Returns `None` if statistics are not being recorded.
+    pub fn stats(&self) -> Option<StatsReport> {
+        self.stats.as_ref().map(|stats| stats.borrow().clone())
+    }
+
+    /// List the key types with values which were inserted but never read,
+    /// either because they were removed, replaced or cleared first, or
+    /// because they are still stored unread. Keys are sorted by name.
+    ///
+    /// Lookups through `find`, `find_mut` and `entry` count as reads.
+    /// Returns `None` if statistics are not being recorded.
+    pub fn unused_report(&self) -> Option<Vec<UnusedKey>> {
+        self.stats.as_ref().map(|stats| {
+            let mut unused: Vec<UnusedKey> = stats.borrow().iter().filter_map(|(id, stats)| {
+                let stored = stats.unread && self.contains_id(id);
+                if stats.unused == 0 && !stored { return None }
+                Some(UnusedKey { name: stats.name, discarded: stats.unused, stored: stored })
+            }).collect();
+            unused.sort_by(|a, b| a.name.cmp(&b.name));
+            unused
+        })
+    }
+
+    /// Get the capacity, load factor, number of reallocations and, if the
+    /// storage can tell, the probe-length distribution of the table storing
+    /// the map's entries.
+    ///
+    /// These are always tracked, whether or not access statistics are
+    /// being recorded.
+    pub fn table_stats(&self) -> TableStats {
+        let capacity = self.data.allocated();
+        let probe_lengths = self.data.probe_lengths().map(|lengths| {
+            let mut distribution = Vec::new();
+            for &length in lengths.iter() {
+                if length > distribution.len() { distribution.grow(length - distribution.len(), 0u) }
+                distribution[length - 1] += 1;
+            }
+            distribution
+        });
+        TableStats {
+            len: self.data.len(),
+            capacity: capacity,
+            load_factor: if capacity == 0 { 0.0 } else { self.data.len() as f64 / capacity as f64 },
+            resizes: self.resizes,
+            probe_lengths: probe_lengths
         }
     }
 
     /// Insert a value into the map with a specified key type.
+    ///
+    /// Zero-sized values, like marker structs used as flags, are boxed
+    /// without allocating, so using the map as a type-keyed flag set only
+    /// costs the table entry.
+    ///
+    /// The allocations of removed values are kept in a small free-list and
+    /// reused for later values with the same size and alignment.
     pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
-        self.data.insert(TypeId::of::<K>(), box val as Box<Any>)
+        let val = self.free.boxed(val);
+        self.insert_boxed::<K, V>(val)
+    }
+
+    /// Insert an already boxed value into the map, without reallocating it.
+    pub fn insert_box<K: Assoc<V>, V: 'static>(&mut self, val: Box<V>) -> bool {
+        self.insert_boxed::<K, V>(val as Box<Any + 'static>)
+    }
+
+    fn insert_boxed<K: Assoc<V>, V: 'static>(&mut self, val: Box<Any + 'static>) -> bool {
+        let mut trace = Trace::start::<K>("insert");
+        self.record::<K>(|stats| stats.insert());
+        let id = self.slot::<K>();
+        self.check_insert::<K>(&id);
+        let new = match self.store_id(id, val) {
+            Ok(Some(old)) => {
+                match self.history.find_mut(&id) {
+                    Some(history) => history.push(old),
+                    None => unsafe { self.free.release::<V>(old) }
+                }
+                false
+            },
+            Ok(None) => true,
+            Err(_) => unreachable!()
+        };
+        if !new { trace.rename("replace") }
+        self.expected.record::<V>(id);
+        self.debug_check();
+        new
+    }
+
+    /// Insert a value into the map, recording the file and line of the insert
+    /// in debug builds.
+    ///
+    /// This is usually called through the `typemap_insert!` macro.
+    pub fn insert_at<K: Assoc<V>, V: 'static>(&mut self, val: V, file: &'static str, line: uint) -> bool {
+        let id = self.slot::<K>();
+        self.provenance.record(id, Location { file: file, line: line });
+        self.insert::<K, V>(val)
+    }
+
+    /// Get the locations of the most recent recorded inserts for a key,
+    /// oldest first.
+    ///
+    /// Only inserts made through `insert_at` are recorded, and only in debug
+    /// builds; release builds always return an empty slice.
+    pub fn provenance<K: Assoc<V>, V: 'static>(&self) -> &[Location] {
+        self.provenance.writes(&self.slot::<K>())
     }
 
     /// Find a value in the map and get a reference to it.
     pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
-        self.data.find(&TypeId::of::<K>()).map(|v| unsafe {
+        let _trace = Trace::start::<K>("find");
+        let found = self.data.find(&self.slot::<K>()).map(|v| unsafe {
             v.downcast_ref_unchecked::<V>()
-        })
+        });
+        self.record::<K>(|stats| stats.lookup(found.is_some()));
+        found
     }
 
     /// Find a value in the map and get a mutable reference to it.
     pub fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> {
-        self.data.find_mut(&TypeId::of::<K>()).map(|v| unsafe {
+        let _trace = Trace::start::<K>("find_mut");
+        let id = self.slot::<K>();
+        if self.is_frozen_id(&id) { return None }
+        let found = self.data.contains(&id);
+        self.record::<K>(|stats| stats.lookup(found));
+        if found { self.touch(id) }
+        self.data.find_mut(&id).map(|v| unsafe {
             v.downcast_mut_unchecked::<V>()
         })
     }
 
+    /// Run a closure on the value of a key, if there is one, returning its
+    /// result.
+    ///
+    /// Returns `None` without running it if the key has no value or is
+    /// frozen; `try_apply` tells the two apart.
+    pub fn apply<K: Assoc<V>, V: 'static, R>(&mut self, f: |&mut V| -> R) -> Option<R> {
+        self.find_mut::<K, V>().map(f)
+    }
+
+    /// Run a closure on the value of a key, returning its result, failing
+    /// with `MissingKey` if there is none and with `Frozen` if it is frozen.
+    pub fn try_apply<K: Assoc<V>, V: 'static, R>(&mut self, f: |&mut V| -> R) -> Result<R, TypeMapError> {
+        self.try_find_mut::<K, V>().map(f)
+    }
+
+    /// Find a value in the map and get a clone of it.
+    pub fn find_cloned<K: Assoc<V>, V: Clone + 'static>(&self) -> Option<V> {
+        self.find::<K, V>().map(|v| v.clone())
+    }
+
+    /// Find a value in the map and get a copy of it.
+    pub fn find_copied<K: Assoc<V>, V: Copy + 'static>(&self) -> Option<V> {
+        self.find::<K, V>().map(|&v| v)
+    }
+
+    /// Find a value in the map and get a reference to what it dereferences
+    /// to, such as the contents of an `Rc`.
+    pub fn find_deref<K: Assoc<V>, V: Deref<T> + 'static, Sized? T>(&self) -> Option<&T> {
+        self.find::<K, V>().map(|v| v.deref())
+    }
+
+    /// Find a value in the map, failing with `MissingKey` if there is none.
+    pub fn try_find<K: Assoc<V>, V: 'static>(&self) -> Result<&V, TypeMapError> {
+        match self.find::<K, V>() {
+            Some(val) => Ok(val),
+            None => Err(MissingKey(type_name::<K>(), type_name::<V>()))
+        }
+    }
+
+    /// Find a value in the map and get a mutable reference to it, failing
+    /// with `MissingKey` if there is none and with `Frozen` if it is frozen.
+    pub fn try_find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Result<&mut V, TypeMapError> {
+        if self.is_frozen::<K, V>() { return Err(Frozen(type_name::<K>(), type_name::<V>())) }
+        match self.find_mut::<K, V>() {
+            Some(val) => Ok(val),
+            None => Err(MissingKey(type_name::<K>(), type_name::<V>()))
+        }
+    }
+
+    /// Find a value in the map, panicking with a message naming the key
+    /// if there is none.
+    pub fn expect_find<K: Assoc<V>, V: 'static>(&self) -> &V {
+        match self.find::<K, V>() {
+            Some(val) => val,
+            None => missing::<K, V>()
+        }
+    }
+
+    /// Find a value in the map and get a mutable reference to it,
+    /// panicking with a message naming the key if there is none or it is
+    /// frozen.
+    pub fn expect_find_mut<K: Assoc<V>, V: 'static>(&mut self) -> &mut V {
+        if self.is_frozen::<K, V>() {
+            panic!("cannot borrow the value of frozen key {} mutably", type_name::<K>())
+        }
+        match self.find_mut::<K, V>() {
+            Some(val) => val,
+            None => missing::<K, V>()
+        }
+    }
+
     /// Check if a key has an associated value stored in the map.
     pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
-        self.data.contains_key(&TypeId::of::<K>())
+        self.data.contains(&self.slot::<K>())
+    }
+
+    /// Check that every key type of a schema has a value stored in the map.
+    ///
+    /// The error lists the names of all missing key types.
+    pub fn validate<S: Schema>(&self) -> Result<(), MissingKeys> {
+        let mut missing = Vec::new();
+        Schema::missing(None::<S>, self, &mut missing);
+        if missing.is_empty() { Ok(()) } else { Err(MissingKeys { keys: missing }) }
     }
 
     /// Remove a value from the map.
     ///
     /// Returns `true` if a value was removed.
     pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
-        self.data.remove(&TypeId::of::<K>())
+        let _trace = Trace::start::<K>("remove");
+        let id = self.slot::<K>();
+        let removed = match self.take_id(id) {
+            Some(val) => { unsafe { self.free.release::<V>(val) }; true },
+            None => false
+        };
+        if removed { self.record::<K>(|stats| stats.remove()) }
+        self.debug_check();
+        removed
     }
 
-    /// Get the given key's corresponding entry in the map for in-place manipulation.
-    pub fn entry<'a, K: Assoc<V>, V: 'static>(&'a mut self) -> Entry<'a, K, V> {
-        match self.data.entry(TypeId::of::<K>()) {
-            hashmap::Occupied(e) => Occupied(OccupiedEntry { data: e }),
-            hashmap::Vacant(e) => Vacant(VacantEntry { data: e })
+    /// Insert a value for a key which has none.
+    ///
+    /// Fails with `Frozen` if the key is frozen, with `KeyOccupied` if it
+    /// already has a value, and with `CapacityExceeded` if the map or its
+    /// storage is full.
+    pub fn try_insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> Result<(), TypeMapError> {
+        if self.is_frozen::<K, V>() { return Err(Frozen(type_name::<K>(), type_name::<V>())) }
+        if self.contains::<K, V>() {
+            return Err(KeyOccupied(type_name::<K>(), type_name::<V>()))
         }
+        match self.capacity() {
+            Some(capacity) if self.data.len() >= capacity => return Err(CapacityExceeded(capacity)),
+            _ => ()
+        }
+        self.insert::<K, V>(val);
+        Ok(())
     }
 
-    /// Read the underlying HashMap
-    pub unsafe fn data(&self) -> &HashMap<TypeId, Box<Any + 'static>> { &self.data }
+    /// Remove a value from the map and return it, failing with
+    /// `MissingKey` if there is none and with `Frozen` if it is frozen.
+    pub fn try_remove<K: Assoc<V>, V: 'static>(&mut self) -> Result<V, TypeMapError> {
+        if self.is_frozen::<K, V>() { return Err(Frozen(type_name::<K>(), type_name::<V>())) }
+        match self.remove_box::<K, V>() {
+            Some(val) => Ok(*val),
+            None => Err(MissingKey(type_name::<K>(), type_name::<V>()))
+        }
+    }
 
-    /// Get a mutable reference to the underlying HashMap
-    pub unsafe fn data_mut(&mut self) -> &mut HashMap<TypeId, Box<Any + 'static>> { &mut self.data }
+    /// Remove a value from the map, returning its box intact.
+    pub fn remove_box<K: Assoc<V>, V: 'static>(&mut self) -> Option<Box<V>> {
+        let _trace = Trace::start::<K>("remove");
+        let id = self.slot::<K>();
+        let removed = self.take_id(id).map(|val| unsafe {
+            val.downcast_unchecked::<V>()
+        });
+        if removed.is_some() { self.record::<K>(|stats| stats.remove()) }
+        self.debug_check();
+        removed
+    }
 
     /// Get the number of values stored in the map.
     pub fn len(&self) -> uint {
@@ -87,82 +615,480 @@ impl TypeMap {
 
     /// Return true if the map contains no values.
     pub fn is_empty(&self) -> bool {
-        self.data.is_empty()
+        self.data.len() == 0
+    }
+
+    /// Set the priority of a key in `iter_ordered`, which yields entries in
+    /// increasing order of priority.
+    pub fn set_iteration_priority<K: Assoc<V>, V: 'static>(&mut self, priority: int) {
+        let id = self.slot::<K>();
+        if priority == 0 {
+            self.iteration.remove(&id);
+        } else {
+            self.iteration.insert(id, priority);
+        }
     }
 
-    /// Remove all entries from the map.
+    /// Set the priority with which a key's value is dropped.
+    ///
+    /// When the map is cleared or dropped, values are dropped in increasing
+    /// order of priority. Keys without a priority have priority 0 and are
+    /// dropped in no particular order, so a value which others depend on,
+    /// like a connection pool, should be given a positive priority to be
+    /// dropped after them.
+    pub fn set_drop_priority<K: Assoc<V>, V: 'static>(&mut self, priority: int) {
+        let id = self.slot::<K>();
+        if priority == 0 {
+            self.priorities.remove(&id);
+        } else {
+            self.priorities.insert(id, priority);
+        }
+    }
+
+    /// Remove all entries from the map, dropping them in order of priority.
+    ///
+    /// Frozen values are kept, and stay frozen.
     pub fn clear(&mut self) {
-        self.data.clear()
+        let removed: Vec<TypeId> = self.data.entries().map(|(id, _)| id)
+            .filter(|id| !self.is_frozen_id(id)).collect();
+        let mut kept = Vec::new();
+        for id in self.frozen.iter() {
+            match self.data.pop(id) {
+                Some(val) => kept.push((*id, val)),
+                None => ()
+            }
+        }
+        if kept.is_empty() {
+            self.provenance.clear();
+            self.expected.clear();
+        } else {
+            for (id, _) in self.data.entries() {
+                self.provenance.forget(&id);
+                self.expected.forget(&id);
+            }
+        }
+        for (_, history) in self.history.iter_mut() { history.clear() }
+        match self.stats {
+            Some(ref stats) => {
+                for (id, stats) in stats.borrow_mut().iter_mut() {
+                    if !self.is_frozen_id(&self.resolve(*id)) { stats.discard() }
+                }
+            },
+            None => ()
+        }
+        self.teardown();
+        for (id, val) in kept.into_iter() { self.data.swap(id, val); }
+        for id in removed.into_iter() { self.touch(id) }
+    }
+
+    /// Verify the internal consistency of the map, panicking if it is
+    /// broken.
+    ///
+    /// The storage checks its own bookkeeping. With the `invariants`
+    /// feature, every stored value is also checked to have the type its key
+    /// was inserted with, which is what the unchecked downcasts used for
+    /// lookups rely on; debug builds then run this check after every
+    /// insert and removal.
+    pub fn check_invariants(&self) {
+        self.data.check_invariants();
+        self.expected.check(&self.data);
+    }
+
+    #[cfg(all(feature = "invariants", not(ndebug)))]
+    fn debug_check(&self) {
+        self.check_invariants()
+    }
+
+    #[cfg(not(all(feature = "invariants", not(ndebug))))]
+    #[inline(always)]
+    fn debug_check(&self) {}
+
+    fn teardown(&mut self) {
+        let mut prioritised = Vec::new();
+        for (id, &priority) in self.priorities.iter() {
+            match self.data.pop(id) {
+                Some(val) => prioritised.push((priority, val)),
+                None => ()
+            }
+        }
+        prioritised.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+
+        // Values with a negative priority go before the unprioritised rest,
+        // and values with a positive priority after them.
+        let mut cleared = false;
+        for (priority, val) in prioritised.into_iter() {
+            if priority > 0 && !cleared {
+                self.data.clear();
+                cleared = true;
+            }
+            drop(val);
+        }
+        if !cleared { self.data.clear() }
+    }
+
+    /// Make the key `Alias` share the value of the key `Target`, so both
+    /// keys find, insert and remove the same value.
+    ///
+    /// This lets a key type be replaced by a new one while callers still
+    /// use the old one. A value already stored for `Alias` is moved to
+    /// `Target`, replacing its value.
+    pub fn alias<Alias: Assoc<V>, Target: Assoc<V>, V: 'static>(&mut self) {
+        let (alias, target) = (TypeId::of::<Alias>(), self.slot::<Target>());
+        if target == alias { return }
+        if !self.aliases.contains_key(&alias) {
+            if self.data.contains(&alias) && (self.is_frozen_id(&alias) || self.is_frozen_id(&target)) {
+                panic!("cannot alias {} to {}, one of them is frozen", type_name::<Alias>(), type_name::<Target>())
+            }
+            self.rekey::<Alias, Target, V>();
+        }
+        self.aliases.insert(alias, target);
+    }
+
+    // The number of entries the map will hold: the smaller of its own
+    // limit and its storage's.
+    fn capacity(&self) -> Option<uint> {
+        match (self.max_entries, self.data.capacity()) {
+            (Some(max), Some(capacity)) => Some(cmp::min(max, capacity)),
+            (Some(max), None) => Some(max),
+            (None, capacity) => capacity
+        }
+    }
+
+    /// Get the TypeId of the slot a key's value is stored in.
+    fn slot<K: 'static>(&self) -> TypeId {
+        self.resolve(TypeId::of::<K>())
+    }
+
+    fn resolve(&self, id: TypeId) -> TypeId {
+        if self.aliases.is_empty() { return id }
+        match self.aliases.find(&id) {
+            Some(&target) => target,
+            None => id
+        }
+    }
+
+    // Mark an entry as modified: if the map is tracking modified entries it
+    // becomes dirty, it is to be sent by `take_changes` and written by
+    // `flush_dirty`, and the cached values of the derived keys computed
+    // from it, directly or not, are evicted.
+    fn touch(&mut self, id: TypeId) {
+        match self.tracking {
+            Some(ref mut tracking) => tracking.mark(id),
+            None => ()
+        }
+        if self.dependents.is_empty() { return }
+
+        let stale = match self.dependents.find(&id) {
+            Some(dependents) => dependents.clone(),
+            None => return
+        };
+        // Evicting a derived value touches it in turn, dropping the values
+        // derived from it.
+        for derived in stale.into_iter() {
+            self.evict_id(derived);
+        }
+    }
+
+    // Panic, naming the key, if a value cannot be stored for it.
+    fn check_insert<K: 'static>(&mut self, id: &TypeId) {
+        if self.is_frozen_id(id) { panic!("cannot replace the value of frozen key {}", type_name::<K>()) }
+        match self.capacity() {
+            Some(capacity) if !self.can_store(id) =>
+                panic!("TypeMap is full ({} entries), cannot insert {}", capacity, type_name::<K>()),
+            _ => ()
+        }
+        self.name_slot(*id, TypeId::of::<K>(), type_name::<K>());
+    }
+
+    // Remember the name of a key type whose value is stored in its own
+    // slot, for `iter_ordered`. Aliases are not named, so the name of a
+    // slot does not depend on which key stored its value.
+    fn name_slot(&mut self, id: TypeId, key: TypeId, name: &'static str) {
+        if id == key { self.names.insert(id, name); }
+    }
+
+    // Check that a value can be stored for an id: it is not frozen, and
+    // the map and its storage have room for it, if it is a new key.
+    fn can_store(&self, id: &TypeId) -> bool {
+        if self.is_frozen_id(id) { return false }
+        match self.capacity() {
+            Some(capacity) => self.data.len() < capacity || self.data.contains(id),
+            None => true
+        }
+    }
+
+    // Store a value for an id, returning the value it replaces. Every path
+    // which stores values goes through here, so none can replace a frozen
+    // value or overfill the map; the value is handed back if it cannot be
+    // stored.
+    fn store_id(&mut self, id: TypeId, val: Box<Any + 'static>) -> Result<Option<Box<Any + 'static>>, Box<Any + 'static>> {
+        if !self.can_store(&id) { return Err(val) }
+        let allocated = self.data.allocated();
+        let old = self.data.swap(id, val);
+        if self.data.allocated() != allocated { self.resizes += 1 }
+        self.touch(id);
+        Ok(old)
+    }
+
+    // Remove the value of an id. Every path which removes values goes
+    // through here, so frozen values are never removed.
+    fn take_id(&mut self, id: TypeId) -> Option<Box<Any + 'static>> {
+        if self.is_frozen_id(&id) { return None }
+        let val = self.data.pop(&id);
+        if val.is_some() { self.touch(id) }
+        self.provenance.forget(&id);
+        self.expected.forget(&id);
+        val
+    }
+
+    // Remove the value of an id where the key type is not known, like a
+    // bulk removal, recording the removal for the key types stored there.
+    fn evict_id(&mut self, id: TypeId) -> Option<Box<Any + 'static>> {
+        let val = self.take_id(id);
+        if val.is_some() { self.record_id(&id, |stats| stats.remove()) }
+        val
+    }
+
+    // Store the decoded values of registered keys, removing the values of
+    // those paired with `None`, like `apply_changes` and `load`. Frozen
+    // keys, and new keys which would overfill the map, are skipped.
+    // Returns the slots changed.
+    fn store_decoded(&mut self, decoded: Vec<(&KeyInfo, Option<Box<Any + 'static>>)>) -> Vec<TypeId> {
+        let mut changed = Vec::new();
+        for (info, val) in decoded.into_iter() {
+            let slot = self.resolve(info.key());
+            if self.is_frozen_id(&slot) { continue }
+            match val {
+                Some(val) => match self.store_id(slot, val) {
+                    Ok(_) => {
+                        self.record_id(&slot, |stats| stats.insert());
+                        self.expected.record_type(slot, info.value(), info.value_name());
+                        self.name_slot(slot, info.key(), info.name());
+                    },
+                    Err(_) => continue
+                },
+                None => { self.evict_id(slot); }
+            }
+            changed.push(slot);
+        }
+        self.debug_check();
+        changed
+    }
+
+    fn is_frozen_id(&self, id: &TypeId) -> bool {
+        !self.frozen.is_empty() && self.frozen.contains(id)
+    }
+
+    fn contains_id(&self, id: &TypeId) -> bool {
+        self.data.contains(&self.resolve(*id))
+    }
+
+    fn record<K: 'static>(&self, f: |&mut KeyStats|) {
+        match self.stats {
+            Some(ref stats) => {
+                let mut stats = stats.borrow_mut();
+                match stats.entry(TypeId::of::<K>()) {
+                    hashmap::Occupied(mut e) => f(e.get_mut()),
+                    hashmap::Vacant(e) => f(e.set(KeyStats::new(type_name::<K>())))
+                }
+            },
+            None => ()
+        }
+    }
+
+    // Record an access to a slot in the statistics of every key type
+    // stored in it which has statistics.
+    fn record_id(&self, id: &TypeId, f: |&mut KeyStats|) {
+        match self.stats {
+            Some(ref stats) => {
+                for (key, stats) in stats.borrow_mut().iter_mut() {
+                    if self.resolve(*key) == *id { f(stats) }
+                }
+            },
+            None => ()
+        }
+    }
+}
+
+impl<S: Storage> TypeMapLike for TypeMap<S> {
+    fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool { self.insert::<K, V>(val) }
+
+    fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> { self.find::<K, V>() }
+
+    fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> { self.find_mut::<K, V>() }
+
+    fn contains<K: Assoc<V>, V: 'static>(&self) -> bool { self.contains::<K, V>() }
+
+    fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool { self.remove::<K, V>() }
+
+    fn len(&self) -> uint { self.len() }
+
+    fn is_empty(&self) -> bool { self.is_empty() }
+}
+
+#[unsafe_destructor]
+impl<S: Storage> Drop for TypeMap<S> {
+    fn drop(&mut self) {
+        if !self.priorities.is_empty() { self.teardown() }
     }
 }
 
+/// Decode the JSON of a value written at a version of its key, upgrading
+/// it with the key's migrations first.
+fn decode_value(info: &KeyInfo, version: u64, val: Json) -> Result<Box<Any + 'static>, ReplicationError> {
+    match info.upgrade(version, val) {
+        Some(val) => info.decode(val).unwrap().map_err(replicate::Decode),
+        None => Err(replicate::Version(version))
+    }
+}
+
+/// Panic because a key has no value, suggesting why not.
+fn missing<K: 'static, V: 'static>() -> ! {
+    panic!("TypeMap has no value for key {} (value type {}). Was it inserted before \
+            this lookup, and not removed since? Values inserted under a different key \
+            type, or into another map, are not found.", type_name::<K>(), type_name::<V>())
+}
+
+/// Get a human-readable name for a type.
+fn type_name<T>() -> &'static str {
+    unsafe { intrinsics::type_name::<T>() }
+}
+
 /// A view onto an entry in a TypeMap.
-pub enum Entry<'a, K, V> {
+pub enum Entry<'a, K, V, S: 'a = HashMap<TypeId, Box<Any + 'static>>> {
     /// A view onto an occupied entry in a TypeMap.
-    Occupied(OccupiedEntry<'a, K, V>),
+    Occupied(OccupiedEntry<'a, K, V, S>),
     /// A view onto an unoccupied entry in a TypeMap.
-    Vacant(VacantEntry<'a, K, V>)
+    Vacant(VacantEntry<'a, K, V, S>)
 }
 
 /// A view onto an occupied entry in a TypeMap.
-pub struct OccupiedEntry<'a, K, V> {
-    data: hashmap::OccupiedEntry<'a, TypeId, Box<Any + 'static>>
+pub struct OccupiedEntry<'a, K, V, S: 'a = HashMap<TypeId, Box<Any + 'static>>> {
+    map: &'a mut TypeMap<S>,
+    id: TypeId
 }
 
 /// A view onto an unoccupied entry in a TypeMap.
-pub struct VacantEntry<'a, K, V> {
-    data: hashmap::VacantEntry<'a, TypeId, Box<Any + 'static>>
+pub struct VacantEntry<'a, K, V, S: 'a = HashMap<TypeId, Box<Any + 'static>>> {
+    map: &'a mut TypeMap<S>,
+    id: TypeId
 }
 
-impl<'a, K, V: 'static> OccupiedEntry<'a, K, V> {
+impl<'a, K: Assoc<V>, V: 'static, S: Storage> OccupiedEntry<'a, K, V, S> {
     /// Get a reference to the entry's value.
     pub fn get(&self) -> &V {
         unsafe {
-            self.data.get().downcast_ref_unchecked::<V>()
+            self.map.data.find(&self.id).unwrap().downcast_ref_unchecked::<V>()
         }
     }
 
     /// Get a mutable reference to the entry's value.
     pub fn get_mut(&mut self) -> &mut V {
         unsafe {
-            self.data.get_mut().downcast_mut_unchecked::<V>()
+            self.map.data.find_mut(&self.id).unwrap().downcast_mut_unchecked::<V>()
         }
     }
 
     /// Transform the entry into a mutable reference with the same lifetime as the map.
     pub fn into_mut(self) -> &'a mut V {
+        let OccupiedEntry { map, id } = self;
         unsafe {
-            self.data.into_mut().downcast_mut_unchecked::<V>()
+            map.data.find_mut(&id).unwrap().downcast_mut_unchecked::<V>()
         }
     }
 
-    /// Set the entry's value and return the previous value.
-    pub fn set(&mut self, value: V) -> V {
-        unsafe {
-            *self.data.set(box value as Box<Any + 'static>).downcast_unchecked::<V>()
+    /// Transform the entry into a reference with the same lifetime as the map.
+    pub fn into_ref(self) -> &'a V {
+        &*self.into_mut()
+    }
+
+    /// Set the entry's value, like `TypeMap::insert`.
+    ///
+    /// Returns the previous value, or `None` if it was kept in the key's
+    /// history.
+    pub fn set(&mut self, value: V) -> Option<V> {
+        let id = self.id;
+        self.map.record::<K>(|stats| stats.insert());
+        let val = self.map.free.boxed(value);
+        let old = match self.map.store_id(id, val) {
+            Ok(Some(old)) => old,
+            _ => unreachable!()
+        };
+        self.map.expected.record::<V>(id);
+        self.map.debug_check();
+        match self.map.history.find_mut(&id) {
+            Some(history) => { history.push(old); None },
+            None => Some(unsafe { self.map.free.take::<V>(old) })
         }
     }
 
     /// Move the entry's value out of the map, consuming the entry.
     pub fn take(self) -> V {
-        unsafe {
-            *self.data.take().downcast_unchecked::<V>()
-        }
+        let OccupiedEntry { map, id } = self;
+        let val = map.take_id(id).unwrap();
+        map.record::<K>(|stats| stats.remove());
+        map.debug_check();
+        unsafe { map.free.take::<V>(val) }
     }
 }
 
-impl<'a, K, V: 'static> VacantEntry<'a, K, V> {
-    /// Set the entry's value and return a mutable reference to it.
+impl<'a, K: Assoc<V>, V: 'static, S: Storage> VacantEntry<'a, K, V, S> {
+    /// Set the entry's value, like `TypeMap::insert`, and return a mutable
+    /// reference to it.
     pub fn set(self, value: V) -> &'a mut V {
+        let VacantEntry { map, id } = self;
+        map.insert::<K, V>(value);
         unsafe {
-            self.data.set(box value as Box<Any + 'static>).downcast_mut_unchecked::<V>()
+            map.data.find_mut(&id).unwrap().downcast_mut_unchecked::<V>()
         }
     }
 }
 
+/// Views onto the entries of two distinct keys in a TypeMap.
+///
+/// Either entry can be taken in turn, and both values borrowed at once.
+pub struct Entries<'a, K1, V1, K2, V2, S: 'a = HashMap<TypeId, Box<Any + 'static>>> {
+    map: &'a mut TypeMap<S>
+}
+
+impl<'a, K1: Assoc<V1>, V1: 'static, K2: Assoc<V2>, V2: 'static, S: Storage> Entries<'a, K1, V1, K2, V2, S> {
+    /// Get the entry of the first key, like `TypeMap::entry`.
+    pub fn first<'b>(&'b mut self) -> Entry<'b, K1, V1, S> {
+        self.map.entry::<K1, V1>()
+    }
+
+    /// Get the entry of the second key, like `TypeMap::entry`.
+    pub fn second<'b>(&'b mut self) -> Entry<'b, K2, V2, S> {
+        self.map.entry::<K2, V2>()
+    }
+
+    /// Get mutable references to the values of both keys, like
+    /// `TypeMap::find_mut`.
+    pub fn find_mut<'b>(&'b mut self) -> (Option<&'b mut V1>, Option<&'b mut V2>) {
+        let first = self.map.find_mut::<K1, V1>().map(|val| val as *mut V1);
+        let second = self.map.find_mut::<K2, V2>();
+        // The keys have distinct slots, so the references cannot alias.
+        (first.map(|val| unsafe { &mut *val }), second)
+    }
+}
+
+impl<'a, K1: Assoc<V>, K2: Assoc<V>, V: 'static, S: Storage> Entries<'a, K1, V, K2, V, S> {
+    /// Move the value of the first key to the second, replacing any value
+    /// the second has, like `TypeMap::rekey`.
+    ///
+    /// Returns `true` if the first key had a value to move.
+    pub fn move_to_second(&mut self) -> bool {
+        self.map.rekey::<K1, K2, V>()
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{TypeMap, Assoc, Occupied, Vacant};
+    use std::cell::RefCell;
+    use std::intrinsics::TypeId;
+    use std::rc::Rc;
+    use super::{TypeMap, Assoc, Occupied, Vacant, MissingKey, KeyOccupied, CapacityExceeded};
+    use super::{UnusedKey, VecStorage, type_name};
 
     #[deriving(Show, PartialEq)]
     struct Key;
@@ -170,7 +1096,35 @@ mod test {
     #[deriving(Show, PartialEq)]
     struct Value;
 
+    #[deriving(Show, PartialEq)]
+    struct OtherKey;
+
+    struct Count;
+
+    struct Shared;
+
+    struct Pool;
+
+    struct User;
+
+    struct Logger;
+
+    struct Dropped(&'static str, Rc<RefCell<Vec<&'static str>>>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            let Dropped(name, ref order) = *self;
+            order.borrow_mut().push(name);
+        }
+    }
+
     impl Assoc<Value> for Key {}
+    impl Assoc<Value> for OtherKey {}
+    impl Assoc<uint> for Count {}
+    impl Assoc<Rc<uint>> for Shared {}
+    impl Assoc<Dropped> for Pool {}
+    impl Assoc<Dropped> for User {}
+    impl Assoc<Dropped> for Logger {}
 
     #[test] fn test_pairing() {
         let mut map = TypeMap::new();
@@ -187,6 +1141,169 @@ mod test {
         assert!(!map.contains::<Key, Value>());
     }
 
+    #[test] fn test_stats() {
+        let mut map = TypeMap::with_stats();
+        map.insert::<Key, Value>(Value);
+        map.find::<Key, Value>();
+        map.remove::<Key, Value>();
+        map.find::<Key, Value>();
+        map.remove::<Key, Value>();
+
+        let report = map.stats().unwrap();
+        let stats = report.find(&TypeId::of::<Key>()).unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.inserts, stats.removals), (1, 1, 1, 1));
+        assert!(TypeMap::new().stats().is_none());
+    }
+
+    #[test] fn test_unused_report() {
+        let mut map = TypeMap::with_stats();
+        map.insert::<Key, Value>(Value);
+        map.insert::<Key, Value>(Value);
+        map.insert::<Count, uint>(1);
+        map.find::<Count, uint>();
+
+        assert_eq!(map.unused_report().unwrap(),
+                   vec![UnusedKey { name: type_name::<Key>(), discarded: 1, stored: true }]);
+        map.clear();
+        assert_eq!(map.unused_report().unwrap(),
+                   vec![UnusedKey { name: type_name::<Key>(), discarded: 2, stored: false }]);
+        assert!(TypeMap::new().unused_report().is_none());
+    }
+
+    #[test] fn test_unused_report_bulk_removals() {
+        let mut map = TypeMap::with_stats();
+        map.set_frame_scoped::<Key, Value>();
+        map.insert::<Key, Value>(Value);
+        map.end_frame();
+        map.insert::<Count, uint>(1);
+        map.mark_for_removal::<Count, uint>();
+        map.sweep();
+
+        let unused = map.unused_report().unwrap();
+        assert_eq!(unused.len(), 2);
+        assert!(unused.iter().all(|key| key.discarded == 1 && !key.stored));
+    }
+
+    #[test] fn test_table_stats() {
+        let mut map = TypeMap::new();
+        assert_eq!(map.table_stats().resizes, 0);
+        map.insert::<Key, Value>(Value);
+        map.insert::<Count, uint>(1);
+
+        let stats = map.table_stats();
+        assert_eq!(stats.len, 2);
+        assert!(stats.capacity >= 2);
+        assert!(stats.resizes >= 1);
+        assert!(stats.load_factor > 0.0 && stats.load_factor <= 1.0);
+        assert_eq!(stats.probe_lengths, None);
+
+        let mut map = TypeMap::with_storage(VecStorage::new());
+        map.insert::<Key, Value>(Value);
+        map.insert::<Count, uint>(1);
+        assert_eq!(map.table_stats().probe_lengths, Some(vec![1, 1]));
+    }
+
+    #[cfg(not(ndebug))]
+    #[test] fn test_provenance() {
+        let mut map = TypeMap::new();
+        typemap_insert!(map, Key, Value, Value);
+        typemap_insert!(map, Key, Value, Value);
+        let writes = map.provenance::<Key, Value>();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].file, file!());
+        assert!(writes[0].line < writes[1].line);
+
+        map.remove::<Key, Value>();
+        assert!(map.provenance::<Key, Value>().is_empty());
+    }
+
+    #[cfg(feature = "invariants")]
+    #[test] #[should_fail] fn test_check_invariants() {
+        use std::any::Any;
+        let mut map = TypeMap::new();
+        map.insert::<Key, Value>(Value);
+        map.check_invariants();
+        unsafe { map.data_mut().insert(TypeId::of::<Key>(), box 5u as Box<Any + 'static>); }
+        map.check_invariants();
+    }
+
+    #[test] fn test_alias() {
+        let mut map = TypeMap::new();
+        map.insert::<Key, Value>(Value);
+        map.alias::<Key, OtherKey, Value>();
+        assert!(map.contains::<OtherKey, Value>());
+
+        map.remove::<Key, Value>();
+        assert!(!map.contains::<OtherKey, Value>());
+        map.insert::<OtherKey, Value>(Value);
+        assert_eq!(*map.find::<Key, Value>().unwrap(), Value);
+        assert_eq!(map.len(), 1);
+        assert!(map.validate::<(Key,)>().is_ok());
+    }
+
+    #[test] fn test_max_entries() {
+        let mut map = TypeMap::with_max_entries(1);
+        assert!(map.try_insert::<Key, Value>(Value).is_ok());
+        assert_eq!(map.try_insert::<Count, uint>(1), Err(CapacityExceeded(1)));
+        assert!(!map.insert::<Key, Value>(Value));
+
+        map.set_max_entries(None);
+        assert!(map.insert::<Count, uint>(1));
+    }
+
+    #[test] #[should_fail] fn test_insert_over_max_entries() {
+        let mut map = TypeMap::with_max_entries(1);
+        map.insert::<Key, Value>(Value);
+        map.insert::<Count, uint>(1);
+    }
+
+    #[test] fn test_apply() {
+        let mut map = TypeMap::new();
+        assert_eq!(map.apply::<Count, uint, uint>(|count| *count + 1), None);
+
+        map.insert::<Count, uint>(1);
+        assert_eq!(map.apply::<Count, uint, uint>(|count| { *count += 1; *count * 10 }), Some(20));
+        assert_eq!(*map.find::<Count, uint>().unwrap(), 2);
+    }
+
+    #[test] fn test_try_methods() {
+        let mut map = TypeMap::new();
+        match map.try_find::<Key, Value>() {
+            Err(MissingKey(..)) => (),
+            _ => panic!("Found a missing key.")
+        }
+        assert!(map.try_insert::<Key, Value>(Value).is_ok());
+        match map.try_insert::<Key, Value>(Value) {
+            Err(KeyOccupied(..)) => (),
+            _ => panic!("Inserted into an occupied key.")
+        }
+        assert_eq!(map.try_find::<Key, Value>(), Ok(&Value));
+        assert_eq!(map.try_remove::<Key, Value>(), Ok(Value));
+        assert!(map.try_remove::<Key, Value>().is_err());
+    }
+
+    #[test] fn test_expect_find() {
+        let mut map = TypeMap::new();
+        map.insert::<Count, uint>(1);
+        *map.expect_find_mut::<Count, uint>() += 1;
+        assert_eq!(*map.expect_find::<Count, uint>(), 2);
+    }
+
+    #[test] #[should_fail] fn test_expect_find_missing() {
+        TypeMap::new().expect_find::<Key, Value>();
+    }
+
+    #[test] fn test_reuses_allocations() {
+        let mut map = TypeMap::new();
+        map.insert::<Count, uint>(1);
+        let first = map.find::<Count, uint>().unwrap() as *const uint;
+        map.remove::<Count, uint>();
+
+        map.insert::<Count, uint>(2);
+        assert_eq!(map.find::<Count, uint>().unwrap() as *const uint, first);
+        assert_eq!(*map.find::<Count, uint>().unwrap(), 2);
+    }
+
     #[test] fn test_entry() {
         let mut map = TypeMap::new();
         map.insert::<Key, Value>(Value);
@@ -206,5 +1323,151 @@ mod test {
         }
         assert!(map.contains::<Key, Value>());
     }
-}
 
+    #[test] fn test_iter_ordered() {
+        let mut map = TypeMap::new();
+        map.set_iteration_priority::<Logger, Dropped>(1);
+        map.set_iteration_priority::<Pool, Dropped>(-1);
+        let order = Rc::new(RefCell::new(Vec::new()));
+        map.insert::<Logger, Dropped>(Dropped("logger", order.clone()));
+        map.insert::<User, Dropped>(Dropped("user", order.clone()));
+        map.insert::<Pool, Dropped>(Dropped("pool", order.clone()));
+
+        let ids: Vec<TypeId> = map.iter_ordered().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![TypeId::of::<Pool>(), TypeId::of::<User>(), TypeId::of::<Logger>()]);
+    }
+
+    #[test] fn test_drop_priority() {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        {
+            let mut map = TypeMap::new();
+            map.set_drop_priority::<Pool, Dropped>(10);
+            map.set_drop_priority::<Logger, Dropped>(-1);
+            map.insert::<Pool, Dropped>(Dropped("pool", order.clone()));
+            map.insert::<User, Dropped>(Dropped("user", order.clone()));
+            map.insert::<Logger, Dropped>(Dropped("logger", order.clone()));
+        }
+        assert_eq!(*order.borrow(), vec!["logger", "user", "pool"]);
+
+        order.borrow_mut().clear();
+        let mut map = TypeMap::new();
+        map.set_drop_priority::<User, Dropped>(1);
+        map.insert::<User, Dropped>(Dropped("user", order.clone()));
+        map.insert::<Pool, Dropped>(Dropped("pool", order.clone()));
+        map.clear();
+        assert_eq!(*order.borrow(), vec!["pool", "user"]);
+        assert!(map.is_empty());
+    }
+
+    #[test] fn test_into_inner_from_parts() {
+        let mut map = TypeMap::new();
+        map.insert::<Key, Value>(Value);
+        map.insert::<Count, uint>(3);
+
+        let entries = map.into_inner();
+        assert_eq!(entries.len(), 2);
+        let map = unsafe { TypeMap::from_parts(entries) };
+        assert_eq!(*map.find::<Key, Value>().unwrap(), Value);
+        assert_eq!(*map.find::<Count, uint>().unwrap(), 3);
+    }
+
+    #[test] fn test_find_cloned_copied() {
+        let mut map = TypeMap::new();
+        map.insert::<Count, uint>(4);
+        let count = map.find_copied::<Count, uint>().unwrap();
+        map.insert::<Count, uint>(count + 1);
+        assert_eq!(map.find_cloned::<Count, uint>(), Some(5));
+        map.remove::<Count, uint>();
+        assert_eq!(map.find_copied::<Count, uint>(), None);
+    }
+
+    #[test] fn test_find_deref() {
+        let mut map = TypeMap::new();
+        map.insert::<Shared, Rc<uint>>(Rc::new(7));
+        assert_eq!(map.find_deref::<Shared, Rc<uint>, uint>(), Some(&7));
+    }
+
+    #[test] fn test_insert_remove_box() {
+        let mut map = TypeMap::new();
+        let val = box 9u;
+        let ptr = &*val as *const uint;
+        assert!(map.insert_box::<Count, uint>(val));
+        assert_eq!(map.find::<Count, uint>().unwrap() as *const uint, ptr);
+
+        let val = map.remove_box::<Count, uint>().unwrap();
+        assert_eq!(&*val as *const uint, ptr);
+        assert!(map.remove_box::<Count, uint>().is_none());
+    }
+
+    #[test] fn test_entry_bookkeeping() {
+        let mut map = TypeMap::with_stats();
+        map.keep_history::<Count, uint>(1);
+        map.insert::<Count, uint>(1);
+        match map.entry::<Count, uint>() {
+            Occupied(mut e) => assert_eq!(e.set(2), None),
+            Vacant(_) => panic!("Unable to locate inserted item.")
+        }
+        assert_eq!(map.history::<Count, uint>(), vec![&1]);
+        match map.entry::<Count, uint>() {
+            Occupied(e) => assert_eq!(e.take(), 2),
+            Vacant(_) => panic!("Unable to locate inserted item.")
+        }
+        let report = map.stats().unwrap();
+        let stats = report.find(&TypeId::of::<Count>()).unwrap();
+        assert_eq!((stats.inserts, stats.removals), (2, 1));
+        assert!(!map.contains::<Count, uint>());
+    }
+
+    #[test] #[should_fail] fn test_entry_max_entries() {
+        let mut map = TypeMap::with_max_entries(1);
+        map.insert::<Key, Value>(Value);
+        match map.entry::<Count, uint>() {
+            Vacant(e) => { e.set(1); },
+            Occupied(_) => panic!("Found non-existant entry.")
+        }
+    }
+
+    #[test] fn test_entries() {
+        let mut map = TypeMap::new();
+        map.insert::<Key, Value>(Value);
+        map.insert::<Count, uint>(1);
+        {
+            let mut both = map.entries::<Key, Value, Count, uint>();
+            match both.find_mut() {
+                (Some(_), Some(count)) => *count += 1,
+                _ => panic!("Unable to locate inserted items.")
+            }
+            let taken = match both.first() {
+                Occupied(e) => Some(e.take()),
+                Vacant(_) => None
+            };
+            assert_eq!(taken, Some(Value));
+            match both.second() {
+                Occupied(e) => assert_eq!(*e.get(), 2),
+                Vacant(_) => panic!("Unable to locate inserted item.")
+            }
+        }
+        assert!(!map.contains::<Key, Value>());
+
+        map.insert::<Key, Value>(Value);
+        assert!(map.entries::<Key, Value, OtherKey, Value>().move_to_second());
+        assert!(!map.contains::<Key, Value>());
+        assert_eq!(*map.find::<OtherKey, Value>().unwrap(), Value);
+    }
+
+    #[test] #[should_fail] fn test_entries_same_slot() {
+        let mut map = TypeMap::new();
+        map.alias::<OtherKey, Key, Value>();
+        map.entries::<Key, Value, OtherKey, Value>();
+    }
+
+    #[test] fn test_entry_into_ref() {
+        let mut map = TypeMap::new();
+        map.insert::<Count, uint>(1);
+        let count = match map.entry::<Count, uint>() {
+            Occupied(e) => e.into_ref(),
+            Vacant(_) => panic!("Unable to locate inserted item.")
+        };
+        assert_eq!(*count, 1);
+    }
+}
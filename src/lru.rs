@@ -0,0 +1,289 @@
+//! A TypeMap bounded by a number of entries or a byte budget, evicting
+//! the least recently used entries first.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::intrinsics::TypeId;
+use std::{mem, uint};
+
+use evict::EvictionCallbacks;
+
+use super::{TypeMap, Assoc};
+
+/// A TypeMap holding at most a fixed number of entries, or a fixed number
+/// of bytes.
+///
+/// When an insert would overflow the map, the least recently used entries
+/// are evicted and passed to the callback registered for their key, if
+/// there is one.
+pub struct LruTypeMap {
+    map: TypeMap,
+    meta: HashMap<TypeId, Meta>,
+    sizes: HashMap<TypeId, Box<Any + 'static>>,
+    clock: Cell<u64>,
+    capacity: uint,
+    budget: uint,
+    bytes: uint,
+    callbacks: EvictionCallbacks
+}
+
+struct Meta {
+    used: Cell<u64>,
+    bytes: uint
+}
+
+impl LruTypeMap {
+    /// Create a new, empty LruTypeMap holding at most `capacity` entries.
+    pub fn with_capacity(capacity: uint) -> LruTypeMap {
+        LruTypeMap {
+            map: TypeMap::new(),
+            meta: HashMap::new(),
+            sizes: HashMap::new(),
+            clock: Cell::new(0),
+            capacity: capacity,
+            budget: uint::MAX,
+            bytes: 0,
+            callbacks: EvictionCallbacks::new()
+        }
+    }
+
+    /// Create a new, empty LruTypeMap holding at most `budget` bytes of values.
+    ///
+    /// The size of a value is measured when it is inserted, using the size
+    /// function registered for its key or `mem::size_of` if there is none.
+    pub fn with_byte_budget(budget: uint) -> LruTypeMap {
+        let mut map = LruTypeMap::with_capacity(uint::MAX);
+        map.budget = budget;
+        map
+    }
+
+    /// Set a callback to be run with the evicted values of a key, so that
+    /// resources they hold can be released or logged.
+    pub fn on_evict<K: Assoc<V>, V: 'static>(&mut self, callback: fn(V)) {
+        self.callbacks.set::<V>(TypeId::of::<K>(), callback);
+    }
+
+    /// Register a function measuring the size in bytes of values for a key.
+    ///
+    /// Only values inserted after registration are measured with it, and
+    /// values are not re-measured after being mutated in place.
+    pub fn register_size<K: Assoc<V>, V: 'static>(&mut self, size: fn(&V) -> uint) {
+        self.sizes.insert(TypeId::of::<K>(), box size as Box<Any + 'static>);
+    }
+
+    /// Insert a value into the map with a specified key type.
+    ///
+    /// If the map is full, the least recently used entries are evicted. A
+    /// value larger than the whole byte budget is evicted immediately.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        let id = TypeId::of::<K>();
+        let bytes = match self.sizes.find(&id).and_then(|f| f.downcast_ref::<fn(&V) -> uint>()) {
+            Some(size) => (*size)(&val),
+            None => mem::size_of::<V>()
+        };
+
+        let tick = self.tick();
+        match self.meta.swap(id, Meta { used: Cell::new(tick), bytes: bytes }) {
+            Some(old) => self.bytes -= old.bytes,
+            None => ()
+        }
+        self.bytes += bytes;
+        let new = self.map.insert::<K, V>(val);
+
+        while self.map.len() > self.capacity || self.bytes > self.budget {
+            if !self.evict_lru() { break }
+        }
+
+        new
+    }
+
+    /// Find a value in the map and get a reference to it.
+    ///
+    /// This marks the entry as the most recently used.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        self.touch(&TypeId::of::<K>());
+        self.map.find::<K, V>()
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    ///
+    /// This marks the entry as the most recently used.
+    pub fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> {
+        self.touch(&TypeId::of::<K>());
+        self.map.find_mut::<K, V>()
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    ///
+    /// This does not affect which entry is the least recently used.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.map.contains::<K, V>()
+    }
+
+    /// Remove a value from the map, without running the eviction callback.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        match self.meta.pop(&TypeId::of::<K>()) {
+            Some(meta) => self.bytes -= meta.bytes,
+            None => ()
+        }
+        self.map.remove::<K, V>()
+    }
+
+    /// Get the maximum number of entries the map will hold.
+    pub fn capacity(&self) -> uint {
+        self.capacity
+    }
+
+    /// Get the maximum number of bytes of values the map will hold.
+    pub fn byte_budget(&self) -> uint {
+        self.budget
+    }
+
+    /// Get the number of bytes of values currently stored in the map.
+    pub fn bytes(&self) -> uint {
+        self.bytes
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> uint {
+        self.map.len()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Remove all entries from the map, without running the eviction callback.
+    pub fn clear(&mut self) {
+        self.meta.clear();
+        self.bytes = 0;
+        self.map.clear()
+    }
+
+    fn tick(&self) -> u64 {
+        let tick = self.clock.get() + 1;
+        self.clock.set(tick);
+        tick
+    }
+
+    fn touch(&self, id: &TypeId) {
+        match self.meta.find(id) {
+            Some(meta) => meta.used.set(self.tick()),
+            None => ()
+        }
+    }
+
+    fn evict_lru(&mut self) -> bool {
+        let id = match self.meta.iter().min_by(|&(_, meta)| meta.used.get()) {
+            Some((&id, _)) => id,
+            None => return false
+        };
+
+        let meta = self.meta.pop(&id).unwrap();
+        self.bytes -= meta.bytes;
+        match self.map.evict_id(id) {
+            Some(val) => { self.callbacks.run(&id, val); },
+            None => ()
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+    use super::LruTypeMap;
+    use Assoc;
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct OtherKey;
+
+    #[deriving(Show, PartialEq)]
+    struct ThirdKey;
+
+    #[deriving(Show, PartialEq)]
+    struct Value;
+
+    impl Assoc<Value> for Key {}
+    impl Assoc<Value> for OtherKey {}
+    impl Assoc<Value> for ThirdKey {}
+
+    struct Bytes;
+    struct MoreBytes;
+    struct EvenMoreBytes;
+
+    impl Assoc<Vec<u8>> for Bytes {}
+    impl Assoc<Vec<u8>> for MoreBytes {}
+    impl Assoc<Vec<u8>> for EvenMoreBytes {}
+
+    static EVICTED: AtomicUint = INIT_ATOMIC_UINT;
+
+    fn count_evictions(_: Value) {
+        EVICTED.fetch_add(1, SeqCst);
+    }
+
+    #[test] fn test_evicts_least_recently_used() {
+        let mut map = LruTypeMap::with_capacity(2);
+        map.on_evict::<OtherKey, Value>(count_evictions);
+        map.insert::<Key, Value>(Value);
+        map.insert::<OtherKey, Value>(Value);
+        map.find::<Key, Value>();
+
+        map.insert::<ThirdKey, Value>(Value);
+        assert_eq!(map.len(), 2);
+        assert!(map.contains::<Key, Value>());
+        assert!(!map.contains::<OtherKey, Value>());
+        assert!(map.contains::<ThirdKey, Value>());
+        assert_eq!(EVICTED.load(SeqCst), 1);
+    }
+
+    #[test] fn test_key_eviction_callback() {
+        static CLOSED: AtomicUint = INIT_ATOMIC_UINT;
+
+        fn close(bytes: Vec<u8>) {
+            assert_eq!(bytes, vec![1, 2]);
+            CLOSED.fetch_add(1, SeqCst);
+        }
+
+        let mut map = LruTypeMap::with_capacity(1);
+        map.on_evict::<Bytes, Vec<u8>>(close);
+        map.insert::<Bytes, Vec<u8>>(vec![1, 2]);
+        map.insert::<MoreBytes, Vec<u8>>(vec![]);
+        map.insert::<EvenMoreBytes, Vec<u8>>(vec![]);
+        assert_eq!(CLOSED.load(SeqCst), 1);
+    }
+
+    #[test] fn test_byte_budget() {
+        fn size(_: &Vec<u8>) -> uint { 10 }
+
+        let mut map = LruTypeMap::with_byte_budget(25);
+        map.register_size::<Bytes, Vec<u8>>(size);
+        map.register_size::<MoreBytes, Vec<u8>>(size);
+        map.register_size::<EvenMoreBytes, Vec<u8>>(size);
+        map.insert::<Bytes, Vec<u8>>(vec![]);
+        map.insert::<MoreBytes, Vec<u8>>(vec![]);
+        assert_eq!(map.bytes(), 20);
+
+        map.insert::<EvenMoreBytes, Vec<u8>>(vec![]);
+        assert_eq!(map.bytes(), 20);
+        assert!(!map.contains::<Bytes, Vec<u8>>());
+
+        map.remove::<MoreBytes, Vec<u8>>();
+        assert_eq!(map.bytes(), 10);
+    }
+
+    #[test] fn test_replace_does_not_evict() {
+        let mut map = LruTypeMap::with_capacity(1);
+        map.insert::<Key, Value>(Value);
+        assert!(!map.insert::<Key, Value>(Value));
+        assert_eq!(map.len(), 1);
+    }
+}
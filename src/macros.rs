@@ -0,0 +1,154 @@
+//! Macros exported by this crate.
+
+/// Insert a value into a TypeMap, recording the location of the insert in
+/// debug builds.
+///
+/// ```ignore
+/// typemap_insert!(map, Key, Value, Value);
+/// ```
+#[macro_export]
+macro_rules! typemap_insert {
+    ($map:expr, $key:ty, $value:ty, $val:expr) => (
+        $map.insert_at::<$key, $value>($val, file!(), line!())
+    )
+}
+
+/// Define a struct storing a fixed set of keys in plain fields.
+///
+/// The struct implements `StaticTypeMap`, so values are accessed with the
+/// same `find::<K, V>()` vocabulary as a TypeMap, but every lookup compiles
+/// down to a field access with no hashing or boxing.
+///
+/// A static map can be frozen from, and spilled back into, a TypeMap. It
+/// can have at most twelve keys.
+///
+/// ```ignore
+/// static_typemap! {
+///     Context {
+///         user: UserKey => User,
+///         db: DbKey => Connection
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! static_typemap {
+    ($name:ident { $($field:ident: $key:ty => $value:ty),+ }) => (
+        /// A static map with a fixed set of keys.
+        pub struct $name {
+            $($field: Option<$value>),+
+        }
+
+        impl $name {
+            /// Create a new static map with no values.
+            pub fn new() -> $name {
+                $name { $($field: None),+ }
+            }
+
+            /// Move the value of every key of this map out of a TypeMap.
+            ///
+            /// Fails without modifying the TypeMap if any key is missing or
+            /// frozen in it.
+            pub fn freeze(map: &mut $crate::TypeMap) -> Result<$name, $crate::FreezeError> {
+                match map.validate::<($($key,)+)>() {
+                    Ok(()) => (),
+                    Err(missing) => return Err($crate::static_map::Missing(missing))
+                }
+                $(if map.is_frozen::<$key, $value>() {
+                    return Err($crate::static_map::Frozen(stringify!($key)))
+                })+
+                Ok($name {
+                    $($field: map.try_remove::<$key, $value>().ok()),+
+                })
+            }
+
+            /// Move every value of this map into a TypeMap.
+            ///
+            /// Panics, like `TypeMap::insert`, if a key is frozen in it.
+            pub fn spill(self, map: &mut $crate::TypeMap) {
+                let $name { $($field),+ } = self;
+                $(match $field {
+                    Some(val) => { map.insert::<$key, $value>(val); },
+                    None => ()
+                })+
+            }
+        }
+
+        $(impl $crate::Field<$key, $value> for $name {
+            #[inline]
+            fn field(&self) -> &Option<$value> { &self.$field }
+
+            #[inline]
+            fn field_mut(&mut self) -> &mut Option<$value> { &mut self.$field }
+        })+
+
+        impl $crate::StaticTypeMap for $name {}
+    )
+}
+
+/// Assert that two TypeMaps are equal, according to the comparisons
+/// registered in a `KeyRegistry`.
+///
+/// On failure, the panic message lists each differing key with the names
+/// of its key and value types and the values in both maps.
+///
+/// ```ignore
+/// assert_typemap_eq!(registry, expected, actual);
+/// ```
+#[macro_export]
+macro_rules! assert_typemap_eq {
+    ($registry:expr, $left:expr, $right:expr) => ({
+        let diff = $registry.diff(&$left, &$right);
+        if !diff.is_empty() {
+            panic!("assertion failed: TypeMaps differ:\n{}", diff.connect("\n"))
+        }
+    })
+}
+
+/// Declare a key for a value type, with a `KeyAddress` so it can be used
+/// with an `AddressTypeMap`.
+///
+/// This implements both `Assoc` and `StaticKey` for the key.
+///
+/// ```ignore
+/// static_key!(SessionKey => Session)
+/// ```
+#[macro_export]
+macro_rules! static_key {
+    ($key:ty => $value:ty) => (
+        impl $crate::Assoc<$value> for $key {}
+
+        impl $crate::StaticKey<$value> for $key {
+            fn address(_: Option<$key>) -> &'static $crate::KeyAddress {
+                // The name makes the contents of each static distinct, so
+                // they are never merged into one address.
+                static ADDRESS: $crate::KeyAddress = $crate::KeyAddress {
+                    name: concat!(module_path!(), "::", stringify!($key))
+                };
+                &ADDRESS
+            }
+        }
+    )
+}
+
+/// Register views of the values of a key as trait objects, so they can be
+/// found with `TypeMap::find_view`.
+///
+/// ```ignore
+/// register_views!(map, PoolKey => PostgresPool: Database + 'static, Show + 'static);
+/// let db = map.find_view::<PoolKey, Database + 'static>();
+/// ```
+#[macro_export]
+macro_rules! register_views {
+    ($map:expr, $key:ty => $value:ty: $($view:ty),+) => ({
+        $({
+            fn cast(val: &(::std::any::Any + 'static)) -> ::std::raw::TraitObject {
+                unsafe {
+                    let object: ::std::raw::TraitObject = ::std::mem::transmute(val);
+                    let val: &$value = ::std::mem::transmute(object.data);
+                    ::std::mem::transmute(val as &$view)
+                }
+            }
+            unsafe { $map.register_view::<$key, $value, $view>(cast as $crate::ViewCast) }
+        })+
+    })
+}
@@ -0,0 +1,134 @@
+//! Moving values from old key types to new ones, for refactorings which
+//! rename keys used by long-lived maps.
+
+use std::intrinsics::TypeId;
+
+use invariants::Expectations;
+
+use super::{TypeMap, Assoc, Storage, type_name};
+
+/// A list of key renames to apply to maps together.
+///
+/// ```ignore
+/// let migration = Migration::new()
+///     .rename::<OldSession, Session, SessionData>()
+///     .rename::<OldUser, User, UserData>();
+/// migration.apply(&mut map);
+/// ```
+#[deriving(Clone)]
+pub struct Migration {
+    // The old and new keys, the name of the new key, and a function
+    // recording the value type moved.
+    renames: Vec<(TypeId, TypeId, &'static str, fn(&mut Expectations, TypeId))>
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Move the value of the key `Old` to the key `New`, replacing any value
+    /// `New` already has.
+    ///
+    /// Returns `true` if `Old` had a value to move. Nothing is moved if
+    /// either key is frozen.
+    pub fn rekey<Old: Assoc<V>, New: Assoc<V>, V: 'static>(&mut self) -> bool {
+        let (old, new) = (self.slot::<Old>(), self.slot::<New>());
+        if old == new { return self.data.contains(&old) }
+        let moved = self.move_id(old, new);
+        if moved {
+            self.expected.record::<V>(new);
+            self.name_slot(new, TypeId::of::<New>(), type_name::<New>());
+        }
+        self.debug_check();
+        moved
+    }
+
+    // Move the value of one slot to another, unless either is frozen.
+    fn move_id(&mut self, old: TypeId, new: TypeId) -> bool {
+        if self.is_frozen_id(&new) { return false }
+        match self.take_id(old) {
+            Some(val) => match self.store_id(new, val) {
+                Ok(_) => true,
+                // Only an overfull map, whose limit was lowered, refuses the
+                // value, so it goes back where it was.
+                Err(val) => { self.data.swap(old, val); false }
+            },
+            None => false
+        }
+    }
+}
+
+impl Migration {
+    /// Create a Migration with no renames.
+    pub fn new() -> Migration {
+        Migration { renames: Vec::new() }
+    }
+
+    /// Add a rename of the key `Old` to the key `New`.
+    pub fn rename<Old: Assoc<V>, New: Assoc<V>, V: 'static>(mut self) -> Migration {
+        let expect = expect::<V> as fn(&mut Expectations, TypeId);
+        self.renames.push((TypeId::of::<Old>(), TypeId::of::<New>(), type_name::<New>(), expect));
+        self
+    }
+
+    /// Apply the renames to a map, in the order they were added. Values
+    /// are not moved from or to frozen keys.
+    ///
+    /// Returns the number of values moved.
+    pub fn apply<S: Storage>(&self, map: &mut TypeMap<S>) -> uint {
+        let mut moved = 0;
+        for &(old, key, name, expect) in self.renames.iter() {
+            let (old, new) = (map.resolve(old), map.resolve(key));
+            if old == new { continue }
+            if map.move_id(old, new) {
+                expect(&mut map.expected, new);
+                map.name_slot(new, key, name);
+                moved += 1;
+            }
+        }
+        map.debug_check();
+        moved
+    }
+}
+
+fn expect<V: 'static>(expected: &mut Expectations, id: TypeId) {
+    expected.record::<V>(id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Migration;
+    use {TypeMap, Assoc};
+
+    struct OldSession;
+
+    struct Session;
+
+    struct OldUser;
+
+    struct User;
+
+    impl Assoc<uint> for OldSession {}
+    impl Assoc<uint> for Session {}
+    impl Assoc<String> for OldUser {}
+    impl Assoc<String> for User {}
+
+    #[test] fn test_rekey() {
+        let mut map = TypeMap::new();
+        map.insert::<OldSession, uint>(3);
+        assert!(map.rekey::<OldSession, Session, uint>());
+        assert!(!map.contains::<OldSession, uint>());
+        assert_eq!(*map.find::<Session, uint>().unwrap(), 3);
+        assert!(!map.rekey::<OldSession, Session, uint>());
+        assert_eq!(*map.find::<Session, uint>().unwrap(), 3);
+    }
+
+    #[test] fn test_migration() {
+        let migration = Migration::new()
+            .rename::<OldSession, Session, uint>()
+            .rename::<OldUser, User, String>();
+
+        let mut map = TypeMap::new();
+        map.insert::<OldUser, String>("jonathan".to_string());
+        assert_eq!(migration.apply(&mut map), 1);
+        assert_eq!(map.find::<User, String>().unwrap().as_slice(), "jonathan");
+        assert_eq!(map.len(), 1);
+    }
+}
@@ -0,0 +1,68 @@
+//! A map which stores nothing, for compiling features built on a map out.
+
+use super::{Assoc, TypeMapLike};
+
+/// A map which drops every value inserted into it, so it is always empty.
+///
+/// Unlike a `TypeMap` with `NoopStorage`, it has no bookkeeping at all:
+/// every method is empty and inlined, so code using it through
+/// `TypeMapLike` compiles to nothing.
+pub struct NoopTypeMap;
+
+impl NoopTypeMap {
+    /// Create a new NoopTypeMap.
+    #[inline]
+    pub fn new() -> NoopTypeMap { NoopTypeMap }
+}
+
+impl TypeMapLike for NoopTypeMap {
+    #[inline]
+    fn insert<K: Assoc<V>, V: 'static>(&mut self, _: V) -> bool { true }
+
+    #[inline]
+    fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> { None }
+
+    #[inline]
+    fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> { None }
+
+    #[inline]
+    fn contains<K: Assoc<V>, V: 'static>(&self) -> bool { false }
+
+    #[inline]
+    fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool { false }
+
+    #[inline]
+    fn len(&self) -> uint { 0 }
+
+    #[inline]
+    fn is_empty(&self) -> bool { true }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NoopTypeMap;
+    use {TypeMap, TypeMapLike, Assoc};
+
+    struct Key;
+
+    impl Assoc<uint> for Key {}
+
+    fn count<M: TypeMapLike>(map: &mut M) -> Option<uint> {
+        map.insert::<Key, uint>(1);
+        match map.find_mut::<Key, uint>() {
+            Some(count) => *count += 1,
+            None => ()
+        }
+        map.find::<Key, uint>().map(|&count| count)
+    }
+
+    #[test] fn test_noop() {
+        let mut map = NoopTypeMap::new();
+        assert_eq!(count(&mut map), None);
+        assert!(!map.contains::<Key, uint>());
+        assert!(!map.remove::<Key, uint>());
+        assert!(map.is_empty());
+
+        assert_eq!(count(&mut TypeMap::new()), Some(2));
+    }
+}
@@ -0,0 +1,303 @@
+//! A TypeMap which remembers the order entries were inserted in.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::intrinsics::TypeId;
+use std::slice;
+
+use downcast::{UncheckedAnyDowncast, UncheckedAnyMutDowncast};
+
+use super::Assoc;
+
+/// A TypeMap which iterates over its entries in insertion order.
+///
+/// Replacing a value keeps the position of its entry. Entries can also be
+/// accessed by their position.
+pub struct OrderedTypeMap {
+    entries: Vec<(TypeId, Box<Any + 'static>)>,
+    indices: HashMap<TypeId, uint>
+}
+
+/// A cursor over the entries of an OrderedTypeMap, which can remove and
+/// insert entries as it walks them.
+pub struct Cursor<'a> {
+    map: &'a mut OrderedTypeMap,
+    index: uint
+}
+
+/// An iterator over the entries of an OrderedTypeMap, in insertion order.
+pub struct Entries<'a> {
+    inner: slice::Items<'a, (TypeId, Box<Any + 'static>)>
+}
+
+impl OrderedTypeMap {
+    /// Create a new, empty OrderedTypeMap.
+    pub fn new() -> OrderedTypeMap {
+        OrderedTypeMap {
+            entries: Vec::new(),
+            indices: HashMap::new()
+        }
+    }
+
+    /// Insert a value into the map with a specified key type.
+    ///
+    /// New keys are added at the end; replaced values keep their position.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        let id = TypeId::of::<K>();
+        let val = box val as Box<Any + 'static>;
+        match self.indices.find(&id) {
+            Some(&index) => {
+                let (_, ref mut slot) = self.entries[index];
+                *slot = val;
+                return false
+            },
+            None => ()
+        }
+
+        self.indices.insert(id, self.entries.len());
+        self.entries.push((id, val));
+        true
+    }
+
+    /// Find a value in the map and get a reference to it.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        match self.index_of::<K, V>() {
+            Some(index) => {
+                let (_, ref val) = self.entries[index];
+                Some(unsafe { val.downcast_ref_unchecked::<V>() })
+            },
+            None => None
+        }
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    pub fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> {
+        match self.index_of::<K, V>() {
+            Some(index) => {
+                let (_, ref mut val) = self.entries[index];
+                Some(unsafe { val.downcast_mut_unchecked::<V>() })
+            },
+            None => None
+        }
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.indices.contains_key(&TypeId::of::<K>())
+    }
+
+    /// Get the position of a key's entry in the map.
+    pub fn index_of<K: Assoc<V>, V: 'static>(&self) -> Option<uint> {
+        self.indices.find(&TypeId::of::<K>()).map(|&index| index)
+    }
+
+    /// Remove a value from the map, shifting later entries down to keep
+    /// their order.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        let index = match self.indices.pop(&TypeId::of::<K>()) {
+            Some(index) => index,
+            None => return false
+        };
+
+        self.entries.remove(index);
+        self.reindex(index);
+        true
+    }
+
+    /// Get the key and value of the entry at a position.
+    pub fn get_index(&self, index: uint) -> Option<(TypeId, &(Any + 'static))> {
+        self.entries.get(index).map(|&(id, ref val)| (id, &**val))
+    }
+
+    /// Remove the entry at a position, replacing it with the last entry.
+    ///
+    /// This does not preserve the order of the entries, but is O(1).
+    pub fn swap_remove_index(&mut self, index: uint) -> Option<(TypeId, Box<Any + 'static>)> {
+        let removed = self.entries.swap_remove(index);
+        match removed {
+            Some((ref id, _)) => {
+                self.indices.remove(id);
+                match self.entries.get(index) {
+                    Some(&(ref moved, _)) => { self.indices.insert(*moved, index); },
+                    None => ()
+                }
+            },
+            None => ()
+        }
+        removed
+    }
+
+    /// Get a cursor positioned at the first entry of the map.
+    pub fn cursor_front<'a>(&'a mut self) -> Cursor<'a> {
+        Cursor { map: self, index: 0 }
+    }
+
+    /// Iterate over the keys and values of the map, in insertion order.
+    pub fn iter<'a>(&'a self) -> Entries<'a> {
+        Entries { inner: self.entries.iter() }
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove all entries from the map.
+    pub fn clear(&mut self) {
+        self.indices.clear();
+        self.entries.clear()
+    }
+
+    fn reindex(&mut self, from: uint) {
+        for (index, &(ref id, _)) in self.entries.iter().enumerate().skip(from) {
+            self.indices.insert(*id, index);
+        }
+    }
+}
+
+impl<'a> Cursor<'a> {
+    /// Get the key and value of the entry at the cursor, or `None` if the
+    /// cursor is past the last entry.
+    pub fn current(&self) -> Option<(TypeId, &(Any + 'static))> {
+        self.map.get_index(self.index)
+    }
+
+    /// Get the key and a mutable reference to the value of the entry at the
+    /// cursor.
+    pub fn current_mut(&mut self) -> Option<(TypeId, &mut (Any + 'static))> {
+        match self.map.entries.get_mut(self.index) {
+            Some(entry) => {
+                let (id, ref mut val) = *entry;
+                Some((id, &mut **val))
+            },
+            None => None
+        }
+    }
+
+    /// Get the position of the cursor.
+    pub fn index(&self) -> uint {
+        self.index
+    }
+
+    /// Move the cursor to the next entry.
+    ///
+    /// Returns `false` if the cursor is now past the last entry.
+    pub fn move_next(&mut self) -> bool {
+        if self.index < self.map.len() { self.index += 1 }
+        self.index < self.map.len()
+    }
+
+    /// Remove the entry at the cursor, which moves on to the entry after
+    /// it.
+    pub fn remove_current(&mut self) -> Option<(TypeId, Box<Any + 'static>)> {
+        if self.index >= self.map.len() { return None }
+        let (id, val) = self.map.entries.remove(self.index).unwrap();
+        self.map.indices.remove(&id);
+        self.map.reindex(self.index);
+        Some((id, val))
+    }
+
+    /// Insert a value for a new key before the entry at the cursor, which
+    /// stays where it is. If the cursor is past the last entry, the value
+    /// is added at the end.
+    ///
+    /// If the key already has a value, it is replaced in its own position
+    /// instead, and `false` is returned.
+    pub fn insert_before<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        if self.map.contains::<K, V>() {
+            return self.map.insert::<K, V>(val)
+        }
+        self.map.entries.insert(self.index, (TypeId::of::<K>(), box val as Box<Any + 'static>));
+        self.map.reindex(self.index);
+        self.index += 1;
+        true
+    }
+}
+
+impl<'a> Iterator<(TypeId, &'a (Any + 'static))> for Entries<'a> {
+    fn next(&mut self) -> Option<(TypeId, &'a (Any + 'static))> {
+        self.inner.next().map(|&(id, ref val)| (id, &**val))
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::intrinsics::TypeId;
+    use super::OrderedTypeMap;
+    use Assoc;
+
+    struct First;
+    struct Second;
+    struct Third;
+
+    impl Assoc<uint> for First {}
+    impl Assoc<uint> for Second {}
+    impl Assoc<uint> for Third {}
+
+    fn ids(map: &OrderedTypeMap) -> Vec<TypeId> {
+        map.iter().map(|(id, _)| id).collect()
+    }
+
+    #[test] fn test_insertion_order() {
+        let mut map = OrderedTypeMap::new();
+        map.insert::<Second, uint>(2);
+        map.insert::<First, uint>(1);
+        map.insert::<Third, uint>(3);
+        map.insert::<Second, uint>(4);
+        assert_eq!(ids(&map), vec![TypeId::of::<Second>(), TypeId::of::<First>(), TypeId::of::<Third>()]);
+        assert_eq!(*map.find::<Second, uint>().unwrap(), 4);
+
+        map.remove::<Second, uint>();
+        assert_eq!(ids(&map), vec![TypeId::of::<First>(), TypeId::of::<Third>()]);
+        assert_eq!(map.index_of::<Third, uint>(), Some(1));
+    }
+
+    #[test] fn test_cursor() {
+        let mut map = OrderedTypeMap::new();
+        map.insert::<First, uint>(1);
+        map.insert::<Second, uint>(2);
+
+        {
+            let mut cursor = map.cursor_front();
+            let (id, _) = cursor.remove_current().unwrap();
+            assert_eq!(id, TypeId::of::<First>());
+            assert!(cursor.insert_before::<Third, uint>(3));
+            assert!(!cursor.insert_before::<Third, uint>(4));
+            let (id, _) = cursor.current_mut().unwrap();
+            assert_eq!(id, TypeId::of::<Second>());
+            assert!(!cursor.move_next());
+            assert!(cursor.current().is_none());
+            assert!(cursor.remove_current().is_none());
+        }
+
+        assert_eq!(ids(&map), vec![TypeId::of::<Third>(), TypeId::of::<Second>()]);
+        assert_eq!(*map.find::<Second, uint>().unwrap(), 2);
+        assert_eq!(*map.find::<Third, uint>().unwrap(), 4);
+        assert_eq!(map.index_of::<Second, uint>(), Some(1));
+    }
+
+    #[test] fn test_swap_remove_index() {
+        let mut map = OrderedTypeMap::new();
+        map.insert::<First, uint>(1);
+        map.insert::<Second, uint>(2);
+        map.insert::<Third, uint>(3);
+
+        let (id, _) = map.swap_remove_index(0).unwrap();
+        assert_eq!(id, TypeId::of::<First>());
+        let (id, _) = map.get_index(0).unwrap();
+        assert_eq!(id, TypeId::of::<Third>());
+        assert_eq!(*map.find::<Third, uint>().unwrap(), 3);
+        assert!(map.swap_remove_index(5).is_none());
+    }
+}
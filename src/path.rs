@@ -0,0 +1,97 @@
+//! Lookups through TypeMaps nested inside each other.
+//!
+//! When a key's value is itself a TypeMap, `map.at::<Outer>()` starts a
+//! path into it, and the path's lookups short-circuit to `None` if any map
+//! along the way is missing:
+//!
+//! ```ignore
+//! let port = map.at::<Config>().at::<Server>().find::<Port, u16>();
+//! ```
+
+use super::{TypeMap, Assoc, Storage};
+
+/// A path to a TypeMap nested in another one, which may not exist.
+pub struct MapPath<'a> {
+    map: Option<&'a TypeMap>
+}
+
+/// A mutable path to a TypeMap nested in another one, which may not exist.
+pub struct MapPathMut<'a> {
+    map: Option<&'a mut TypeMap>
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Start a path into the TypeMap stored for a key.
+    pub fn at<'a, K: Assoc<TypeMap>>(&'a self) -> MapPath<'a> {
+        MapPath { map: self.find::<K, TypeMap>() }
+    }
+
+    /// Start a mutable path into the TypeMap stored for a key.
+    pub fn at_mut<'a, K: Assoc<TypeMap>>(&'a mut self) -> MapPathMut<'a> {
+        MapPathMut { map: self.find_mut::<K, TypeMap>() }
+    }
+}
+
+impl<'a> MapPath<'a> {
+    /// Continue the path into the TypeMap stored for a key.
+    pub fn at<K: Assoc<TypeMap>>(self) -> MapPath<'a> {
+        MapPath { map: self.map.and_then(|map| map.find::<K, TypeMap>()) }
+    }
+
+    /// Find a value in the map at the end of the path.
+    pub fn find<K: Assoc<V>, V: 'static>(self) -> Option<&'a V> {
+        self.map.and_then(|map| map.find::<K, V>())
+    }
+
+    /// Get the map at the end of the path.
+    pub fn map(self) -> Option<&'a TypeMap> {
+        self.map
+    }
+}
+
+impl<'a> MapPathMut<'a> {
+    /// Continue the path into the TypeMap stored for a key.
+    pub fn at_mut<K: Assoc<TypeMap>>(self) -> MapPathMut<'a> {
+        MapPathMut { map: self.map.and_then(|map| map.find_mut::<K, TypeMap>()) }
+    }
+
+    /// Find a value in the map at the end of the path and get a mutable
+    /// reference to it.
+    pub fn find_mut<K: Assoc<V>, V: 'static>(self) -> Option<&'a mut V> {
+        self.map.and_then(|map| map.find_mut::<K, V>())
+    }
+
+    /// Get the map at the end of the path.
+    pub fn map(self) -> Option<&'a mut TypeMap> {
+        self.map
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {TypeMap, Assoc};
+
+    struct Config;
+
+    struct Server;
+
+    struct Port;
+
+    impl Assoc<TypeMap> for Config {}
+    impl Assoc<TypeMap> for Server {}
+    impl Assoc<u16> for Port {}
+
+    #[test] fn test_nested_lookup() {
+        let mut server = TypeMap::new();
+        server.insert::<Port, u16>(80);
+        let mut config = TypeMap::new();
+        config.insert::<Server, TypeMap>(server);
+        let mut map = TypeMap::new();
+        assert!(map.at::<Config>().at::<Server>().find::<Port, u16>().is_none());
+
+        map.insert::<Config, TypeMap>(config);
+        *map.at_mut::<Config>().at_mut::<Server>().find_mut::<Port, u16>().unwrap() += 1;
+        assert_eq!(map.at::<Config>().at::<Server>().find::<Port, u16>(), Some(&81));
+        assert!(map.at::<Server>().find::<Port, u16>().is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! Write-behind persistence of the entries modified since the last flush.
+//!
+//! `flush_dirty` writes the entries of keys registered with
+//! `KeyRegistry::register_replicated` which were modified since the last
+//! flush to a `Sink`, named by their stable identifiers and encoded as
+//! JSON. Modified entries of other keys are not written. Flushing is
+//! independent of `checkpoint` and `take_changes`.
+//!
+//! Values are written with the version of their key's shape, as
+//! `{"value":...,"version":1}`, so `load` can read values written by older
+//! builds: values of older versions are upgraded by the key's migrations,
+//! registered with `KeyRegistry::register_migration`, before they are
+//! decoded.
+
+use std::any::Any;
+use std::collections::TreeMap;
+use std::io::IoResult;
+
+use serialize::json::{mod, Json};
+
+use super::{TypeMap, KeyRegistry, KeyInfo, Storage, ReplicationError, decode_value};
+use replicate::{UnknownKey, Decode};
+
+/// A destination for persisted entries.
+pub trait Sink {
+    /// Store the value of the key with a stable identifier.
+    fn write(&mut self, stable_id: &str, val: &Json) -> IoResult<()>;
+
+    /// Forget the value of the key with a stable identifier.
+    fn remove(&mut self, stable_id: &str) -> IoResult<()>;
+}
+
+/// A TreeMap keeps persisted entries in memory.
+impl Sink for TreeMap<String, Json> {
+    fn write(&mut self, stable_id: &str, val: &Json) -> IoResult<()> {
+        self.insert(stable_id.to_string(), val.clone());
+        Ok(())
+    }
+
+    fn remove(&mut self, stable_id: &str) -> IoResult<()> {
+        self.remove(&stable_id.to_string());
+        Ok(())
+    }
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Write the entries of persisted keys modified since the last flush to
+    /// a sink, removing the values of removed entries from it.
+    ///
+    /// Starts tracking modified entries if the map is not tracking them, so
+    /// the first flush writes every entry stored in the map. Keys aliased
+    /// in this map write the values of their targets. Modified entries of
+    /// keys which are not persisted are forgotten, not kept for later
+    /// flushes.
+    ///
+    /// Returns the number of entries written or removed. If the sink fails,
+    /// the entries not yet written are kept for the next flush to retry.
+    pub fn flush_dirty<T: Sink>(&mut self, registry: &KeyRegistry, sink: &mut T) -> IoResult<uint> {
+        self.track_dirty();
+        let mut flushed = 0;
+        for info in registry.keys().into_iter() {
+            if !info.can_replicate() { continue }
+            let slot = self.resolve(info.key());
+            if !self.tracking.as_ref().unwrap().unflushed.contains(&slot) { continue }
+            let stable_id = info.stable_id().unwrap();
+            match self.data.find(&slot) {
+                Some(val) => try!(sink.write(stable_id, &versioned(info, info.encode(&**val).unwrap()))),
+                None => try!(sink.remove(stable_id))
+            }
+            self.tracking.as_mut().unwrap().unflushed.remove(&slot);
+            flushed += 1;
+        }
+        self.tracking.as_mut().unwrap().unflushed.clear();
+        Ok(flushed)
+    }
+
+    /// Load entries written by `flush_dirty`, such as the contents of a
+    /// TreeMap sink, into the map, returning the number of keys set.
+    ///
+    /// Values of older versions of their keys are upgraded with the keys'
+    /// migrations. If an entry is of a key which is not persisted by the
+    /// registry, or its value is newer than this build reads or cannot be
+    /// decoded, the map is left unchanged. Entries of keys frozen in this
+    /// map, and new keys which would overfill it, are skipped. Loaded
+    /// entries are not dirty and are not written back by `flush_dirty`.
+    pub fn load(&mut self, registry: &KeyRegistry, entries: &TreeMap<String, Json>) -> Result<uint, ReplicationError> {
+        let mut decoded: Vec<(&KeyInfo, Option<Box<Any + 'static>>)> = Vec::new();
+        for (stable_id, entry) in entries.iter() {
+            let info = match registry.find_stable(stable_id.as_slice()) {
+                Some(info) if info.can_replicate() => info,
+                _ => return Err(UnknownKey(stable_id.clone()))
+            };
+            let (version, val) = match (entry.find(&"version".to_string()), entry.find(&"value".to_string())) {
+                (Some(&json::U64(version)), Some(val)) => (version, val.clone()),
+                _ => return Err(Decode(json::ExpectedError("versioned value".to_string(), entry.to_string())))
+            };
+            decoded.push((info, Some(try!(decode_value(info, version, val)))));
+        }
+
+        self.track_dirty();
+        let loaded = self.store_decoded(decoded);
+        let tracking = self.tracking.as_mut().unwrap();
+        for slot in loaded.iter() {
+            tracking.dirty.remove(slot);
+            tracking.unflushed.remove(slot);
+        }
+        Ok(loaded.len())
+    }
+}
+
+// Wrap the JSON of a value with the version of its key.
+fn versioned(info: &KeyInfo, val: Json) -> Json {
+    let mut entry = TreeMap::new();
+    entry.insert("value".to_string(), val);
+    entry.insert("version".to_string(), json::U64(info.version()));
+    json::Object(entry)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::TreeMap;
+    use serialize::json::{mod, Json};
+    use {TypeMap, Assoc, KeyRegistry, StableKey};
+
+    struct Counter;
+
+    struct Local;
+
+    impl Assoc<uint> for Counter {}
+    impl Assoc<uint> for Local {}
+
+    impl StableKey<uint> for Counter {
+        fn stable_id(_: Option<Counter>) -> &'static str { "test.counter" }
+    }
+
+    #[test] fn test_flush_dirty() {
+        let mut registry = KeyRegistry::new();
+        registry.register_replicated::<Counter, uint>();
+        let mut sink: TreeMap<String, Json> = TreeMap::new();
+
+        let mut map = TypeMap::new();
+        map.insert::<Counter, uint>(1);
+        map.insert::<Local, uint>(2);
+        assert_eq!(map.flush_dirty(&registry, &mut sink), Ok(1));
+        assert_eq!(sink.find(&"test.counter".to_string()).unwrap().find(&"value".to_string()), Some(&json::U64(1)));
+        assert_eq!(map.flush_dirty(&registry, &mut sink), Ok(0));
+
+        map.remove::<Counter, uint>();
+        assert_eq!(map.flush_dirty(&registry, &mut sink), Ok(1));
+        assert!(sink.is_empty());
+        assert!(map.tracking.as_ref().unwrap().unflushed.is_empty());
+    }
+
+    #[test] fn test_flush_aliased() {
+        let mut registry = KeyRegistry::new();
+        registry.register_replicated::<Counter, uint>();
+        let mut sink: TreeMap<String, Json> = TreeMap::new();
+
+        let mut map = TypeMap::new();
+        map.alias::<Counter, Local, uint>();
+        map.insert::<Local, uint>(3);
+        assert_eq!(map.flush_dirty(&registry, &mut sink), Ok(1));
+        assert_eq!(sink.find(&"test.counter".to_string()).unwrap().find(&"value".to_string()), Some(&json::U64(3)));
+    }
+
+    #[test] fn test_load() {
+        fn to_cents(json: Json) -> Json {
+            match json {
+                json::U64(dollars) => json::U64(dollars * 100),
+                other => other
+            }
+        }
+
+        let mut old = KeyRegistry::new();
+        old.register_replicated::<Counter, uint>();
+        let mut sink: TreeMap<String, Json> = TreeMap::new();
+        let mut map = TypeMap::new();
+        map.insert::<Counter, uint>(3);
+        map.flush_dirty(&old, &mut sink).unwrap();
+
+        let mut new = KeyRegistry::new();
+        new.register_replicated::<Counter, uint>();
+        new.register_migration::<Counter, uint>(1, to_cents);
+        let mut loaded = TypeMap::new();
+        assert_eq!(loaded.load(&new, &sink), Ok(1));
+        assert_eq!(*loaded.find::<Counter, uint>().unwrap(), 300);
+        assert_eq!(loaded.flush_dirty(&new, &mut sink), Ok(0));
+
+        loaded.insert::<Counter, uint>(450);
+        loaded.flush_dirty(&new, &mut sink).unwrap();
+        assert!(TypeMap::new().load(&old, &sink).is_err());
+        assert_eq!(TypeMap::new().load(&new, &sink), Ok(1));
+    }
+
+    #[test] fn test_independent_of_replication() {
+        let mut registry = KeyRegistry::new();
+        registry.register_replicated::<Counter, uint>();
+        let mut sink: TreeMap<String, Json> = TreeMap::new();
+
+        let mut map = TypeMap::new();
+        map.insert::<Counter, uint>(1);
+        map.take_changes(&registry);
+        map.checkpoint();
+        assert_eq!(map.flush_dirty(&registry, &mut sink), Ok(1));
+        assert_eq!(map.take_changes(&registry).as_slice(), r#"{"remove":[],"set":{},"version":2,"versions":{}}"#);
+        assert_eq!(map.flush_dirty(&registry, &mut sink), Ok(0));
+
+        map.remove::<Counter, uint>();
+        assert_eq!(map.flush_dirty(&registry, &mut sink), Ok(1));
+        assert_eq!(map.take_changes(&registry).as_slice(), r#"{"remove":["test.counter"],"set":{},"version":2,"versions":{}}"#);
+    }
+}
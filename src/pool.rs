@@ -0,0 +1,199 @@
+//! A pool of reusable TypeMaps.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::mem;
+
+use recycle::FreeList;
+
+use super::TypeMap;
+
+/// The default number of idle maps kept by a TypeMapPool.
+static DEFAULT_MAX_IDLE: uint = 32;
+
+/// A pool of TypeMaps which are reset and reused instead of dropped.
+///
+/// Resetting a map drops its values and everything configured on it,
+/// like freezes, aliases, limits and derived keys, but keeps the capacity
+/// of its table, so maps checked out of a warm pool can be filled without
+/// allocating a new table.
+pub struct TypeMapPool {
+    maps: RefCell<Vec<TypeMap>>,
+    max_idle: uint,
+    hits: Cell<uint>,
+    misses: Cell<uint>
+}
+
+/// A TypeMap checked out of a TypeMapPool.
+///
+/// The map is reset and returned to its pool when this is dropped.
+pub struct PooledTypeMap<'a> {
+    map: Option<TypeMap>,
+    pool: &'a TypeMapPool
+}
+
+impl TypeMapPool {
+    /// Create a new, empty TypeMapPool.
+    pub fn new() -> TypeMapPool {
+        TypeMapPool::with_max_idle(DEFAULT_MAX_IDLE)
+    }
+
+    /// Create a new, empty TypeMapPool which keeps at most `max_idle` maps.
+    ///
+    /// Maps returned to a full pool are dropped.
+    pub fn with_max_idle(max_idle: uint) -> TypeMapPool {
+        TypeMapPool {
+            maps: RefCell::new(Vec::new()),
+            max_idle: max_idle,
+            hits: Cell::new(0),
+            misses: Cell::new(0)
+        }
+    }
+
+    /// Check out an empty map, reusing an idle one if possible.
+    pub fn checkout<'a>(&'a self) -> PooledTypeMap<'a> {
+        let map = match self.maps.borrow_mut().pop() {
+            Some(map) => {
+                self.hits.set(self.hits.get() + 1);
+                map
+            },
+            None => {
+                self.misses.set(self.misses.get() + 1);
+                TypeMap::new()
+            }
+        };
+
+        PooledTypeMap { map: Some(map), pool: self }
+    }
+
+    /// Get the number of idle maps currently held by the pool.
+    pub fn idle(&self) -> uint {
+        self.maps.borrow().len()
+    }
+
+    /// Get the number of checkouts which reused an idle map.
+    pub fn hits(&self) -> uint { self.hits.get() }
+
+    /// Get the number of checkouts which had to create a new map.
+    pub fn misses(&self) -> uint { self.misses.get() }
+
+    /// Get the fraction of checkouts which reused an idle map.
+    ///
+    /// Returns 0 if no maps have been checked out yet.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits() + self.misses();
+        if total == 0 { 0.0 } else { self.hits() as f64 / total as f64 }
+    }
+
+    fn release(&self, mut map: TypeMap) {
+        let mut maps = self.maps.borrow_mut();
+        if maps.len() < self.max_idle {
+            map.reset();
+            maps.push(map);
+        }
+    }
+}
+
+impl TypeMap {
+    // Make the map like a new one, keeping only its table and free-list.
+    fn reset(&mut self) {
+        self.frozen.clear();
+        self.clear();
+        let data = mem::replace(&mut self.data, HashMap::new());
+        let free = mem::replace(&mut self.free, FreeList::new());
+        *self = TypeMap::with_storage(data);
+        self.free = free;
+    }
+}
+
+impl<'a> PooledTypeMap<'a> {
+    /// Detach the map from its pool, so it is not returned when dropped.
+    pub fn detach(mut self) -> TypeMap {
+        self.map.take().unwrap()
+    }
+}
+
+impl<'a> Deref<TypeMap> for PooledTypeMap<'a> {
+    fn deref(&self) -> &TypeMap {
+        self.map.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut<TypeMap> for PooledTypeMap<'a> {
+    fn deref_mut(&mut self) -> &mut TypeMap {
+        self.map.as_mut().unwrap()
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for PooledTypeMap<'a> {
+    fn drop(&mut self) {
+        match self.map.take() {
+            Some(map) => self.pool.release(map),
+            None => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TypeMapPool;
+    use Assoc;
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct Value;
+
+    #[deriving(Show, PartialEq)]
+    struct Other;
+
+    impl Assoc<Value> for Key {}
+    impl Assoc<Value> for Other {}
+
+    #[test] fn test_reuse() {
+        let pool = TypeMapPool::new();
+        {
+            let mut map = pool.checkout();
+            map.insert::<Key, Value>(Value);
+        }
+        assert_eq!(pool.idle(), 1);
+
+        let map = pool.checkout();
+        assert!(!map.contains::<Key, Value>());
+        assert_eq!(pool.hits(), 1);
+        assert_eq!(pool.misses(), 1);
+        assert_eq!(pool.hit_rate(), 0.5);
+    }
+
+    #[test] fn test_release_resets() {
+        let pool = TypeMapPool::new();
+        {
+            let mut map = pool.checkout();
+            map.insert::<Key, Value>(Value);
+            map.freeze::<Key, Value>();
+            map.set_max_entries(Some(0));
+            map.alias::<Other, Key, Value>();
+        }
+
+        let mut map = pool.checkout();
+        assert!(!map.contains::<Key, Value>());
+        assert!(!map.is_frozen::<Key, Value>());
+        map.insert::<Other, Value>(Value);
+        assert!(!map.contains::<Key, Value>());
+    }
+
+    #[test] fn test_max_idle() {
+        let pool = TypeMapPool::with_max_idle(1);
+        {
+            let _a = pool.checkout();
+            let _b = pool.checkout();
+        }
+        assert_eq!(pool.idle(), 1);
+
+        let map = pool.checkout().detach();
+        drop(map);
+        assert_eq!(pool.idle(), 0);
+    }
+}
@@ -0,0 +1,151 @@
+//! A TypeMap sharing unchanged values with a prototype.
+//!
+//! Many maps, like per-session state, start from the same defaults and only
+//! change a few of them. A `PrototypeMap` reads values from a shared
+//! prototype until they are changed, and copies each value into the map
+//! itself only the first time it is borrowed mutably.
+
+use std::collections::HashSet;
+use std::intrinsics::TypeId;
+use std::rc::Rc;
+
+use super::{TypeMap, Assoc};
+
+/// A TypeMap whose values default to those of a shared prototype.
+pub struct PrototypeMap {
+    proto: Rc<TypeMap>,
+    local: TypeMap,
+    removed: HashSet<TypeId>
+}
+
+impl PrototypeMap {
+    /// Create a map with the values of a prototype.
+    pub fn from_prototype(proto: Rc<TypeMap>) -> PrototypeMap {
+        PrototypeMap {
+            proto: proto,
+            local: TypeMap::new(),
+            removed: HashSet::new()
+        }
+    }
+
+    /// Get the prototype of the map.
+    pub fn prototype(&self) -> &Rc<TypeMap> {
+        &self.proto
+    }
+
+    /// Insert a value into the map with a specified key type, without
+    /// affecting the prototype.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        let shadowed = self.from_proto::<K, V>();
+        self.removed.remove(&TypeId::of::<K>());
+        self.local.insert::<K, V>(val) && !shadowed
+    }
+
+    /// Find a value in the map, or else in the prototype, and get a
+    /// reference to it.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        match self.local.find::<K, V>() {
+            Some(val) => Some(val),
+            None if self.removed.contains(&TypeId::of::<K>()) => None,
+            None => self.proto.find::<K, V>()
+        }
+    }
+
+    /// Find a value in the map and get a mutable reference to it, first
+    /// copying it from the prototype if it is still shared.
+    pub fn find_mut<K: Assoc<V>, V: Clone + 'static>(&mut self) -> Option<&mut V> {
+        if self.from_proto::<K, V>() {
+            let val = self.proto.find::<K, V>().unwrap().clone();
+            self.local.insert::<K, V>(val);
+        }
+        self.local.find_mut::<K, V>()
+    }
+
+    /// Check if a key has an associated value, in the map or the prototype.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.find::<K, V>().is_some()
+    }
+
+    /// Check if the value of a key is still read from the prototype.
+    pub fn is_shared<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.from_proto::<K, V>()
+    }
+
+    /// Remove a value from the map, hiding the prototype's value for the key.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        let shared = self.from_proto::<K, V>();
+        let removed = self.local.remove::<K, V>();
+        if self.proto.contains::<K, V>() { self.removed.insert(TypeId::of::<K>()); }
+        removed || shared
+    }
+
+    /// Get the number of values in the map, including those read from the
+    /// prototype.
+    pub fn len(&self) -> uint {
+        let shared = unsafe { self.proto.data() }.keys().filter(|id| {
+            !self.removed.contains(*id) && !unsafe { self.local.data() }.contains_key(*id)
+        }).count();
+        self.local.len() + shared
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of values which were copied from the prototype or
+    /// inserted into the map itself.
+    pub fn local_len(&self) -> uint {
+        self.local.len()
+    }
+
+    // Whether the value of K would be read from the prototype.
+    fn from_proto<K: Assoc<V>, V: 'static>(&self) -> bool {
+        !self.local.contains::<K, V>() && !self.removed.contains(&TypeId::of::<K>())
+            && self.proto.contains::<K, V>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use {TypeMap, Assoc};
+    use super::PrototypeMap;
+
+    struct Language;
+
+    struct Theme;
+
+    struct Cart;
+
+    impl Assoc<String> for Language {}
+    impl Assoc<String> for Theme {}
+    impl Assoc<Vec<uint>> for Cart {}
+
+    #[test] fn test_copy_on_write() {
+        let mut proto = TypeMap::new();
+        proto.insert::<Language, String>("en".to_string());
+        proto.insert::<Theme, String>("light".to_string());
+        let proto = Rc::new(proto);
+
+        let mut session = PrototypeMap::from_prototype(proto.clone());
+        assert_eq!(session.len(), 2);
+        assert_eq!(session.local_len(), 0);
+
+        session.find_mut::<Theme, String>().unwrap().push_str("-contrast");
+        assert!(!session.is_shared::<Theme, String>());
+        assert!(session.is_shared::<Language, String>());
+        assert_eq!(session.find::<Theme, String>().unwrap().as_slice(), "light-contrast");
+        assert_eq!(proto.find::<Theme, String>().unwrap().as_slice(), "light");
+
+        assert!(session.insert::<Cart, Vec<uint>>(vec![1]));
+        assert!(session.remove::<Language, String>());
+        assert!(!session.contains::<Language, String>());
+        assert_eq!(session.len(), 2);
+
+        assert!(session.insert::<Language, String>("fr".to_string()));
+        assert_eq!(session.find::<Language, String>().unwrap().as_slice(), "fr");
+    }
+}
@@ -0,0 +1,84 @@
+//! Tracking of where values were inserted, in debug builds.
+
+use std::fmt;
+#[cfg(not(ndebug))]
+use std::collections::{hashmap, HashMap};
+use std::intrinsics::TypeId;
+
+/// The number of writes remembered for each key.
+#[cfg(not(ndebug))]
+static MAX_WRITES: uint = 8;
+
+/// A location in the source code which wrote to a map.
+#[deriving(Clone, PartialEq)]
+pub struct Location {
+    /// The file containing the write.
+    pub file: &'static str,
+    /// The line of the write.
+    pub line: uint
+}
+
+impl fmt::Show for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// The most recent write locations of each key.
+#[cfg(not(ndebug))]
+pub struct Provenance {
+    writes: HashMap<TypeId, Vec<Location>>
+}
+
+/// Write locations are not recorded in release builds.
+#[cfg(ndebug)]
+pub struct Provenance;
+
+#[cfg(not(ndebug))]
+impl Provenance {
+    pub fn new() -> Provenance {
+        Provenance { writes: HashMap::new() }
+    }
+
+    pub fn record(&mut self, id: TypeId, location: Location) {
+        let writes = match self.writes.entry(id) {
+            hashmap::Occupied(e) => e.into_mut(),
+            hashmap::Vacant(e) => e.set(Vec::new())
+        };
+        if writes.len() == MAX_WRITES { writes.remove(0); }
+        writes.push(location);
+    }
+
+    pub fn forget(&mut self, id: &TypeId) {
+        self.writes.remove(id);
+    }
+
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+
+    pub fn writes(&self, id: &TypeId) -> &[Location] {
+        match self.writes.find(id) {
+            Some(writes) => writes.as_slice(),
+            None => &[]
+        }
+    }
+}
+
+#[cfg(ndebug)]
+impl Provenance {
+    #[inline(always)]
+    pub fn new() -> Provenance { Provenance }
+
+    #[inline(always)]
+    pub fn record(&mut self, _: TypeId, _: Location) {}
+
+    #[inline(always)]
+    pub fn forget(&mut self, _: &TypeId) {}
+
+    #[inline(always)]
+    pub fn clear(&mut self) {}
+
+    #[inline(always)]
+    pub fn writes(&self, _: &TypeId) -> &[Location] { &[] }
+}
@@ -0,0 +1,123 @@
+//! A protocol for pulling typed data out of values which don't name the
+//! types they hold, such as errors carrying context, and its
+//! implementation for TypeMap.
+//!
+//! A `Provider` is handed a `Demand` for one type at a time, and offers it
+//! the references and values it has; `request_ref` and `request_value`
+//! return whatever matched.
+
+use std::any::Any;
+use std::intrinsics::TypeId;
+
+use downcast::{UncheckedAnyDowncast, UncheckedBoxAnyDowncast};
+
+use super::{TypeMap, Storage};
+
+/// A value which can provide typed references and values on demand.
+pub trait Provider {
+    /// Offer the demand everything this value can provide.
+    fn provide<'a>(&'a self, demand: &mut Demand<'a>);
+}
+
+/// A request for a reference to, or a value of, one type.
+pub struct Demand<'a> {
+    wanted: TypeId,
+    by_ref: bool,
+    found_ref: Option<&'a (Any + 'static)>,
+    found_value: Option<Box<Any + 'static>>
+}
+
+impl<'a> Demand<'a> {
+    /// Check if the demand is for the type `T`.
+    pub fn wants<T: 'static>(&self) -> bool {
+        self.wanted == TypeId::of::<T>()
+    }
+
+    /// Offer a reference, which is taken if the demand is for a reference
+    /// to a `T` and nothing has been taken yet.
+    pub fn provide_ref<T: 'static>(&mut self, val: &'a T) -> &mut Demand<'a> {
+        self.provide_any_ref(val as &'a (Any + 'static))
+    }
+
+    /// Offer a reference to a value of a type known only at runtime.
+    pub fn provide_any_ref(&mut self, val: &'a (Any + 'static)) -> &mut Demand<'a> {
+        if self.by_ref && self.found_ref.is_none() && val.get_type_id() == self.wanted {
+            self.found_ref = Some(val);
+        }
+        self
+    }
+
+    /// Offer a value, computed only if the demand is for a `T` value and
+    /// nothing has been taken yet.
+    pub fn provide_value<T: 'static>(&mut self, f: || -> T) -> &mut Demand<'a> {
+        if !self.by_ref && self.found_value.is_none() && self.wants::<T>() {
+            self.found_value = Some(box f() as Box<Any + 'static>);
+        }
+        self
+    }
+}
+
+/// Request a reference to a `T` from a provider.
+pub fn request_ref<'a, T: 'static, Sized? P: Provider>(provider: &'a P) -> Option<&'a T> {
+    let mut demand = Demand::new(TypeId::of::<T>(), true);
+    provider.provide(&mut demand);
+    demand.found_ref.map(|val| unsafe { val.downcast_ref_unchecked::<T>() })
+}
+
+/// Request a `T` value from a provider.
+pub fn request_value<T: 'static, Sized? P: Provider>(provider: &P) -> Option<T> {
+    let mut demand = Demand::new(TypeId::of::<T>(), false);
+    provider.provide(&mut demand);
+    demand.found_value.map(|val| *unsafe { val.downcast_unchecked::<T>() })
+}
+
+impl<'a> Demand<'a> {
+    fn new(wanted: TypeId, by_ref: bool) -> Demand<'a> {
+        Demand { wanted: wanted, by_ref: by_ref, found_ref: None, found_value: None }
+    }
+}
+
+/// A TypeMap provides a reference to each of its values, found by value
+/// type. If several keys hold values of the requested type, any one of
+/// them may be provided.
+impl<S: Storage> Provider for TypeMap<S> {
+    fn provide<'a>(&'a self, demand: &mut Demand<'a>) {
+        for (_, val) in self.data.entries() {
+            demand.provide_any_ref(val);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Provider, Demand, request_ref, request_value};
+    use {TypeMap, Assoc};
+
+    struct RequestId;
+
+    impl Assoc<uint> for RequestId {}
+
+    struct Failure {
+        code: u16,
+        context: TypeMap
+    }
+
+    impl Provider for Failure {
+        fn provide<'a>(&'a self, demand: &mut Demand<'a>) {
+            demand.provide_ref(&self.code).provide_value(|| format!("error {}", self.code));
+            self.context.provide(demand);
+        }
+    }
+
+    #[test] fn test_requests() {
+        let mut context = TypeMap::new();
+        context.insert::<RequestId, uint>(42);
+        let failure = Failure { code: 500, context: context };
+
+        assert_eq!(request_ref::<u16, Failure>(&failure), Some(&500));
+        assert_eq!(request_ref::<uint, Failure>(&failure), Some(&42));
+        assert_eq!(request_value::<String, Failure>(&failure), Some("error 500".to_string()));
+        assert_eq!(request_ref::<String, Failure>(&failure), None);
+        assert_eq!(request_value::<u16, Failure>(&failure), None);
+    }
+}
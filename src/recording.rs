@@ -0,0 +1,341 @@
+//! A TypeMap which records every operation on it, for use in tests and
+//! for replaying its history.
+//!
+//! With `record_values`, the log also holds the stable identifier of each
+//! operation's key and, for keys registered with
+//! `KeyRegistry::register_replicated`, the values written, so
+//! `TypeMap::replay` can rebuild the map as it was after any operation.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::intrinsics::TypeId;
+use std::rc::Rc;
+
+use serialize::json::{mod, Json};
+
+use super::{TypeMap, Assoc, KeyRegistry, Storage, type_name};
+
+/// A kind of operation on a map.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Op {
+    /// An insert.
+    Insert,
+    /// A lookup through `find`.
+    Find,
+    /// A lookup through `find_mut`.
+    FindMut,
+    /// A check through `contains`.
+    Contains,
+    /// A removal.
+    Remove
+}
+
+/// An operation recorded by a RecordingTypeMap.
+#[deriving(Clone, PartialEq)]
+pub struct Operation {
+    /// The key the operation was on.
+    pub key: TypeId,
+    /// The name of the key type.
+    pub name: &'static str,
+    /// The kind of operation.
+    pub op: Op,
+    /// Whether the operation found or removed a value. Inserts always
+    /// succeed.
+    pub success: bool,
+    /// The stable identifier of the key, if values are being recorded and
+    /// the key registered one.
+    pub stable_id: Option<&'static str>,
+    /// The value an insert stored, or the value a `find_mut` left behind,
+    /// if values are being recorded and the key has a JSON codec.
+    pub value: Option<Json>
+}
+
+/// A TypeMap which records every operation on it, and can assert which
+/// operations were made.
+///
+/// Useful for testing code which is handed a map, such as middleware.
+pub struct RecordingTypeMap {
+    map: TypeMap,
+    log: RefCell<Vec<Operation>>,
+    registry: Option<Rc<KeyRegistry>>,
+    // The index of a `find_mut` whose value is recorded once the borrow
+    // has ended, at the next operation.
+    pending: Cell<Option<uint>>
+}
+
+impl RecordingTypeMap {
+    /// Create a new, empty RecordingTypeMap.
+    pub fn new() -> RecordingTypeMap {
+        RecordingTypeMap::from_map(TypeMap::new())
+    }
+
+    /// Record the operations on an existing map.
+    pub fn from_map(map: TypeMap) -> RecordingTypeMap {
+        RecordingTypeMap {
+            map: map,
+            log: RefCell::new(Vec::new()),
+            registry: None,
+            pending: Cell::new(None)
+        }
+    }
+
+    /// Also record the stable identifiers of keys, and the values written,
+    /// as encoded by the registry, so the log can be replayed.
+    pub fn record_values(&mut self, registry: Rc<KeyRegistry>) {
+        self.registry = Some(registry);
+    }
+
+    /// Insert a value into the map with a specified key type.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        self.settle();
+        let value = self.encode(&TypeId::of::<K>(), &val as &(Any + 'static));
+        let new = self.map.insert::<K, V>(val);
+        self.record::<K>(Insert, true, value);
+        new
+    }
+
+    /// Find a value in the map and get a reference to it.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        let found = self.map.find::<K, V>();
+        self.record::<K>(Find, found.is_some(), None);
+        found
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    pub fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> {
+        // A frozen value is found but not handed out, so record the result
+        // of find_mut itself, held as a pointer while the lookup is logged.
+        let found = self.map.find_mut::<K, V>().map(|val| val as *mut V);
+        self.record::<K>(FindMut, found.is_some(), None);
+        if found.is_some() { self.pending.set(Some(self.log.borrow().len() - 1)) }
+        found.map(|val| unsafe { &mut *val })
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        let found = self.map.contains::<K, V>();
+        self.record::<K>(Contains, found, None);
+        found
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        self.settle();
+        let removed = self.map.remove::<K, V>();
+        self.record::<K>(Remove, removed, None);
+        removed
+    }
+
+    /// Get the operations recorded so far, oldest first.
+    pub fn operations(&self) -> Vec<Operation> {
+        self.settle();
+        self.log.borrow().clone()
+    }
+
+    /// Forget the operations recorded so far.
+    pub fn reset(&self) {
+        self.pending.set(None);
+        self.log.borrow_mut().clear()
+    }
+
+    /// Panic unless a value was inserted for a key.
+    pub fn assert_inserted<K: Assoc<V>, V: 'static>(&self) {
+        if !self.any::<K>(|op| op == Insert) {
+            panic!("expected an insert of {}, got: {}", type_name::<K>(), self.describe());
+        }
+    }
+
+    /// Panic if a value was ever looked up for a key.
+    pub fn assert_never_read<K: Assoc<V>, V: 'static>(&self) {
+        if self.any::<K>(|op| op == Find || op == FindMut || op == Contains) {
+            panic!("expected no reads of {}, got: {}", type_name::<K>(), self.describe());
+        }
+    }
+
+    /// Panic if a value was ever removed for a key.
+    pub fn assert_never_removed<K: Assoc<V>, V: 'static>(&self) {
+        if self.any::<K>(|op| op == Remove) {
+            panic!("expected no removals of {}, got: {}", type_name::<K>(), self.describe());
+        }
+    }
+
+    /// Stop recording, returning the map.
+    pub fn into_inner(self) -> TypeMap {
+        self.map
+    }
+
+    fn record<K: 'static>(&self, op: Op, success: bool, value: Option<Json>) {
+        self.settle();
+        let id = TypeId::of::<K>();
+        let stable_id = match self.registry {
+            Some(ref registry) => registry.find(&id).and_then(|info| info.stable_id()),
+            None => None
+        };
+        self.log.borrow_mut().push(Operation {
+            key: id,
+            name: type_name::<K>(),
+            op: op,
+            success: success,
+            stable_id: stable_id,
+            value: value
+        });
+    }
+
+    // Record the value left behind by the last `find_mut`, if it is still
+    // waiting for one.
+    fn settle(&self) {
+        let index = match self.pending.get() {
+            Some(index) => index,
+            None => return
+        };
+        self.pending.set(None);
+        let id = self.log.borrow().as_slice()[index].key;
+        let value = match self.map.data.find(&self.map.resolve(id)) {
+            Some(val) => self.encode(&id, &**val),
+            None => None
+        };
+        self.log.borrow_mut().as_mut_slice()[index].value = value;
+    }
+
+    fn encode(&self, id: &TypeId, val: &(Any + 'static)) -> Option<Json> {
+        match self.registry {
+            Some(ref registry) => registry.find(id).and_then(|info| info.encode(val)),
+            None => None
+        }
+    }
+
+    fn any<K: 'static>(&self, pred: |Op| -> bool) -> bool {
+        let id = TypeId::of::<K>();
+        self.log.borrow().iter().any(|operation| operation.key == id && pred(operation.op.clone()))
+    }
+
+    fn describe(&self) -> String {
+        let ops: Vec<String> = self.log.borrow().iter().map(|op| op.to_string()).collect();
+        format!("[{}]", ops.connect(", "))
+    }
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Replay recorded operations onto the map. Replaying the first few
+    /// operations of a log onto an empty map rebuilds the map as it was at
+    /// that point.
+    ///
+    /// Only the inserts, mutable lookups and removals of keys registered
+    /// with `register_replicated` which were recorded with their values are
+    /// replayed; the rest, including every other lookup, are skipped.
+    /// Values of frozen keys are left as they are. Returns the number of
+    /// operations which changed the map, or the error of the first value
+    /// which cannot be decoded, where replaying stops.
+    pub fn replay(&mut self, operations: &[Operation], registry: &KeyRegistry) -> Result<uint, json::DecoderError> {
+        let mut replayed = 0;
+        for operation in operations.iter() {
+            let info = match operation.stable_id.and_then(|id| registry.find_stable(id)) {
+                Some(info) if info.can_replicate() => info,
+                _ => continue
+            };
+            let slot = self.resolve(info.key());
+            match (&operation.op, &operation.value) {
+                (&Insert, &Some(ref value)) | (&FindMut, &Some(ref value)) => {
+                    let val = try!(info.decode(value.clone()).unwrap());
+                    if self.store_id(slot, val).is_err() { continue }
+                    self.record_id(&slot, |stats| stats.insert());
+                    self.expected.record_type(slot, info.value(), info.value_name());
+                    self.name_slot(slot, info.key(), info.name());
+                },
+                (&Remove, _) if operation.success => if self.evict_id(slot).is_none() { continue },
+                _ => continue
+            }
+            replayed += 1;
+        }
+        self.debug_check();
+        Ok(replayed)
+    }
+}
+
+impl fmt::Show for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({}) -> {}", self.op, self.name, self.success)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use super::{RecordingTypeMap, Insert, Find, Remove};
+    use {TypeMap, Assoc, KeyRegistry, StableKey};
+
+    struct Session;
+
+    struct User;
+
+    impl Assoc<uint> for Session {}
+    impl Assoc<uint> for User {}
+
+    impl StableKey<uint> for Session {
+        fn stable_id(_: Option<Session>) -> &'static str { "test.session" }
+    }
+
+    #[test] fn test_records_operations() {
+        let mut map = RecordingTypeMap::new();
+        map.insert::<Session, uint>(1);
+        map.find::<User, uint>();
+        map.remove::<Session, uint>();
+
+        let ops: Vec<_> = map.operations().into_iter().map(|op| (op.op, op.success)).collect();
+        assert_eq!(ops, vec![(Insert, true), (Find, false), (Remove, true)]);
+        map.assert_inserted::<Session, uint>();
+        map.assert_never_read::<Session, uint>();
+        map.assert_never_removed::<User, uint>();
+    }
+
+    #[test] #[should_fail] fn test_assert_never_read() {
+        let mut map = RecordingTypeMap::new();
+        map.insert::<Session, uint>(1);
+        map.contains::<Session, uint>();
+        map.assert_never_read::<Session, uint>();
+    }
+
+    #[test] fn test_replay() {
+        let mut registry = KeyRegistry::new();
+        registry.register_replicated::<Session, uint>();
+        let registry = Rc::new(registry);
+
+        let mut map = RecordingTypeMap::new();
+        map.record_values(registry.clone());
+        map.insert::<Session, uint>(1);
+        *map.find_mut::<Session, uint>().unwrap() += 1;
+        map.insert::<User, uint>(7);
+        map.remove::<Session, uint>();
+
+        let log = map.operations();
+        assert_eq!(log[0].stable_id, Some("test.session"));
+        assert_eq!(log[2].stable_id, None);
+        let at = |n: uint| {
+            let mut replayed = TypeMap::new();
+            replayed.replay(log.slice_to(n), &*registry).unwrap();
+            replayed
+        };
+        assert_eq!(at(1).find::<Session, uint>(), Some(&1));
+        assert_eq!(at(3).find::<Session, uint>(), Some(&2));
+        assert!(!at(3).contains::<User, uint>());
+        assert!(at(4).is_empty());
+        assert_eq!(TypeMap::new().replay(log.as_slice(), &*registry), Ok(3));
+
+        let mut frozen = TypeMap::new();
+        frozen.insert::<Session, uint>(5);
+        frozen.freeze::<Session, uint>();
+        assert_eq!(frozen.replay(log.as_slice(), &*registry), Ok(0));
+        assert_eq!(frozen.find::<Session, uint>(), Some(&5));
+    }
+
+    #[test] fn test_find_mut_frozen() {
+        let mut map = RecordingTypeMap::new();
+        map.insert::<Session, uint>(1);
+        map.map.freeze::<Session, uint>();
+        assert!(map.find_mut::<Session, uint>().is_none());
+        assert!(!map.operations()[1].success);
+    }
+}
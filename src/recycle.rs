@@ -0,0 +1,82 @@
+//! A free-list of value allocations, reused by later inserts.
+
+use std::any::Any;
+use std::collections::{hashmap, HashMap};
+use std::{mem, ptr};
+
+use alloc::heap;
+
+use downcast::UncheckedBoxAnyDowncast;
+
+/// The number of free allocations kept for each size and alignment.
+static MAX_FREE: uint = 4;
+
+/// Allocations freed by removals, grouped by size and alignment.
+pub struct FreeList {
+    blocks: HashMap<(uint, uint), Vec<*mut u8>>
+}
+
+impl FreeList {
+    pub fn new() -> FreeList {
+        FreeList { blocks: HashMap::new() }
+    }
+
+    /// Box a value, reusing a free allocation of the right layout if there is one.
+    pub fn boxed<V: 'static>(&mut self, val: V) -> Box<Any + 'static> {
+        let block = match self.blocks.find_mut(&layout::<V>()) {
+            Some(blocks) => blocks.pop(),
+            None => None
+        };
+
+        match block {
+            Some(block) => unsafe {
+                let block = block as *mut V;
+                ptr::write(block, val);
+                let val: Box<V> = mem::transmute(block);
+                val as Box<Any + 'static>
+            },
+            None => box val as Box<Any + 'static>
+        }
+    }
+
+    /// Drop a boxed value, keeping its allocation for reuse if there is room.
+    ///
+    /// The box must contain a value of type `V`.
+    pub unsafe fn release<V: 'static>(&mut self, val: Box<Any + 'static>) {
+        drop(self.take::<V>(val))
+    }
+
+    /// Move a value out of its box, keeping the allocation for reuse if
+    /// there is room.
+    ///
+    /// The box must contain a value of type `V`.
+    pub unsafe fn take<V: 'static>(&mut self, val: Box<Any + 'static>) -> V {
+        let val = val.downcast_unchecked::<V>();
+        let (size, align) = layout::<V>();
+        if size == 0 { return *val }
+
+        let blocks = match self.blocks.entry((size, align)) {
+            hashmap::Occupied(e) => e.into_mut(),
+            hashmap::Vacant(e) => e.set(Vec::new())
+        };
+        if blocks.len() == MAX_FREE { return *val }
+
+        let block: *mut V = mem::transmute(val);
+        blocks.push(block as *mut u8);
+        ptr::read(block as *const V)
+    }
+}
+
+impl Drop for FreeList {
+    fn drop(&mut self) {
+        for (&(size, align), blocks) in self.blocks.iter() {
+            for &block in blocks.iter() {
+                unsafe { heap::deallocate(block, size, align) }
+            }
+        }
+    }
+}
+
+fn layout<V>() -> (uint, uint) {
+    (mem::size_of::<V>(), mem::min_align_of::<V>())
+}
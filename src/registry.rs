@@ -0,0 +1,851 @@
+//! Runtime metadata about key types.
+//!
+//! A `KeyRegistry` records, for each registered key, the names of the key
+//! and value types and the TypeId of the value type. Code which only knows
+//! TypeIds at runtime can use it to check and describe entries.
+//!
+//! Keys can also register operations on their values, like formatting and
+//! comparison, which are then available for entries of unknown type,
+//! stable identifiers, which unlike TypeIds are the same in every build, and
+//! short runtime names, for looking entries up from scripts and consoles.
+
+use std::any::Any;
+use std::collections::{hashmap, HashMap, HashSet, TreeMap};
+use std::fmt;
+use std::fmt::Show;
+use std::hash::Hash;
+use std::hash::sip::SipState;
+use std::intrinsics::TypeId;
+
+use serialize::Decodable;
+use serialize::json::{mod, Json, ToJson};
+
+use downcast::UncheckedAnyDowncast;
+
+use super::{TypeMap, Storage, Assoc, TypeMapError, MissingKey, TypeMismatch, type_name};
+
+/// Metadata about a registered key type.
+///
+/// Its fields can only be set by the registry, which checks them: the
+/// operations it holds are trusted to work on values of the key's value
+/// type.
+pub struct KeyInfo {
+    key: TypeId,
+    name: &'static str,
+    value_name: &'static str,
+    value: TypeId,
+    stable_id: Option<&'static str>,
+    runtime_name: Option<&'static str>,
+    show: Option<fn(&(Any + 'static)) -> String>,
+    eq: Option<fn(&(Any + 'static), &(Any + 'static)) -> bool>,
+    json: Option<fn(&(Any + 'static)) -> Json>,
+    decode: Option<fn(Json) -> Result<Box<Any + 'static>, json::DecoderError>>,
+    hash: Option<fn(&(Any + 'static), &mut SipState)>,
+    upgrades: Vec<fn(Json) -> Json>
+}
+
+/// A description of an entry in a map, from `TypeMap::describe`.
+pub struct Description<'a> {
+    /// The TypeId of the entry's key.
+    pub key: TypeId,
+    /// The metadata of the key, if it is registered.
+    pub info: Option<&'a KeyInfo>
+}
+
+/// A handle to a registered key, for code which chooses keys at runtime
+/// and cannot name their types.
+///
+/// It carries the operations the key registered, so it can check for,
+/// format and remove the key's value in any map.
+pub struct ErasedKey {
+    key: TypeId,
+    name: &'static str,
+    show: Option<fn(&(Any + 'static)) -> String>
+}
+
+/// A key with an identifier which, unlike its TypeId, stays the same
+/// across builds, so it can be used to persist entries or send them to
+/// other processes.
+pub trait StableKey<V: 'static>: Assoc<V> {
+    /// Get the key's stable identifier, such as `"myapp.session"`.
+    fn stable_id(_: Option<Self>) -> &'static str;
+}
+
+/// A registry of metadata about key types.
+pub struct KeyRegistry {
+    keys: HashMap<TypeId, KeyInfo>,
+    names: TreeMap<String, TypeId>
+}
+
+impl KeyRegistry {
+    /// Create a new registry with no keys.
+    pub fn new() -> KeyRegistry {
+        KeyRegistry { keys: HashMap::new(), names: TreeMap::new() }
+    }
+
+    /// Register a key, getting its metadata.
+    ///
+    /// Registering the same key again returns the existing metadata.
+    pub fn register<K: Assoc<V>, V: 'static>(&mut self) -> &KeyInfo {
+        &*self.entry::<K, V>()
+    }
+
+    // Get the metadata of a key to fill in, registering it if needed.
+    fn entry<K: Assoc<V>, V: 'static>(&mut self) -> &mut KeyInfo {
+        match self.keys.entry(TypeId::of::<K>()) {
+            hashmap::Occupied(e) => e.into_mut(),
+            hashmap::Vacant(e) => e.set(KeyInfo {
+                key: TypeId::of::<K>(),
+                name: type_name::<K>(),
+                value_name: type_name::<V>(),
+                value: TypeId::of::<V>(),
+                stable_id: None,
+                runtime_name: None,
+                show: None,
+                eq: None,
+                json: None,
+                decode: None,
+                hash: None,
+                upgrades: Vec::new()
+            })
+        }
+    }
+
+    /// Register a key whose values can be formatted with `Show`.
+    pub fn register_show<K: Assoc<V>, V: Show + 'static>(&mut self) -> &KeyInfo {
+        let info = self.entry::<K, V>();
+        info.show = Some(show::<V> as fn(&(Any + 'static)) -> String);
+        &*info
+    }
+
+    /// Register a key whose values can be formatted with `Show` and
+    /// compared with `PartialEq`, so maps containing it can be diffed.
+    pub fn register_comparable<K: Assoc<V>, V: Show + PartialEq + 'static>(&mut self) -> &KeyInfo {
+        self.register_show::<K, V>();
+        let info = self.entry::<K, V>();
+        info.eq = Some(eq::<V> as fn(&(Any + 'static), &(Any + 'static)) -> bool);
+        &*info
+    }
+
+    /// Register a key whose values can be converted to JSON.
+    pub fn register_json<K: Assoc<V>, V: ToJson + 'static>(&mut self) -> &KeyInfo {
+        let info = self.entry::<K, V>();
+        info.json = Some(to_json::<V> as fn(&(Any + 'static)) -> Json);
+        &*info
+    }
+
+    /// Register a key with its stable identifier.
+    ///
+    /// Panics if another key has registered the same identifier.
+    pub fn register_stable<K: StableKey<V>, V: 'static>(&mut self) -> &KeyInfo {
+        let id = StableKey::stable_id(None::<K>);
+        match self.find_stable(id) {
+            Some(info) if info.key != TypeId::of::<K>() =>
+                panic!("stable key id {} is used by both {} and {}", id, info.name, type_name::<K>()),
+            _ => ()
+        }
+
+        let info = self.entry::<K, V>();
+        info.stable_id = Some(id);
+        &*info
+    }
+
+    /// Register a key with a runtime name, such as `"session"`, by which
+    /// code that does not know the key type can look up its value.
+    ///
+    /// Registering a key again with another name replaces its old name.
+    /// Panics if another key has registered the same name.
+    pub fn register_named<K: Assoc<V>, V: 'static>(&mut self, name: &'static str) -> &KeyInfo {
+        let key = TypeId::of::<K>();
+        match self.find_named(name) {
+            Some(info) if info.key != key =>
+                panic!("key name {} is used by both {} and {}", name, info.name, type_name::<K>()),
+            _ => ()
+        }
+
+        match self.keys.find(&key).and_then(|info| info.runtime_name) {
+            Some(old) => { self.names.remove(&old.to_string()); },
+            None => ()
+        }
+        self.names.insert(name.to_string(), key);
+
+        let info = self.entry::<K, V>();
+        info.runtime_name = Some(name);
+        &*info
+    }
+
+    /// Get the metadata of the key with a runtime name.
+    pub fn find_named(&self, name: &str) -> Option<&KeyInfo> {
+        self.names.find(&name.to_string()).and_then(|key| self.keys.find(key))
+    }
+
+    /// Get the runtime names of every named key, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        self.names.keys().map(|name| name.as_slice()).collect()
+    }
+
+    /// Register a key whose values can be converted to and from JSON under
+    /// its stable identifier, so its changes can be sent to other processes
+    /// with `TypeMap::take_changes`.
+    pub fn register_replicated<K: StableKey<V>, V: ToJson + Decodable<json::Decoder, json::DecoderError> + 'static>
+                              (&mut self) -> &KeyInfo {
+        self.register_json::<K, V>();
+        self.register_stable::<K, V>();
+        let info = self.entry::<K, V>();
+        info.decode = Some(from_json::<V> as fn(Json) -> Result<Box<Any + 'static>, json::DecoderError>);
+        &*info
+    }
+
+    /// Register a change in the shape of a key's values: values of the
+    /// key are now of `version`, and `upgrade` converts the JSON of a
+    /// value of the previous version to it.
+    ///
+    /// Values start at version 0, so the first migration is version 1.
+    /// Panics if `version` is not the one after the key's current version.
+    pub fn register_migration<K: Assoc<V>, V: 'static>(&mut self, version: u64, upgrade: fn(Json) -> Json) -> &KeyInfo {
+        let info = self.entry::<K, V>();
+        if version != info.version() + 1 {
+            panic!("migration of key {} to version {} does not follow version {}", info.name, version, info.version())
+        }
+        info.upgrades.push(upgrade);
+        &*info
+    }
+
+    /// Register a key whose values can be hashed, with its stable
+    /// identifier, so it is included in `TypeMap::fingerprint`.
+    pub fn register_hashable<K: StableKey<V>, V: Hash + 'static>(&mut self) -> &KeyInfo {
+        self.register_stable::<K, V>();
+        let info = self.entry::<K, V>();
+        info.hash = Some(hash_value::<V> as fn(&(Any + 'static), &mut SipState));
+        &*info
+    }
+
+    /// Get the metadata of the key with a stable identifier.
+    pub fn find_stable(&self, id: &str) -> Option<&KeyInfo> {
+        self.keys.values().find(|info| info.stable_id == Some(id))
+    }
+
+    /// Get the metadata of every registered key, in no particular order.
+    pub fn keys<'a>(&'a self) -> Vec<&'a KeyInfo> {
+        self.keys.values().collect()
+    }
+
+    /// Get the metadata of a registered key.
+    pub fn find(&self, key: &TypeId) -> Option<&KeyInfo> {
+        self.keys.find(key)
+    }
+
+    /// Check if a key is registered.
+    pub fn contains<K: 'static>(&self) -> bool {
+        self.keys.contains_key(&TypeId::of::<K>())
+    }
+
+    /// Get the number of registered keys.
+    pub fn len(&self) -> uint {
+        self.keys.len()
+    }
+
+    /// Format the value stored in a map for a key, if the key registered a
+    /// way to format it.
+    pub fn show<S: Storage>(&self, map: &TypeMap<S>, key: &TypeId) -> Option<String> {
+        match (self.find(key).and_then(|info| info.show), map.data.find(&map.resolve(*key))) {
+            (Some(show), Some(val)) => Some(show(&**val)),
+            _ => None
+        }
+    }
+
+    /// Describe the differences between two maps, one line per key.
+    ///
+    /// Keys only in `left` are prefixed with `-`, keys only in `right` with
+    /// `+`, and keys whose values differ with `~`. Values of keys which did
+    /// not register a comparison are always reported as different.
+    pub fn diff<S: Storage, T: Storage>(&self, left: &TypeMap<S>, right: &TypeMap<T>) -> Vec<String> {
+        let mut keys: HashSet<TypeId> = left.data.entries().map(|(id, _)| id).collect();
+        keys.extend(right.data.entries().map(|(id, _)| id));
+
+        let mut lines = Vec::new();
+        for key in keys.iter() {
+            let name = self.name(key);
+            let show = |val: &(Any + 'static)| match self.find(key).and_then(|info| info.show) {
+                Some(show) => show(val),
+                None => "<value>".to_string()
+            };
+            match (left.data.find(key), right.data.find(key)) {
+                (Some(a), None) => lines.push(format!("- {}: {}", name, show(&**a))),
+                (None, Some(b)) => lines.push(format!("+ {}: {}", name, show(&**b))),
+                (Some(a), Some(b)) => {
+                    let same = match self.find(key).and_then(|info| info.eq) {
+                        Some(eq) => eq(&**a, &**b),
+                        None => false
+                    };
+                    if !same {
+                        lines.push(format!("~ {}: {} != {}", name, show(&**a), show(&**b)));
+                    }
+                },
+                (None, None) => ()
+            }
+        }
+        lines.sort();
+        lines
+    }
+
+    fn name(&self, key: &TypeId) -> String {
+        match self.find(key) {
+            Some(info) => format!("{} ({})", info.name, info.value_name),
+            None => format!("unregistered key {}", key)
+        }
+    }
+}
+
+impl KeyInfo {
+    /// Get the TypeId of the key type.
+    pub fn key(&self) -> TypeId {
+        self.key
+    }
+
+    /// Get the name of the key type.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Get the name of the value type associated with the key.
+    pub fn value_name(&self) -> &'static str {
+        self.value_name
+    }
+
+    /// Get the TypeId of the value type associated with the key.
+    pub fn value(&self) -> TypeId {
+        self.value
+    }
+
+    /// Get the stable identifier of the key, if it registered one.
+    pub fn stable_id(&self) -> Option<&'static str> {
+        self.stable_id
+    }
+
+    /// Get the runtime name of the key, if it registered one.
+    pub fn runtime_name(&self) -> Option<&'static str> {
+        self.runtime_name
+    }
+
+    /// Check if values of the key can be formatted.
+    pub fn can_show(&self) -> bool {
+        self.show.is_some()
+    }
+
+    /// Check if values of the key can be compared.
+    pub fn can_compare(&self) -> bool {
+        self.eq.is_some()
+    }
+
+    /// Get an erased handle to the key.
+    pub fn erased(&self) -> ErasedKey {
+        ErasedKey { key: self.key, name: self.name, show: self.show }
+    }
+
+    /// Check if values of the key can be replicated.
+    pub fn can_replicate(&self) -> bool {
+        self.stable_id.is_some() && self.json.is_some() && self.decode.is_some()
+    }
+
+    /// Convert a value of the key to JSON, if the key registered a way to.
+    pub fn encode(&self, val: &(Any + 'static)) -> Option<Json> {
+        self.json.map(|to_json| to_json(val))
+    }
+
+    /// Get the version of the shape of the key's values, the number of
+    /// migrations it registered.
+    pub fn version(&self) -> u64 {
+        self.upgrades.len() as u64
+    }
+
+    /// Convert the JSON of a value of an older version of the key to the
+    /// current version, with the key's migrations.
+    ///
+    /// Returns `None` if the version is newer than the key's.
+    pub fn upgrade(&self, version: u64, json: Json) -> Option<Json> {
+        if version > self.version() { return None }
+        let mut json = json;
+        for upgrade in self.upgrades.slice_from(version as uint).iter() {
+            json = (*upgrade)(json);
+        }
+        Some(json)
+    }
+
+    /// Convert JSON to a boxed value of the key, if the key registered a
+    /// way to.
+    pub fn decode(&self, json: Json) -> Option<Result<Box<Any + 'static>, json::DecoderError>> {
+        self.decode.map(|decode| decode(json))
+    }
+}
+
+impl ErasedKey {
+    /// Get the TypeId of the key type.
+    pub fn key(&self) -> TypeId {
+        self.key
+    }
+
+    /// Get the name of the key type.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Check if the key has an associated value stored in a map.
+    pub fn contains<S: Storage>(&self, map: &TypeMap<S>) -> bool {
+        map.contains_id(&self.key)
+    }
+
+    /// Format the key's value in a map, if it has one and the key
+    /// registered a way to format it.
+    pub fn show<S: Storage>(&self, map: &TypeMap<S>) -> Option<String> {
+        match (self.show, map.data.find(&map.resolve(self.key))) {
+            (Some(show), Some(val)) => Some(show(&**val)),
+            _ => None
+        }
+    }
+
+    /// Remove the key's value from a map.
+    ///
+    /// Returns `true` if a value was removed. Frozen values are not removed.
+    pub fn remove<S: Storage>(&self, map: &mut TypeMap<S>) -> bool {
+        let id = map.resolve(self.key);
+        let removed = map.evict_id(id).is_some();
+        map.debug_check();
+        removed
+    }
+}
+
+impl Clone for ErasedKey {
+    fn clone(&self) -> ErasedKey {
+        ErasedKey { key: self.key, name: self.name, show: self.show }
+    }
+}
+
+impl<'a> fmt::Show for Description<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let info = match self.info {
+            Some(info) => info,
+            None => return write!(f, "unregistered key {}", self.key)
+        };
+        try!(write!(f, "{}: {}", info.name, info.value_name));
+        if info.can_show() { try!(write!(f, " [show]")) }
+        if info.can_compare() { try!(write!(f, " [compare]")) }
+        match info.stable_id {
+            Some(id) => write!(f, " [stable id {}]", id),
+            None => Ok(())
+        }
+    }
+}
+
+fn show<V: Show + 'static>(val: &(Any + 'static)) -> String {
+    unsafe { val.downcast_ref_unchecked::<V>() }.to_string()
+}
+
+fn to_json<V: ToJson + 'static>(val: &(Any + 'static)) -> Json {
+    unsafe { val.downcast_ref_unchecked::<V>() }.to_json()
+}
+
+fn from_json<V: Decodable<json::Decoder, json::DecoderError> + 'static>(json: Json)
+                                                                   -> Result<Box<Any + 'static>, json::DecoderError> {
+    let mut decoder = json::Decoder::new(json);
+    let val: V = try!(Decodable::decode(&mut decoder));
+    Ok(box val as Box<Any + 'static>)
+}
+
+fn hash_value<V: Hash + 'static>(val: &(Any + 'static), state: &mut SipState) {
+    unsafe { val.downcast_ref_unchecked::<V>() }.hash(state)
+}
+
+fn eq<V: PartialEq + 'static>(a: &(Any + 'static), b: &(Any + 'static)) -> bool {
+    unsafe { a.downcast_ref_unchecked::<V>() == b.downcast_ref_unchecked::<V>() }
+}
+
+impl TypeMap {
+    /// Create a TypeMap from a raw HashMap, checking every entry against
+    /// the registry.
+    ///
+    /// Panics if an entry's key is not registered, or if its box does not
+    /// contain a value of the type registered for the key.
+    pub fn from_raw(raw: HashMap<TypeId, Box<Any + 'static>>, registry: &KeyRegistry) -> TypeMap {
+        for (key, val) in raw.iter() {
+            let info = match registry.find(key) {
+                Some(info) => info,
+                None => panic!("TypeMap::from_raw: unregistered key {}", key)
+            };
+            if (**val).get_type_id() != info.value {
+                panic!("TypeMap::from_raw: {} is not a value of type {}", info.name, info.value_name);
+            }
+        }
+        TypeMap::with_storage(raw)
+    }
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Describe every entry of the map using the metadata in a registry,
+    /// registered entries first, sorted by key name.
+    pub fn describe<'a>(&self, registry: &'a KeyRegistry) -> Vec<Description<'a>> {
+        let mut descriptions: Vec<Description<'a>> = self.data.entries().map(|(key, _)| {
+            Description { key: key, info: self.info_for(registry, &key) }
+        }).collect();
+        descriptions.sort_by(|a, b| {
+            let name = |d: &Description| d.info.map(|info| info.name).unwrap_or("~");
+            name(a).cmp(&name(b))
+        });
+        descriptions
+    }
+
+    /// Render the map as a JSON object keyed by the names of its key types,
+    /// for attaching to logs and bug reports.
+    ///
+    /// Values of keys which registered a JSON conversion are rendered with
+    /// it, and values of keys which can only be formatted are rendered as
+    /// strings. The values of other keys are `null`.
+    pub fn to_debug_json(&self, registry: &KeyRegistry) -> Json {
+        let mut object = TreeMap::new();
+        for (key, val) in self.data.entries() {
+            let (name, rendered) = match self.info_for(registry, &key) {
+                Some(info) => (info.name.to_string(), match (info.json, info.show) {
+                    (Some(to_json), _) => to_json(val),
+                    (None, Some(show)) => json::String(show(val)),
+                    (None, None) => json::Null
+                }),
+                None => (format!("unregistered key {}", key), json::Null)
+            };
+            object.insert(name, rendered);
+        }
+        json::Object(object)
+    }
+
+    /// Compute a hash of the entries of keys registered as hashable, which
+    /// is the same in every build and process for maps with equal values
+    /// for those keys, as long as their `Hash` implementations are too.
+    ///
+    /// Values of types whose size depends on the platform, like `uint`,
+    /// hash differently on 32-bit and 64-bit platforms, so fingerprints
+    /// are only comparable between builds for the same word size. Entries
+    /// of keys which are not hashable are ignored.
+    pub fn fingerprint(&self, registry: &KeyRegistry) -> u64 {
+        let mut entries = Vec::new();
+        for (key, val) in self.data.entries() {
+            match self.info_for(registry, &key) {
+                Some(&KeyInfo { stable_id: Some(id), hash: Some(hash), .. }) => entries.push((id, hash, val)),
+                _ => ()
+            }
+        }
+        entries.sort_by(|&(a, _, _), &(b, _, _)| a.cmp(&b));
+
+        let mut state = SipState::new();
+        for &(id, hash, val) in entries.iter() {
+            id.hash(&mut state);
+            hash(val, &mut state);
+        }
+        state.result()
+    }
+
+    /// Find the value of the key with a runtime name.
+    pub fn find_by_name(&self, registry: &KeyRegistry, name: &str) -> Option<&(Any + 'static)> {
+        registry.find_named(name).and_then(|info| self.data.find(&self.resolve(info.key))).map(|val| &**val)
+    }
+
+    /// Find the value of the key with a stable identifier.
+    pub fn find_stable(&self, registry: &KeyRegistry, id: &str) -> Option<&(Any + 'static)> {
+        registry.find_stable(id).and_then(|info| self.data.find(&self.resolve(info.key))).map(|val| &**val)
+    }
+
+    /// Find the value of the key with a stable identifier as a `V`.
+    ///
+    /// Fails with `MissingKey` if the key has no value, and with
+    /// `TypeMismatch` if its value type is not `V`.
+    pub fn try_find_stable<V: 'static>(&self, registry: &KeyRegistry, id: &str) -> Result<&V, TypeMapError> {
+        let info = match registry.find_stable(id) {
+            Some(info) => info,
+            None => return Err(MissingKey("<unregistered stable id>", type_name::<V>()))
+        };
+        if info.value != TypeId::of::<V>() {
+            return Err(TypeMismatch(info.name, type_name::<V>(), info.value_name))
+        }
+        match self.data.find(&self.resolve(info.key)) {
+            Some(val) => Ok(unsafe { val.downcast_ref_unchecked::<V>() }),
+            None => Err(MissingKey(info.name, info.value_name))
+        }
+    }
+
+    /// Get the stable identifier and value of every entry whose key
+    /// registered one, sorted by identifier.
+    pub fn stable_entries(&self, registry: &KeyRegistry) -> Vec<(&'static str, &(Any + 'static))> {
+        let mut entries = Vec::new();
+        for (key, val) in self.data.entries() {
+            match self.info_for(registry, &key).and_then(|info| info.stable_id) {
+                Some(id) => entries.push((id, val)),
+                None => ()
+            }
+        }
+        entries.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+        entries
+    }
+
+    // Get the metadata of the key whose value is stored in a slot: the
+    // slot's own key if it is registered, or else a key aliased to it.
+    fn info_for<'a>(&self, registry: &'a KeyRegistry, slot: &TypeId) -> Option<&'a KeyInfo> {
+        match registry.find(slot) {
+            Some(info) => Some(info),
+            None => self.aliases.iter()
+                .filter(|&(_, target)| target == slot)
+                .filter_map(|(alias, _)| registry.find(alias))
+                .next()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::Any;
+    use std::collections::{HashMap, TreeMap};
+    use std::intrinsics::TypeId;
+    use serialize::json::{mod, Json};
+    use super::{KeyRegistry, StableKey, ErasedKey};
+    use {TypeMap, Assoc, TypeMismatch};
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    struct Name;
+
+    struct Opaque;
+
+    impl Assoc<uint> for Key {}
+    impl Assoc<String> for Name {}
+    impl Assoc<uint> for Opaque {}
+
+    impl StableKey<uint> for Key {
+        fn stable_id(_: Option<Key>) -> &'static str { "test.key" }
+    }
+
+    impl StableKey<uint> for Opaque {
+        fn stable_id(_: Option<Opaque>) -> &'static str { "test.key" }
+    }
+
+    #[test] fn test_register() {
+        let mut registry = KeyRegistry::new();
+        registry.register::<Key, uint>();
+        registry.register::<Key, uint>();
+        assert_eq!(registry.len(), 1);
+        assert!(registry.contains::<Key>());
+        assert_eq!(registry.find(&TypeId::of::<Key>()).unwrap().value(), TypeId::of::<uint>());
+    }
+
+    #[test] fn test_from_raw() {
+        let mut registry = KeyRegistry::new();
+        registry.register::<Key, uint>();
+        let mut raw = HashMap::new();
+        raw.insert(TypeId::of::<Key>(), box 5u as Box<Any + 'static>);
+
+        let map = TypeMap::from_raw(raw, &registry);
+        assert_eq!(*map.find::<Key, uint>().unwrap(), 5);
+    }
+
+    #[test] #[should_fail] fn test_from_raw_wrong_type() {
+        let mut registry = KeyRegistry::new();
+        registry.register::<Key, uint>();
+        let mut raw = HashMap::new();
+        raw.insert(TypeId::of::<Key>(), box "five" as Box<Any + 'static>);
+        TypeMap::from_raw(raw, &registry);
+    }
+
+    #[test] fn test_diff() {
+        let mut registry = KeyRegistry::new();
+        registry.register_comparable::<Key, uint>();
+        registry.register_comparable::<Name, String>();
+
+        let mut left = TypeMap::new();
+        left.insert::<Key, uint>(1);
+        left.insert::<Name, String>("left".to_string());
+        let mut right = TypeMap::new();
+        right.insert::<Key, uint>(1);
+        assert_eq!(registry.diff(&left, &right).len(), 1);
+        assert!(registry.diff(&left, &right)[0].as_slice().starts_with("- "));
+
+        right.insert::<Name, String>("right".to_string());
+        let diff = registry.diff(&left, &right);
+        assert_eq!(diff.len(), 1);
+        assert!(diff[0].as_slice().ends_with(": left != right"));
+
+        right.insert::<Name, String>("left".to_string());
+        assert_typemap_eq!(registry, left, right);
+
+        left.insert::<Opaque, uint>(1);
+        right.insert::<Opaque, uint>(1);
+        assert_eq!(registry.diff(&left, &right).len(), 1);
+    }
+
+    #[test] #[should_fail] fn test_assert_typemap_eq() {
+        let mut registry = KeyRegistry::new();
+        registry.register_comparable::<Key, uint>();
+        let mut left = TypeMap::new();
+        left.insert::<Key, uint>(1);
+        assert_typemap_eq!(registry, left, TypeMap::new());
+    }
+
+    #[test] fn test_describe() {
+        let mut registry = KeyRegistry::new();
+        registry.register_comparable::<Key, uint>();
+        registry.register::<Name, String>();
+
+        let mut map = TypeMap::new();
+        map.insert::<Name, String>("name".to_string());
+        map.insert::<Key, uint>(1);
+        map.insert::<Opaque, uint>(2);
+
+        let descriptions = map.describe(&registry);
+        assert_eq!(descriptions.len(), 3);
+        assert!(descriptions[0].info.unwrap().can_compare());
+        assert!(!descriptions[1].info.unwrap().can_show());
+        assert!(descriptions[2].info.is_none());
+        assert!(descriptions[0].to_string().as_slice().ends_with("uint [show] [compare]"));
+    }
+
+    #[test] fn test_to_debug_json() {
+        let mut registry = KeyRegistry::new();
+        registry.register_json::<Key, uint>();
+        registry.register_show::<Name, String>();
+        registry.register::<Opaque, uint>();
+
+        let mut map = TypeMap::new();
+        map.insert::<Key, uint>(1);
+        map.insert::<Name, String>("name".to_string());
+        map.insert::<Opaque, uint>(2);
+
+        let json = map.to_debug_json(&registry).to_string();
+        assert!(json.as_slice().contains("Key\":1"));
+        assert!(json.as_slice().contains("Name\":\"name\""));
+        assert!(json.as_slice().contains("Opaque\":null"));
+    }
+
+    #[test] fn test_stable_ids() {
+        let mut registry = KeyRegistry::new();
+        registry.register_stable::<Key, uint>();
+        registry.register::<Name, String>();
+        assert_eq!(registry.find_stable("test.key").unwrap().key(), TypeId::of::<Key>());
+
+        let mut map = TypeMap::new();
+        map.insert::<Key, uint>(4);
+        map.insert::<Name, String>("name".to_string());
+        assert_eq!(map.find_stable(&registry, "test.key").unwrap().get_type_id(), TypeId::of::<uint>());
+        assert!(map.find_stable(&registry, "test.name").is_none());
+
+        assert_eq!(map.try_find_stable::<uint>(&registry, "test.key"), Ok(&4));
+        match map.try_find_stable::<String>(&registry, "test.key") {
+            Err(TypeMismatch(..)) => (),
+            _ => panic!("Found a value as the wrong type.")
+        }
+
+        let entries = map.stable_entries(&registry);
+        assert_eq!(entries.len(), 1);
+        let (id, _) = entries[0];
+        assert_eq!(id, "test.key");
+    }
+
+    #[test] fn test_aliased_keys() {
+        let mut registry = KeyRegistry::new();
+        registry.register_hashable::<Key, uint>();
+        registry.register_named::<Key, uint>("count");
+
+        let mut map = TypeMap::new();
+        map.alias::<Key, Opaque, uint>();
+        map.insert::<Key, uint>(4);
+        assert!(map.find_stable(&registry, "test.key").is_some());
+        assert!(map.find_by_name(&registry, "count").is_some());
+        assert_eq!(map.try_find_stable::<uint>(&registry, "test.key"), Ok(&4));
+        assert_eq!(map.stable_entries(&registry).len(), 1);
+
+        let mut plain = TypeMap::new();
+        plain.insert::<Key, uint>(4);
+        assert_eq!(map.fingerprint(&registry), plain.fingerprint(&registry));
+    }
+
+    #[test] fn test_runtime_names() {
+        let mut registry = KeyRegistry::new();
+        registry.register_named::<Key, uint>("count");
+        registry.register_named::<Name, String>("user");
+        registry.register_named::<Name, String>("name");
+        assert_eq!(registry.names(), vec!["count", "name"]);
+        assert!(registry.find_named("user").is_none());
+
+        let mut map = TypeMap::new();
+        map.insert::<Key, uint>(4);
+        assert_eq!(map.find_by_name(&registry, "count").unwrap().get_type_id(), TypeId::of::<uint>());
+        assert!(map.find_by_name(&registry, "name").is_none());
+    }
+
+    #[test] fn test_erased_keys() {
+        let mut registry = KeyRegistry::new();
+        registry.register_show::<Key, uint>();
+        registry.register::<Name, String>();
+        let keys: Vec<ErasedKey> = registry.keys().iter().map(|info| info.erased()).collect();
+
+        let mut map = TypeMap::new();
+        map.insert::<Key, uint>(3);
+        map.insert::<Name, String>("name".to_string());
+        for key in keys.iter() {
+            assert!(key.contains(&map));
+            if key.key() == TypeId::of::<Key>() {
+                assert_eq!(key.show(&map), Some("3".to_string()));
+            } else {
+                assert_eq!(key.show(&map), None);
+            }
+            assert!(key.remove(&mut map));
+        }
+        assert!(map.is_empty());
+    }
+
+    #[test] fn test_fingerprint() {
+        let mut registry = KeyRegistry::new();
+        registry.register_hashable::<Key, uint>();
+
+        let mut a = TypeMap::new();
+        let mut b = TypeMap::new();
+        a.insert::<Key, uint>(1);
+        b.insert::<Key, uint>(1);
+        b.insert::<Name, String>("ignored".to_string());
+        assert_eq!(a.fingerprint(&registry), b.fingerprint(&registry));
+
+        b.insert::<Key, uint>(2);
+        assert!(a.fingerprint(&registry) != b.fingerprint(&registry));
+        assert!(TypeMap::new().fingerprint(&registry) != a.fingerprint(&registry));
+    }
+
+    #[test] fn test_migrations() {
+        fn to_object(json: Json) -> Json {
+            let mut object = TreeMap::new();
+            object.insert("count".to_string(), json);
+            json::Object(object)
+        }
+
+        let mut registry = KeyRegistry::new();
+        assert_eq!(registry.register::<Key, uint>().version(), 0);
+        let info = registry.register_migration::<Key, uint>(1, to_object);
+        assert_eq!(info.version(), 1);
+        assert_eq!(info.upgrade(1, json::U64(3)), Some(json::U64(3)));
+        assert_eq!(info.upgrade(0, json::U64(3)).unwrap().find(&"count".to_string()), Some(&json::U64(3)));
+        assert_eq!(info.upgrade(2, json::U64(3)), None);
+    }
+
+    #[test] #[should_fail] fn test_migration_out_of_order() {
+        fn identity(json: Json) -> Json { json }
+
+        let mut registry = KeyRegistry::new();
+        registry.register_migration::<Key, uint>(2, identity);
+    }
+
+    #[test] #[should_fail] fn test_duplicate_name() {
+        let mut registry = KeyRegistry::new();
+        registry.register_named::<Key, uint>("count");
+        registry.register_named::<Opaque, uint>("count");
+    }
+
+    #[test] #[should_fail] fn test_duplicate_stable_id() {
+        let mut registry = KeyRegistry::new();
+        registry.register_stable::<Key, uint>();
+        registry.register_stable::<Opaque, uint>();
+    }
+}
@@ -0,0 +1,295 @@
+//! Incremental replication of a TypeMap to other processes.
+//!
+//! `take_changes` encodes the entries changed since it last ran into a
+//! frame, and `apply_changes` applies such a frame to another map,
+//! which may live in another process. Frames are JSON text, naming keys by
+//! their stable identifiers:
+//!
+//! ```ignore
+//! {"remove":["myapp.cart"],"set":{"myapp.session":{"user":"jonathan"}},"version":2,"versions":{"myapp.session":1}}
+//! ```
+//!
+//! Only keys registered with `KeyRegistry::register_replicated` are
+//! replicated; changes to other keys are skipped.
+//!
+//! Frames carry the version of their format, so processes running
+//! different builds can tell whether they understand each other's frames.
+//! `apply_changes` rejects frames newer than its own format; frames without
+//! a version predate versioning and are read as the first format.
+//!
+//! Values carry the version of their key's shape too, if the key
+//! registered migrations with `KeyRegistry::register_migration`, so a key
+//! whose value type changes shape keeps its stable identifier: values of
+//! older versions are upgraded by the key's migrations before they are
+//! decoded.
+
+use std::any::Any;
+use std::collections::TreeMap;
+use std::error::Error;
+use std::fmt;
+
+use serialize::json::{mod, Json};
+
+use super::{TypeMap, KeyRegistry, KeyInfo, Storage, decode_value};
+
+/// The version of the frame format written by `take_changes`.
+static FRAME_VERSION: u64 = 2;
+
+/// An error applying a frame with `apply_changes`.
+#[deriving(Clone, PartialEq)]
+pub enum ReplicationError {
+    /// The frame, or a value in it, is of this version, newer than this
+    /// build reads.
+    Version(u64),
+    /// The key with this stable identifier is not replicated by the
+    /// registry.
+    UnknownKey(String),
+    /// The frame, or a value in it, cannot be decoded.
+    Decode(json::DecoderError)
+}
+
+impl fmt::Show for ReplicationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Version(version) => write!(f, "version {} is newer than this build reads", version),
+            UnknownKey(ref stable_id) => write!(f, "key {} is not replicated", stable_id),
+            Decode(ref err) => write!(f, "cannot decode frame: {}", err)
+        }
+    }
+}
+
+impl Error for ReplicationError {
+    fn description(&self) -> &str {
+        match *self {
+            Version(..) => "frame or value version is too new",
+            UnknownKey(..) => "key in frame is not replicated",
+            Decode(..) => "frame cannot be decoded"
+        }
+    }
+
+    fn detail(&self) -> Option<String> {
+        Some(self.to_string())
+    }
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Encode the changes to replicated keys since the last call as a frame.
+    ///
+    /// Starts tracking modified entries if the map is not tracking them, so
+    /// the first frame sets every replicated entry stored in the map. This
+    /// does not affect `checkpoint` or `flush_dirty`, which track changes
+    /// separately.
+    pub fn take_changes(&mut self, registry: &KeyRegistry) -> String {
+        self.track_dirty();
+        let mut set = TreeMap::new();
+        let mut versions = TreeMap::new();
+        let mut removed = Vec::new();
+        for info in registry.keys().into_iter() {
+            if !info.can_replicate() { continue }
+            let slot = self.resolve(info.key());
+            if !self.tracking.as_ref().unwrap().unsent.contains(&slot) { continue }
+            let stable_id = info.stable_id().unwrap().to_string();
+            match self.data.find(&slot) {
+                Some(val) => {
+                    if info.version() > 0 { versions.insert(stable_id.clone(), json::U64(info.version())); }
+                    set.insert(stable_id, info.encode(&**val).unwrap());
+                },
+                None => removed.push(json::String(stable_id))
+            }
+        }
+        self.tracking.as_mut().unwrap().unsent.clear();
+
+        let mut frame = TreeMap::new();
+        frame.insert("set".to_string(), json::Object(set));
+        frame.insert("remove".to_string(), json::List(removed));
+        frame.insert("version".to_string(), json::U64(FRAME_VERSION));
+        frame.insert("versions".to_string(), json::Object(versions));
+        json::Object(frame).to_string()
+    }
+
+    /// Apply a frame from `take_changes` to the map, returning the number
+    /// of keys set or removed.
+    ///
+    /// Values of older versions of their keys are upgraded with the keys'
+    /// migrations. If the frame or one of its values is newer than this
+    /// build reads, the frame names a key which is not replicated by the
+    /// registry, or a value cannot be decoded, the map is left unchanged. Keys aliased in this map change
+    /// the values of their targets. Changes to keys frozen in this map, and
+    /// new keys which would overfill it, are skipped. Applied changes do not
+    /// make entries dirty and are not sent back by `take_changes`, but
+    /// `flush_dirty` writes them. The map starts tracking modified entries
+    /// if it is not tracking them.
+    pub fn apply_changes(&mut self, registry: &KeyRegistry, frame: &str) -> Result<uint, ReplicationError> {
+        let mut frame = match try!(json::from_str(frame).map_err(|err| Decode(json::ParseError(err)))) {
+            json::Object(frame) => frame,
+            other => return Err(expected("Object", other))
+        };
+        match frame.pop(&"version".to_string()) {
+            Some(json::U64(version)) if version <= FRAME_VERSION => (),
+            Some(json::U64(version)) => return Err(Version(version)),
+            Some(other) => return Err(expected("U64", other)),
+            None => ()
+        }
+
+        let versions = match frame.pop(&"versions".to_string()) {
+            Some(json::Object(versions)) => versions,
+            Some(other) => return Err(expected("Object", other)),
+            None => TreeMap::new()
+        };
+
+        let mut decoded: Vec<(&KeyInfo, Option<Box<Any + 'static>>)> = Vec::new();
+        match frame.pop(&"set".to_string()) {
+            Some(json::Object(set)) => for (stable_id, val) in set.into_iter() {
+                let info = try!(replicated(registry, stable_id.as_slice()));
+                let version = match versions.find(&stable_id) {
+                    Some(&json::U64(version)) => version,
+                    Some(other) => return Err(expected("U64", other.clone())),
+                    None => 0
+                };
+                decoded.push((info, Some(try!(decode_value(info, version, val)))));
+            },
+            Some(other) => return Err(expected("Object", other)),
+            None => ()
+        }
+        match frame.pop(&"remove".to_string()) {
+            Some(json::List(removed)) => for stable_id in removed.into_iter() {
+                match stable_id {
+                    json::String(stable_id) => decoded.push((try!(replicated(registry, stable_id.as_slice())), None)),
+                    other => return Err(expected("String", other))
+                }
+            },
+            Some(other) => return Err(expected("List", other)),
+            None => ()
+        }
+
+        self.track_dirty();
+        let applied = self.store_decoded(decoded);
+        let tracking = self.tracking.as_mut().unwrap();
+        for slot in applied.iter() {
+            tracking.dirty.remove(slot);
+            tracking.unsent.remove(slot);
+        }
+        Ok(applied.len())
+    }
+}
+
+fn replicated<'a>(registry: &'a KeyRegistry, stable_id: &str) -> Result<&'a KeyInfo, ReplicationError> {
+    match registry.find_stable(stable_id) {
+        Some(info) if info.can_replicate() => Ok(info),
+        _ => Err(UnknownKey(stable_id.to_string()))
+    }
+}
+
+fn expected(kind: &str, found: Json) -> ReplicationError {
+    Decode(json::ExpectedError(kind.to_string(), found.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use serialize::json::{mod, Json};
+    use super::{Version, UnknownKey, Decode};
+    use {TypeMap, Assoc, KeyRegistry, StableKey};
+
+    struct Counter;
+
+    struct Title;
+
+    struct Local;
+
+    impl Assoc<uint> for Counter {}
+    impl Assoc<String> for Title {}
+    impl Assoc<uint> for Local {}
+
+    impl StableKey<uint> for Counter {
+        fn stable_id(_: Option<Counter>) -> &'static str { "test.counter" }
+    }
+
+    impl StableKey<String> for Title {
+        fn stable_id(_: Option<Title>) -> &'static str { "test.title" }
+    }
+
+    #[test] fn test_replication() {
+        let mut registry = KeyRegistry::new();
+        registry.register_replicated::<Counter, uint>();
+        registry.register_replicated::<Title, String>();
+
+        let mut source = TypeMap::new();
+        let mut replica = TypeMap::new();
+        source.insert::<Counter, uint>(1);
+        source.insert::<Title, String>("draft".to_string());
+        source.insert::<Local, uint>(7);
+
+        let frame = source.take_changes(&registry);
+        assert_eq!(replica.apply_changes(&registry, frame.as_slice()), Ok(2));
+        assert_eq!(*replica.find::<Counter, uint>().unwrap(), 1);
+        assert_eq!(replica.find::<Title, String>().unwrap().as_slice(), "draft");
+        assert!(!replica.contains::<Local, uint>());
+        assert_eq!(replica.iter_dirty().count(), 0);
+        assert!(frame.as_slice().ends_with(r#""version":2,"versions":{}}"#));
+
+        *source.find_mut::<Counter, uint>().unwrap() += 1;
+        source.remove::<Title, String>();
+        let frame = source.take_changes(&registry);
+        assert_eq!(replica.apply_changes(&registry, frame.as_slice()), Ok(2));
+        assert_eq!(*replica.find::<Counter, uint>().unwrap(), 2);
+        assert!(!replica.contains::<Title, String>());
+    }
+
+    #[test] fn test_aliased_keys() {
+        let mut registry = KeyRegistry::new();
+        registry.register_replicated::<Counter, uint>();
+
+        let mut source = TypeMap::new();
+        source.alias::<Counter, Local, uint>();
+        source.insert::<Local, uint>(3);
+        let frame = source.take_changes(&registry);
+
+        let mut replica = TypeMap::new();
+        replica.alias::<Counter, Local, uint>();
+        assert_eq!(replica.apply_changes(&registry, frame.as_slice()), Ok(1));
+        assert_eq!(*replica.find::<Local, uint>().unwrap(), 3);
+        assert_eq!(replica.len(), 1);
+    }
+
+    #[test] fn test_value_migrations() {
+        fn to_cents(json: Json) -> Json {
+            match json {
+                json::U64(dollars) => json::U64(dollars * 100),
+                other => other
+            }
+        }
+
+        let mut old = KeyRegistry::new();
+        old.register_replicated::<Counter, uint>();
+        let mut new = KeyRegistry::new();
+        new.register_replicated::<Counter, uint>();
+        new.register_migration::<Counter, uint>(1, to_cents);
+
+        let mut source = TypeMap::new();
+        source.insert::<Counter, uint>(3);
+        let mut replica = TypeMap::new();
+        let frame = source.take_changes(&old);
+        assert_eq!(replica.apply_changes(&new, frame.as_slice()), Ok(1));
+        assert_eq!(*replica.find::<Counter, uint>().unwrap(), 300);
+
+        replica.insert::<Counter, uint>(450);
+        let frame = replica.take_changes(&new);
+        assert!(frame.as_slice().ends_with(r#""versions":{"test.counter":1}}"#));
+        assert_eq!(replica.apply_changes(&new, frame.as_slice()), Ok(1));
+        assert_eq!(*replica.find::<Counter, uint>().unwrap(), 450);
+        assert_eq!(source.apply_changes(&old, frame.as_slice()), Err(Version(1)));
+    }
+
+    #[test] fn test_bad_frame() {
+        let mut registry = KeyRegistry::new();
+        registry.register_replicated::<Counter, uint>();
+
+        let mut map = TypeMap::new();
+        let frame = r#"{"set":{"test.counter":3,"test.unknown":1}}"#;
+        assert_eq!(map.apply_changes(&registry, frame), Err(UnknownKey("test.unknown".to_string())));
+        assert!(match map.apply_changes(&registry, "not json") { Err(Decode(_)) => true, _ => false });
+        assert!(match map.apply_changes(&registry, r#"{"set":{"test.counter":"three"}}"#) { Err(Decode(_)) => true, _ => false });
+        assert_eq!(map.apply_changes(&registry, r#"{"set":{"test.counter":3},"version":2}"#), Err(Version(2)));
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+//! A map of resources which can be borrowed through a shared reference.
+//!
+//! Each value is kept in its own `RefCell`, so many parts of a program can
+//! share one `&ResourceMap` and borrow different resources, mutably or not,
+//! at the same time. Conflicting borrows of the same resource are caught at
+//! runtime.
+
+use std::any::Any;
+use std::cell::{RefCell, Ref, RefMut};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::intrinsics::TypeId;
+
+use downcast::UncheckedAnyDowncast;
+
+use super::{Assoc, type_name};
+
+/// A map of resources, each of which can be borrowed separately.
+pub struct ResourceMap {
+    data: HashMap<TypeId, Box<Any + 'static>>
+}
+
+/// A shared borrow of a resource.
+pub struct Res<'a, K, V: 'a> {
+    inner: Ref<'a, V>
+}
+
+/// A mutable borrow of a resource.
+pub struct ResMut<'a, K, V: 'a> {
+    inner: RefMut<'a, V>
+}
+
+/// The error returned when a resource cannot be borrowed.
+#[deriving(Clone, PartialEq)]
+pub enum BorrowError {
+    /// The map has no value for the key with this name.
+    Missing(&'static str),
+    /// The value of the key with this name is already borrowed in a way
+    /// which conflicts with the requested borrow.
+    Conflict(&'static str)
+}
+
+/// A borrow of a resource, or a tuple of borrows, which can be fetched from
+/// a ResourceMap.
+///
+/// Implemented for `Res`, `ResMut`, and tuples of up to eight of them.
+pub trait Fetch<'a> {
+    /// Acquire the borrows from the map.
+    fn fetch(_: Option<Self>, map: &'a ResourceMap) -> Result<Self, BorrowError>;
+}
+
+impl ResourceMap {
+    /// Create a new, empty ResourceMap.
+    pub fn new() -> ResourceMap {
+        ResourceMap { data: HashMap::new() }
+    }
+
+    /// Insert a resource into the map with a specified key type.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        self.data.insert(TypeId::of::<K>(), box RefCell::new(val) as Box<Any + 'static>)
+    }
+
+    /// Borrow a resource.
+    ///
+    /// Panics if the resource is missing or mutably borrowed.
+    pub fn res<'a, K: Assoc<V>, V: 'static>(&'a self) -> Res<'a, K, V> {
+        match self.try_res::<K, V>() {
+            Ok(res) => res,
+            Err(err) => panic!("{}", err)
+        }
+    }
+
+    /// Borrow a resource mutably.
+    ///
+    /// Panics if the resource is missing or already borrowed.
+    pub fn res_mut<'a, K: Assoc<V>, V: 'static>(&'a self) -> ResMut<'a, K, V> {
+        match self.try_res_mut::<K, V>() {
+            Ok(res) => res,
+            Err(err) => panic!("{}", err)
+        }
+    }
+
+    /// Borrow a resource, failing if it is missing or mutably borrowed.
+    pub fn try_res<'a, K: Assoc<V>, V: 'static>(&'a self) -> Result<Res<'a, K, V>, BorrowError> {
+        let cell = try!(self.cell::<K, V>());
+        match cell.try_borrow() {
+            Some(inner) => Ok(Res { inner: inner }),
+            None => Err(Conflict(type_name::<K>()))
+        }
+    }
+
+    /// Borrow a resource mutably, failing if it is missing or already
+    /// borrowed.
+    pub fn try_res_mut<'a, K: Assoc<V>, V: 'static>(&'a self) -> Result<ResMut<'a, K, V>, BorrowError> {
+        let cell = try!(self.cell::<K, V>());
+        match cell.try_borrow_mut() {
+            Some(inner) => Ok(ResMut { inner: inner }),
+            None => Err(Conflict(type_name::<K>()))
+        }
+    }
+
+    /// Acquire several borrows at once, such as a
+    /// `(Res<A, uint>, ResMut<B, String>)`.
+    ///
+    /// Panics if a resource is missing or if the borrows conflict, either
+    /// with each other or with borrows held elsewhere.
+    pub fn fetch<'a, F: Fetch<'a>>(&'a self) -> F {
+        match self.try_fetch::<F>() {
+            Ok(fetched) => fetched,
+            Err(err) => panic!("{}", err)
+        }
+    }
+
+    /// Acquire several borrows at once, failing if a resource is missing or
+    /// if the borrows conflict.
+    ///
+    /// If any borrow fails, the ones already acquired are released.
+    pub fn try_fetch<'a, F: Fetch<'a>>(&'a self) -> Result<F, BorrowError> {
+        Fetch::fetch(None::<F>, self)
+    }
+
+    /// Check if a key has an associated resource stored in the map.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.data.contains_key(&TypeId::of::<K>())
+    }
+
+    /// Remove a resource from the map.
+    ///
+    /// Returns `true` if a resource was removed.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        self.data.remove(&TypeId::of::<K>())
+    }
+
+    /// Get the number of resources stored in the map.
+    pub fn len(&self) -> uint {
+        self.data.len()
+    }
+
+    /// Return true if the map contains no resources.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn cell<K: Assoc<V>, V: 'static>(&self) -> Result<&RefCell<V>, BorrowError> {
+        match self.data.find(&TypeId::of::<K>()) {
+            Some(val) => Ok(unsafe { val.downcast_ref_unchecked::<RefCell<V>>() }),
+            None => Err(Missing(type_name::<K>()))
+        }
+    }
+}
+
+impl<'a, K, V> Deref<V> for Res<'a, K, V> {
+    fn deref(&self) -> &V { &*self.inner }
+}
+
+impl<'a, K, V> Deref<V> for ResMut<'a, K, V> {
+    fn deref(&self) -> &V { &*self.inner }
+}
+
+impl<'a, K, V> DerefMut<V> for ResMut<'a, K, V> {
+    fn deref_mut(&mut self) -> &mut V { &mut *self.inner }
+}
+
+impl<'a, K: Assoc<V>, V: 'static> Fetch<'a> for Res<'a, K, V> {
+    fn fetch(_: Option<Res<'a, K, V>>, map: &'a ResourceMap) -> Result<Res<'a, K, V>, BorrowError> {
+        map.try_res::<K, V>()
+    }
+}
+
+impl<'a, K: Assoc<V>, V: 'static> Fetch<'a> for ResMut<'a, K, V> {
+    fn fetch(_: Option<ResMut<'a, K, V>>, map: &'a ResourceMap) -> Result<ResMut<'a, K, V>, BorrowError> {
+        map.try_res_mut::<K, V>()
+    }
+}
+
+macro_rules! tuple_fetch {
+    ($($borrow:ident),+) => (
+        impl<'a, $($borrow: Fetch<'a>),+> Fetch<'a> for ($($borrow,)+) {
+            fn fetch(_: Option<($($borrow,)+)>, map: &'a ResourceMap)
+                     -> Result<($($borrow,)+), BorrowError> {
+                Ok(($(try!(Fetch::fetch(None::<$borrow>, map)),)+))
+            }
+        }
+    )
+}
+
+tuple_fetch!(A)
+tuple_fetch!(A, B)
+tuple_fetch!(A, B, C)
+tuple_fetch!(A, B, C, D)
+tuple_fetch!(A, B, C, D, E)
+tuple_fetch!(A, B, C, D, E, F)
+tuple_fetch!(A, B, C, D, E, F, G)
+tuple_fetch!(A, B, C, D, E, F, G, H)
+
+impl fmt::Show for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Missing(key) => write!(f, "resource {} is missing", key),
+            Conflict(key) => write!(f, "resource {} is already borrowed", key)
+        }
+    }
+}
+
+impl Error for BorrowError {
+    fn description(&self) -> &str {
+        match *self {
+            Missing(_) => "resource is missing from the ResourceMap",
+            Conflict(_) => "resource is already borrowed"
+        }
+    }
+
+    fn detail(&self) -> Option<String> {
+        Some(self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ResourceMap, Res, ResMut, Missing, Conflict};
+    use Assoc;
+
+    struct Time;
+
+    struct Score;
+
+    impl Assoc<uint> for Time {}
+    impl Assoc<uint> for Score {}
+
+    #[test] fn test_disjoint_borrows() {
+        let mut map = ResourceMap::new();
+        map.insert::<Time, uint>(1);
+        map.insert::<Score, uint>(0);
+
+        let time = map.res::<Time, uint>();
+        let again = map.res::<Time, uint>();
+        let mut score = map.res_mut::<Score, uint>();
+        *score += *time + *again;
+        assert_eq!(*score, 2);
+    }
+
+    #[test] fn test_conflict() {
+        let mut map = ResourceMap::new();
+        map.insert::<Score, uint>(0);
+
+        let _score = map.res_mut::<Score, uint>();
+        match map.try_res::<Score, uint>() {
+            Err(Conflict(_)) => (),
+            _ => panic!("Borrowed a mutably borrowed resource.")
+        }
+        match map.try_res_mut::<Time, uint>() {
+            Err(Missing(_)) => (),
+            _ => panic!("Borrowed a missing resource.")
+        }
+    }
+
+    #[test] #[should_fail] fn test_conflict_panics() {
+        let mut map = ResourceMap::new();
+        map.insert::<Score, uint>(0);
+
+        let _score = map.res::<Score, uint>();
+        map.res_mut::<Score, uint>();
+    }
+
+    #[test] fn test_fetch() {
+        let mut map = ResourceMap::new();
+        map.insert::<Time, uint>(3);
+        map.insert::<Score, uint>(1);
+
+        {
+            let (time, mut score): (Res<Time, uint>, ResMut<Score, uint>) = map.fetch();
+            *score += *time;
+        }
+        assert_eq!(*map.res::<Score, uint>(), 4);
+
+        match map.try_fetch::<(Res<Score, uint>, ResMut<Score, uint>)>() {
+            Err(Conflict(_)) => (),
+            _ => panic!("Fetched aliasing borrows.")
+        }
+        assert!(map.try_res_mut::<Score, uint>().is_ok());
+    }
+}
@@ -0,0 +1,113 @@
+//! A TypeMap which may only be extended with an approved set of keys.
+
+use super::{TypeMap, Assoc, Entry};
+
+/// Marks a key type as allowed to be written to maps restricted by `C`.
+///
+/// To seal the set of allowed keys, define `C` in a private module of the
+/// crate handing out the map, so other crates cannot name it to add impls.
+pub trait Allowed<C>: 'static {}
+
+/// A TypeMap which can be read freely, but can only be written to with
+/// key types implementing `Allowed<C>`.
+///
+/// Only reads are exposed for the other keys. The underlying TypeMap is
+/// not reachable, since even its shared methods, like `mark_for_removal`,
+/// can change what the map holds.
+pub struct RestrictedTypeMap<C> {
+    map: TypeMap
+}
+
+impl<C> RestrictedTypeMap<C> {
+    /// Create a new, empty RestrictedTypeMap.
+    pub fn new() -> RestrictedTypeMap<C> {
+        RestrictedTypeMap::from_map(TypeMap::new())
+    }
+
+    /// Restrict an existing map, which may already contain any keys.
+    pub fn from_map(map: TypeMap) -> RestrictedTypeMap<C> {
+        RestrictedTypeMap { map: map }
+    }
+
+    /// Find a value in the map and get a reference to it.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        self.map.find::<K, V>()
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.map.contains::<K, V>()
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> uint {
+        self.map.len()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Insert a value into the map with an allowed key type.
+    pub fn insert<K: Assoc<V> + Allowed<C>, V: 'static>(&mut self, val: V) -> bool {
+        self.map.insert::<K, V>(val)
+    }
+
+    /// Find a value for an allowed key type and get a mutable reference to it.
+    pub fn find_mut<K: Assoc<V> + Allowed<C>, V: 'static>(&mut self) -> Option<&mut V> {
+        self.map.find_mut::<K, V>()
+    }
+
+    /// Remove a value with an allowed key type from the map.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: Assoc<V> + Allowed<C>, V: 'static>(&mut self) -> bool {
+        self.map.remove::<K, V>()
+    }
+
+    /// Get the entry for an allowed key type for in-place manipulation.
+    pub fn entry<'a, K: Assoc<V> + Allowed<C>, V: 'static>(&'a mut self) -> Entry<'a, K, V> {
+        self.map.entry::<K, V>()
+    }
+
+    /// Lift the restriction, getting back the underlying map.
+    pub fn into_inner(self) -> TypeMap {
+        self.map
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RestrictedTypeMap, Allowed};
+    use {TypeMap, Assoc};
+
+    struct Plugins;
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct Internal;
+
+    #[deriving(Show, PartialEq)]
+    struct Value;
+
+    impl Assoc<Value> for Key {}
+    impl Assoc<Value> for Internal {}
+    impl Allowed<Plugins> for Key {}
+
+    #[test] fn test_restricted() {
+        let mut inner = TypeMap::new();
+        inner.insert::<Internal, Value>(Value);
+
+        let mut map: RestrictedTypeMap<Plugins> = RestrictedTypeMap::from_map(inner);
+        map.insert::<Key, Value>(Value);
+        assert_eq!(*map.find::<Internal, Value>().unwrap(), Value);
+        assert_eq!(*map.find::<Key, Value>().unwrap(), Value);
+        assert!(map.contains::<Internal, Value>());
+        assert!(map.remove::<Key, Value>());
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.into_inner().len(), 1);
+    }
+}
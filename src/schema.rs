@@ -0,0 +1,101 @@
+//! Validation that a map contains a required set of keys.
+
+use std::error::Error;
+use std::fmt;
+use std::intrinsics::TypeId;
+
+use super::{TypeMap, Storage, type_name};
+
+/// A set of key types which must all have values in a map.
+///
+/// Implemented for tuples of up to twelve key types.
+pub trait Schema {
+    /// Push the names of the key types missing from the map.
+    fn missing<S: Storage>(_: Option<Self>, map: &TypeMap<S>, missing: &mut Vec<&'static str>);
+}
+
+/// The error returned when a map does not contain every key of a schema.
+#[deriving(Clone, PartialEq)]
+pub struct MissingKeys {
+    /// The names of the missing key types.
+    pub keys: Vec<&'static str>
+}
+
+impl fmt::Show for MissingKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "missing keys: "));
+        for (i, key) in self.keys.iter().enumerate() {
+            if i != 0 { try!(write!(f, ", ")); }
+            try!(write!(f, "{}", key));
+        }
+        Ok(())
+    }
+}
+
+impl Error for MissingKeys {
+    fn description(&self) -> &str {
+        "required keys are missing from the TypeMap"
+    }
+
+    fn detail(&self) -> Option<String> {
+        Some(self.to_string())
+    }
+}
+
+impl Schema for () {
+    fn missing<S: Storage>(_: Option<()>, _: &TypeMap<S>, _: &mut Vec<&'static str>) {}
+}
+
+macro_rules! tuple_schema {
+    ($($key:ident),+) => (
+        impl<$($key: 'static),+> Schema for ($($key,)+) {
+            fn missing<S: Storage>(_: Option<($($key,)+)>, map: &TypeMap<S>,
+                                   missing: &mut Vec<&'static str>) {
+                $(if !map.contains_id(&TypeId::of::<$key>()) {
+                    missing.push(type_name::<$key>());
+                })+
+            }
+        }
+    )
+}
+
+tuple_schema!(A)
+tuple_schema!(A, B)
+tuple_schema!(A, B, C)
+tuple_schema!(A, B, C, D)
+tuple_schema!(A, B, C, D, E)
+tuple_schema!(A, B, C, D, E, F)
+tuple_schema!(A, B, C, D, E, F, G)
+tuple_schema!(A, B, C, D, E, F, G, H)
+tuple_schema!(A, B, C, D, E, F, G, H, I)
+tuple_schema!(A, B, C, D, E, F, G, H, I, J)
+tuple_schema!(A, B, C, D, E, F, G, H, I, J, K)
+tuple_schema!(A, B, C, D, E, F, G, H, I, J, K, L)
+
+#[cfg(test)]
+mod test {
+    use TypeMap;
+    use Assoc;
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct OtherKey;
+
+    #[deriving(Show, PartialEq)]
+    struct Value;
+
+    impl Assoc<Value> for Key {}
+    impl Assoc<Value> for OtherKey {}
+
+    #[test] fn test_validate() {
+        let mut map = TypeMap::new();
+        map.insert::<Key, Value>(Value);
+        assert!(map.validate::<(Key,)>().is_ok());
+
+        let err = map.validate::<(Key, OtherKey)>().unwrap_err();
+        assert_eq!(err.keys.len(), 1);
+        assert!(err.keys[0].ends_with("OtherKey"));
+    }
+}
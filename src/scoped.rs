@@ -0,0 +1,131 @@
+//! Temporary overrides of entries which are undone when dropped.
+
+use std::any::Any;
+
+use super::{TypeMap, Assoc, Storage};
+
+/// A guard for a value inserted with `TypeMap::insert_scoped`.
+///
+/// When dropped, the key's previous value is restored, or the key is
+/// removed if it had none. The map can be used through the guard in the
+/// meantime; if the key is frozen through it, the scoped value stays.
+pub struct ScopedInsert<'a, K, V, S: 'a> {
+    map: &'a mut TypeMap<S>,
+    previous: Option<Box<Any + 'static>>
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Insert a value into the map until the returned guard is dropped.
+    ///
+    /// Dropping the guard restores the key's previous value, or removes the
+    /// key if it had none, even when unwinding from a panic. Panics, like
+    /// `insert`, if the key is frozen.
+    pub fn insert_scoped<'a, K: Assoc<V>, V: 'static>(&'a mut self, val: V) -> ScopedInsert<'a, K, V, S> {
+        let id = self.slot::<K>();
+        self.check_insert::<K>(&id);
+        let previous = self.take_id(id);
+        self.insert::<K, V>(val);
+        ScopedInsert { map: self, previous: previous }
+    }
+}
+
+impl<'a, K, V, S> Deref<TypeMap<S>> for ScopedInsert<'a, K, V, S> {
+    fn deref(&self) -> &TypeMap<S> {
+        &*self.map
+    }
+}
+
+impl<'a, K, V, S> DerefMut<TypeMap<S>> for ScopedInsert<'a, K, V, S> {
+    fn deref_mut(&mut self) -> &mut TypeMap<S> {
+        &mut *self.map
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, K: Assoc<V>, V: 'static, S: Storage> Drop for ScopedInsert<'a, K, V, S> {
+    fn drop(&mut self) {
+        match self.previous.take() {
+            Some(previous) => {
+                let id = self.map.slot::<K>();
+                match self.map.store_id(id, previous) {
+                    Ok(_) => self.map.expected.record::<V>(id),
+                    // The key was frozen through the guard, so the scoped
+                    // value stays.
+                    Err(_) if self.map.is_frozen_id(&id) => (),
+                    // The scoped value was removed through the guard and
+                    // the map filled up; the previous value was in the map
+                    // before, so it goes back anyway.
+                    Err(previous) => {
+                        self.map.data.swap(id, previous);
+                        self.map.touch(id);
+                        self.map.expected.record::<V>(id);
+                    }
+                }
+                self.map.debug_check();
+            },
+            None => { self.map.remove::<K, V>(); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {TypeMap, Assoc};
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct Other;
+
+    impl Assoc<uint> for Key {}
+    impl Assoc<uint> for Other {}
+
+    #[test] fn test_restores_previous() {
+        let mut map = TypeMap::new();
+        map.insert::<Key, uint>(1);
+        {
+            let mut scoped = map.insert_scoped::<Key, uint>(2);
+            assert_eq!(*scoped.find::<Key, uint>().unwrap(), 2);
+            *scoped.find_mut::<Key, uint>().unwrap() = 3;
+        }
+        assert_eq!(*map.find::<Key, uint>().unwrap(), 1);
+    }
+
+    #[test] #[should_fail] fn test_frozen() {
+        let mut map = TypeMap::new();
+        map.insert::<Key, uint>(1);
+        map.freeze::<Key, uint>();
+        map.insert_scoped::<Key, uint>(2);
+    }
+
+    #[test] fn test_frozen_through_guard() {
+        let mut map = TypeMap::new();
+        map.insert::<Key, uint>(1);
+        {
+            let mut scoped = map.insert_scoped::<Key, uint>(2);
+            scoped.freeze::<Key, uint>();
+        }
+        assert_eq!(*map.find::<Key, uint>().unwrap(), 2);
+    }
+
+    #[test] fn test_restores_into_full_map() {
+        let mut map = TypeMap::with_max_entries(1);
+        map.insert::<Key, uint>(1);
+        {
+            let mut scoped = map.insert_scoped::<Key, uint>(2);
+            scoped.remove::<Key, uint>();
+            scoped.insert::<Other, uint>(3);
+        }
+        assert_eq!(*map.find::<Key, uint>().unwrap(), 1);
+        assert_eq!(*map.find::<Other, uint>().unwrap(), 3);
+    }
+
+    #[test] fn test_removes_new() {
+        let mut map = TypeMap::new();
+        {
+            let _scoped = map.insert_scoped::<Key, uint>(2);
+        }
+        assert!(!map.contains::<Key, uint>());
+    }
+}
@@ -0,0 +1,86 @@
+//! A map shared between contexts which preempt each other, like an
+//! interrupt handler and the main loop of firmware, guarded by a lock
+//! supplied by the caller.
+//!
+//! This crate has no lock of its own for such targets: the caller
+//! implements `Lock` with whatever its platform provides, such as a
+//! critical section which disables interrupts. Every access to the map
+//! runs inside the lock, so neither context sees the other's changes half
+//! made. An access made from inside another, through the same
+//! SharedTypeMap, panics instead of aliasing the map.
+//!
+//! The map itself still allocates and builds on `std`; only the lock is
+//! left to the caller.
+
+use std::cell::RefCell;
+
+use super::TypeMap;
+
+/// A lock guarding a SharedTypeMap.
+pub trait Lock {
+    /// Run `f` while holding the lock, so no other context can run it at
+    /// the same time.
+    fn with<R>(&self, f: || -> R) -> R;
+}
+
+/// A TypeMap which is only accessed while holding a lock.
+pub struct SharedTypeMap<L> {
+    lock: L,
+    map: RefCell<TypeMap>
+}
+
+impl<L: Lock> SharedTypeMap<L> {
+    /// Create a new, empty SharedTypeMap guarded by a lock.
+    pub fn new(lock: L) -> SharedTypeMap<L> {
+        SharedTypeMap { lock: lock, map: RefCell::new(TypeMap::new()) }
+    }
+
+    /// Run `f` on the map while holding the lock, returning its result.
+    ///
+    /// Panics if called from inside `f`.
+    pub fn with<R>(&self, f: |&mut TypeMap| -> R) -> R {
+        let map = &self.map;
+        self.lock.with(|| f(&mut *map.borrow_mut()))
+    }
+
+    /// Take the map back out of its lock.
+    pub fn into_inner(self) -> TypeMap {
+        self.map.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use super::{Lock, SharedTypeMap};
+    use Assoc;
+
+    struct Counted {
+        held: Cell<uint>
+    }
+
+    impl Lock for Counted {
+        fn with<R>(&self, f: || -> R) -> R {
+            self.held.set(self.held.get() + 1);
+            f()
+        }
+    }
+
+    struct Ticks;
+
+    impl Assoc<uint> for Ticks {}
+
+    #[test] fn test_shared() {
+        let shared = SharedTypeMap::new(Counted { held: Cell::new(0) });
+        shared.with(|map| map.insert::<Ticks, uint>(1));
+        shared.with(|map| *map.find_mut::<Ticks, uint>().unwrap() += 1);
+        assert_eq!(shared.with(|map| *map.find::<Ticks, uint>().unwrap()), 2);
+        assert_eq!(shared.lock.held.get(), 3);
+        assert_eq!(shared.into_inner().len(), 1);
+    }
+
+    #[test] #[should_fail] fn test_reentrant() {
+        let shared = SharedTypeMap::new(Counted { held: Cell::new(0) });
+        shared.with(|_| shared.with(|map| map.insert::<Ticks, uint>(1)));
+    }
+}
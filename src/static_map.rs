@@ -0,0 +1,140 @@
+//! Maps over a fixed set of keys known at compile time.
+//!
+//! Static maps are defined with the `static_typemap!` macro.
+
+use std::fmt;
+
+use super::{Assoc, MissingKeys};
+
+/// The error returned when a static map cannot be frozen from a TypeMap.
+#[deriving(Clone, PartialEq)]
+pub enum FreezeError {
+    /// Some keys of the static map have no value in the TypeMap.
+    Missing(MissingKeys),
+    /// The key with this name is frozen in the TypeMap, so its value
+    /// cannot be moved out.
+    Frozen(&'static str)
+}
+
+impl fmt::Show for FreezeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Missing(ref missing) => missing.fmt(f),
+            Frozen(key) => write!(f, "key {} is frozen", key)
+        }
+    }
+}
+
+/// Access to the field storing the value of a key in a static map.
+///
+/// Implemented by `static_typemap!` for every key of the map.
+pub trait Field<K: Assoc<V>, V: 'static> {
+    /// Get a reference to the field storing the key's value.
+    fn field(&self) -> &Option<V>;
+
+    /// Get a mutable reference to the field storing the key's value.
+    fn field_mut(&mut self) -> &mut Option<V>;
+}
+
+/// A map over a fixed set of keys, each stored in its own field.
+///
+/// Only keys which are part of the map can be used, which is checked at
+/// compile time.
+pub trait StaticTypeMap {
+    /// Insert a value into the map with a specified key type.
+    #[inline]
+    fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool where Self: Field<K, V> {
+        let new = self.field().is_none();
+        *self.field_mut() = Some(val);
+        new
+    }
+
+    /// Find a value in the map and get a reference to it.
+    #[inline]
+    fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> where Self: Field<K, V> {
+        self.field().as_ref()
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    #[inline]
+    fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> where Self: Field<K, V> {
+        self.field_mut().as_mut()
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    #[inline]
+    fn contains<K: Assoc<V>, V: 'static>(&self) -> bool where Self: Field<K, V> {
+        self.field().is_some()
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed.
+    #[inline]
+    fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool where Self: Field<K, V> {
+        self.field_mut().take().is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StaticTypeMap, Missing, Frozen};
+    use {TypeMap, Assoc};
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct Value;
+
+    #[deriving(Show, PartialEq)]
+    struct Count;
+
+    impl Assoc<Value> for Key {}
+    impl Assoc<uint> for Count {}
+
+    static_typemap! {
+        Context {
+            key: Key => Value,
+            count: Count => uint
+        }
+    }
+
+    #[test] fn test_static_map() {
+        let mut map = Context::new();
+        assert!(!map.contains::<Key, Value>());
+        assert!(map.insert::<Key, Value>(Value));
+        map.insert::<Count, uint>(1);
+        *map.find_mut::<Count, uint>().unwrap() += 1;
+
+        assert_eq!(*map.find::<Key, Value>().unwrap(), Value);
+        assert_eq!(*map.find::<Count, uint>().unwrap(), 2);
+        assert!(map.remove::<Key, Value>());
+        assert!(map.find::<Key, Value>().is_none());
+    }
+
+    #[test] fn test_freeze_and_spill() {
+        let mut dynamic = TypeMap::new();
+        dynamic.insert::<Key, Value>(Value);
+        assert!(match Context::freeze(&mut dynamic) { Err(Missing(_)) => true, _ => false });
+        assert!(dynamic.contains::<Key, Value>());
+
+        dynamic.insert::<Count, uint>(3);
+        let map = Context::freeze(&mut dynamic).unwrap();
+        assert!(dynamic.is_empty());
+        assert_eq!(*map.find::<Count, uint>().unwrap(), 3);
+
+        map.spill(&mut dynamic);
+        assert_eq!(dynamic.len(), 2);
+        assert_eq!(*dynamic.find::<Key, Value>().unwrap(), Value);
+    }
+
+    #[test] fn test_freeze_frozen_key() {
+        let mut dynamic = TypeMap::new();
+        dynamic.insert::<Key, Value>(Value);
+        dynamic.insert::<Count, uint>(3);
+        dynamic.freeze::<Count, uint>();
+        assert!(match Context::freeze(&mut dynamic) { Err(Frozen("Count")) => true, _ => false });
+        assert_eq!(dynamic.len(), 2);
+    }
+}
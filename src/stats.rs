@@ -0,0 +1,92 @@
+//! Opt-in per-key access statistics.
+
+use std::collections::HashMap;
+use std::intrinsics::TypeId;
+
+/// Access statistics recorded for a single key type.
+#[deriving(Show, Clone, PartialEq)]
+pub struct KeyStats {
+    /// The name of the key type.
+    pub name: &'static str,
+    /// The number of lookups which found a value.
+    pub hits: uint,
+    /// The number of lookups which found nothing.
+    pub misses: uint,
+    /// The number of values inserted.
+    pub inserts: uint,
+    /// The number of values removed.
+    pub removals: uint,
+    /// The number of values removed, replaced or cleared without having
+    /// been read.
+    pub unused: uint,
+    /// Whether the value stored most recently has not been read yet.
+    pub unread: bool
+}
+
+/// A key type with values which were inserted but never read.
+#[deriving(Show, Clone, PartialEq)]
+pub struct UnusedKey {
+    /// The name of the key type.
+    pub name: &'static str,
+    /// The number of values removed, replaced or cleared without having
+    /// been read.
+    pub discarded: uint,
+    /// Whether the value stored now has not been read.
+    pub stored: bool
+}
+
+/// Statistics about the table storing the entries of a map.
+#[deriving(Show, Clone, PartialEq)]
+pub struct TableStats {
+    /// The number of values stored.
+    pub len: uint,
+    /// The number of values the table has room for without reallocating.
+    pub capacity: uint,
+    /// The ratio of stored values to capacity.
+    pub load_factor: f64,
+    /// The number of times an insert made the table reallocate.
+    pub resizes: uint,
+    /// The distribution of probe lengths: element `i` is the number of
+    /// stored keys a lookup finds after `i + 1` probes.
+    ///
+    /// `None` if the storage cannot tell. The standard HashMap does not
+    /// expose where it placed its entries relative to their hashes, so
+    /// maps using it never report probe lengths.
+    pub probe_lengths: Option<Vec<uint>>
+}
+
+/// A report of the access statistics of every key type used with a map.
+pub type StatsReport = HashMap<TypeId, KeyStats>;
+
+impl KeyStats {
+    /// Create empty statistics for the key type with the given name.
+    pub fn new(name: &'static str) -> KeyStats {
+        KeyStats { name: name, hits: 0, misses: 0, inserts: 0, removals: 0, unused: 0, unread: false }
+    }
+
+    /// Record the result of a lookup.
+    pub fn lookup(&mut self, found: bool) {
+        if found { self.hits += 1; self.unread = false } else { self.misses += 1 }
+    }
+
+    /// Record an insert, counting the replaced value as unused if it
+    /// was never read.
+    pub fn insert(&mut self) {
+        self.inserts += 1;
+        self.discard();
+        self.unread = true;
+    }
+
+    /// Record a removal.
+    pub fn remove(&mut self) {
+        self.removals += 1;
+        self.discard();
+    }
+
+    /// Record that the stored value, if any, was dropped, counting it as
+    /// unused if it was never read.
+    pub fn discard(&mut self) {
+        if self.unread { self.unused += 1 }
+        self.unread = false;
+    }
+}
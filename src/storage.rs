@@ -0,0 +1,473 @@
+//! Representations a TypeMap can use to store its entries.
+//!
+//! A TypeMap is generic over its `Storage`, defaulting to a HashMap. Other
+//! representations suit other entry counts and ordering needs:
+//!
+//! * `BTreeStorage` orders entries by key TypeId, the same way on every run.
+//! * `VecStorage` searches a vector linearly, which is fastest for a
+//!   handful of entries.
+//! * `ArrayStorage` keeps up to eight entries in a fixed array, never
+//!   allocating a table.
+//! * `NoopStorage` stores nothing, for benchmarking against a map whose
+//!   storage does no work. To compile a map out entirely, use a
+//!   `NoopTypeMap` instead.
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::intrinsics::TypeId;
+use std::{mem, vec};
+
+/// The number of entries an ArrayStorage can hold.
+pub const ARRAY_CAPACITY: uint = 8;
+
+/// A representation of the entries of a TypeMap.
+pub trait Storage {
+    /// Get a reference to the value stored for a key.
+    fn find(&self, id: &TypeId) -> Option<&Box<Any + 'static>>;
+
+    /// Get a mutable reference to the value stored for a key.
+    fn find_mut(&mut self, id: &TypeId) -> Option<&mut Box<Any + 'static>>;
+
+    /// Store a value for a key, returning the previous value.
+    fn swap(&mut self, id: TypeId, val: Box<Any + 'static>) -> Option<Box<Any + 'static>>;
+
+    /// Remove the value stored for a key, returning it.
+    fn pop(&mut self, id: &TypeId) -> Option<Box<Any + 'static>>;
+
+    /// Get the number of values stored.
+    fn len(&self) -> uint;
+
+    /// Remove all stored values.
+    fn clear(&mut self);
+
+    /// Get the stored entries, in the order the storage keeps them.
+    fn entries<'a>(&'a self) -> vec::MoveItems<(TypeId, &'a (Any + 'static))>;
+
+    /// Check if a value is stored for a key.
+    fn contains(&self, id: &TypeId) -> bool {
+        self.find(id).is_some()
+    }
+
+    /// Get the number of values the storage can hold, if it is limited.
+    fn capacity(&self) -> Option<uint> { None }
+
+    /// Get the number of values the storage has room for without
+    /// reallocating.
+    fn allocated(&self) -> uint { self.len() }
+
+    /// Get the number of probes a lookup of each stored key takes, if the
+    /// storage can tell.
+    fn probe_lengths(&self) -> Option<Vec<uint>> { None }
+
+    /// Panic if the storage's internal bookkeeping is inconsistent.
+    fn check_invariants(&self) {}
+}
+
+impl Storage for HashMap<TypeId, Box<Any + 'static>> {
+    fn find(&self, id: &TypeId) -> Option<&Box<Any + 'static>> { self.find(id) }
+
+    fn find_mut(&mut self, id: &TypeId) -> Option<&mut Box<Any + 'static>> { self.find_mut(id) }
+
+    fn swap(&mut self, id: TypeId, val: Box<Any + 'static>) -> Option<Box<Any + 'static>> {
+        self.swap(id, val)
+    }
+
+    fn pop(&mut self, id: &TypeId) -> Option<Box<Any + 'static>> { self.pop(id) }
+
+    fn len(&self) -> uint { self.len() }
+
+    fn clear(&mut self) { self.clear() }
+
+    fn entries<'a>(&'a self) -> vec::MoveItems<(TypeId, &'a (Any + 'static))> {
+        self.iter().map(|(id, val)| (*id, &**val)).collect::<Vec<_>>().into_iter()
+    }
+
+    fn contains(&self, id: &TypeId) -> bool { self.contains_key(id) }
+
+    fn allocated(&self) -> uint { self.capacity() }
+}
+
+/// Storage ordering entries by key TypeId, using a BTreeMap.
+///
+/// The order is the same on every run of a build, but not across builds;
+/// `BTreeTypeMap` orders by key type name instead.
+pub struct BTreeStorage {
+    data: BTreeMap<u64, (TypeId, Box<Any + 'static>)>
+}
+
+impl BTreeStorage {
+    /// Create a new, empty BTreeStorage.
+    pub fn new() -> BTreeStorage {
+        BTreeStorage { data: BTreeMap::new() }
+    }
+}
+
+impl Storage for BTreeStorage {
+    fn find(&self, id: &TypeId) -> Option<&Box<Any + 'static>> {
+        self.data.find(&id.hash()).map(|&(_, ref val)| val)
+    }
+
+    fn find_mut(&mut self, id: &TypeId) -> Option<&mut Box<Any + 'static>> {
+        match self.data.find_mut(&id.hash()) {
+            Some(entry) => {
+                let (_, ref mut val) = *entry;
+                Some(val)
+            },
+            None => None
+        }
+    }
+
+    fn swap(&mut self, id: TypeId, val: Box<Any + 'static>) -> Option<Box<Any + 'static>> {
+        self.data.swap(id.hash(), (id, val)).map(|(_, val)| val)
+    }
+
+    fn pop(&mut self, id: &TypeId) -> Option<Box<Any + 'static>> {
+        self.data.pop(&id.hash()).map(|(_, val)| val)
+    }
+
+    fn len(&self) -> uint { self.data.len() }
+
+    fn clear(&mut self) { self.data.clear() }
+
+    fn entries<'a>(&'a self) -> vec::MoveItems<(TypeId, &'a (Any + 'static))> {
+        self.data.values().map(|&(id, ref val)| (id, &**val)).collect::<Vec<_>>().into_iter()
+    }
+}
+
+/// Storage searching a vector of entries linearly.
+pub struct VecStorage {
+    entries: Vec<(TypeId, Box<Any + 'static>)>
+}
+
+impl VecStorage {
+    /// Create a new, empty VecStorage.
+    pub fn new() -> VecStorage {
+        VecStorage { entries: Vec::new() }
+    }
+
+    fn position(&self, id: &TypeId) -> Option<uint> {
+        self.entries.iter().position(|&(ref other, _)| other == id)
+    }
+}
+
+impl Storage for VecStorage {
+    fn find(&self, id: &TypeId) -> Option<&Box<Any + 'static>> {
+        self.entries.iter().find(|&&(ref other, _)| other == id).map(|&(_, ref val)| val)
+    }
+
+    fn find_mut(&mut self, id: &TypeId) -> Option<&mut Box<Any + 'static>> {
+        match self.position(id) {
+            Some(index) => {
+                let (_, ref mut val) = self.entries[index];
+                Some(val)
+            },
+            None => None
+        }
+    }
+
+    fn swap(&mut self, id: TypeId, val: Box<Any + 'static>) -> Option<Box<Any + 'static>> {
+        match self.position(&id) {
+            Some(index) => {
+                let (_, ref mut old) = self.entries[index];
+                Some(mem::replace(old, val))
+            },
+            None => {
+                self.entries.push((id, val));
+                None
+            }
+        }
+    }
+
+    fn pop(&mut self, id: &TypeId) -> Option<Box<Any + 'static>> {
+        match self.position(id) {
+            Some(index) => self.entries.swap_remove(index).map(|(_, val)| val),
+            None => None
+        }
+    }
+
+    fn len(&self) -> uint { self.entries.len() }
+
+    fn clear(&mut self) { self.entries.clear() }
+
+    fn entries<'a>(&'a self) -> vec::MoveItems<(TypeId, &'a (Any + 'static))> {
+        self.entries.iter().map(|&(id, ref val)| (id, &**val)).collect::<Vec<_>>().into_iter()
+    }
+
+    fn allocated(&self) -> uint { self.entries.capacity() }
+
+    fn probe_lengths(&self) -> Option<Vec<uint>> {
+        Some(range(1, self.entries.len() + 1).collect())
+    }
+
+    fn check_invariants(&self) {
+        for (index, &(ref id, _)) in self.entries.iter().enumerate() {
+            if self.position(id) != Some(index) {
+                panic!("VecStorage invariant violated: key {} is stored twice", id);
+            }
+        }
+    }
+}
+
+/// Storage keeping up to `ARRAY_CAPACITY` entries in a fixed array.
+///
+/// Storing a value for a new key in a full ArrayStorage panics.
+pub struct ArrayStorage {
+    slots: [Option<(TypeId, Box<Any + 'static>)>, ..ARRAY_CAPACITY],
+    len: uint
+}
+
+impl ArrayStorage {
+    /// Create a new, empty ArrayStorage.
+    pub fn new() -> ArrayStorage {
+        ArrayStorage {
+            slots: [None, None, None, None, None, None, None, None],
+            len: 0
+        }
+    }
+
+    fn position(&self, id: &TypeId) -> Option<uint> {
+        self.slots.iter().position(|slot| match *slot {
+            Some((ref other, _)) => other == id,
+            None => false
+        })
+    }
+}
+
+impl Storage for ArrayStorage {
+    fn find(&self, id: &TypeId) -> Option<&Box<Any + 'static>> {
+        match self.position(id) {
+            Some(index) => self.slots[index].as_ref().map(|&(_, ref val)| val),
+            None => None
+        }
+    }
+
+    fn find_mut(&mut self, id: &TypeId) -> Option<&mut Box<Any + 'static>> {
+        match self.position(id) {
+            Some(index) => match self.slots[index] {
+                Some((_, ref mut val)) => Some(val),
+                None => None
+            },
+            None => None
+        }
+    }
+
+    fn swap(&mut self, id: TypeId, val: Box<Any + 'static>) -> Option<Box<Any + 'static>> {
+        let index = match self.position(&id) {
+            Some(index) => index,
+            None => match self.slots.iter().position(|slot| slot.is_none()) {
+                Some(index) => { self.len += 1; index },
+                None => panic!("ArrayStorage is full ({} entries)", ARRAY_CAPACITY)
+            }
+        };
+
+        mem::replace(&mut self.slots[index], Some((id, val))).map(|(_, val)| val)
+    }
+
+    fn pop(&mut self, id: &TypeId) -> Option<Box<Any + 'static>> {
+        match self.position(id) {
+            Some(index) => {
+                self.len -= 1;
+                self.slots[index].take().map(|(_, val)| val)
+            },
+            None => None
+        }
+    }
+
+    fn len(&self) -> uint { self.len }
+
+    fn capacity(&self) -> Option<uint> { Some(ARRAY_CAPACITY) }
+
+    fn allocated(&self) -> uint { ARRAY_CAPACITY }
+
+    fn probe_lengths(&self) -> Option<Vec<uint>> {
+        Some(self.slots.iter().enumerate().filter(|&(_, slot)| slot.is_some()).map(|(index, _)| index + 1).collect())
+    }
+
+    fn clear(&mut self) {
+        for slot in self.slots.iter_mut() { *slot = None }
+        self.len = 0;
+    }
+
+    fn entries<'a>(&'a self) -> vec::MoveItems<(TypeId, &'a (Any + 'static))> {
+        let mut entries = Vec::with_capacity(self.len);
+        for slot in self.slots.iter() {
+            match *slot {
+                Some((id, ref val)) => entries.push((id, &**val)),
+                None => ()
+            }
+        }
+        entries.into_iter()
+    }
+
+    fn check_invariants(&self) {
+        let mut occupied = 0;
+        for (index, slot) in self.slots.iter().enumerate() {
+            match *slot {
+                Some((ref id, _)) => {
+                    occupied += 1;
+                    if self.position(id) != Some(index) {
+                        panic!("ArrayStorage invariant violated: key {} is stored twice", id);
+                    }
+                },
+                None => ()
+            }
+        }
+        if occupied != self.len {
+            panic!("ArrayStorage invariant violated: {} entries counted, {} stored", self.len, occupied);
+        }
+    }
+}
+
+/// Storage which drops every value stored in it, so the map is always
+/// empty.
+///
+/// Every lookup misses and every insert is of a new value. The storage
+/// itself does nothing, but the map still boxes inserted values and keeps
+/// its own bookkeeping, such as dirty flags and statistics.
+pub struct NoopStorage;
+
+impl Storage for NoopStorage {
+    #[inline]
+    fn find(&self, _: &TypeId) -> Option<&Box<Any + 'static>> { None }
+
+    #[inline]
+    fn find_mut(&mut self, _: &TypeId) -> Option<&mut Box<Any + 'static>> { None }
+
+    #[inline]
+    fn swap(&mut self, _: TypeId, _: Box<Any + 'static>) -> Option<Box<Any + 'static>> { None }
+
+    #[inline]
+    fn pop(&mut self, _: &TypeId) -> Option<Box<Any + 'static>> { None }
+
+    #[inline]
+    fn len(&self) -> uint { 0 }
+
+    #[inline]
+    fn clear(&mut self) {}
+
+    #[inline]
+    fn entries<'a>(&'a self) -> vec::MoveItems<(TypeId, &'a (Any + 'static))> {
+        Vec::new().into_iter()
+    }
+
+    #[inline]
+    fn contains(&self, _: &TypeId) -> bool { false }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::Any;
+    use std::intrinsics::TypeId;
+    use super::{Storage, BTreeStorage, VecStorage, ArrayStorage, NoopStorage, ARRAY_CAPACITY};
+    use {TypeMap, Assoc, CapacityExceeded, KeyRegistry, StableKey};
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct OtherKey;
+
+    #[deriving(Show, PartialEq)]
+    struct Value;
+
+    // One key for each type parameter, for filling storages.
+    struct Slot<T>;
+
+    impl Assoc<Value> for Key {}
+    impl Assoc<uint> for OtherKey {}
+    impl<T: 'static> Assoc<uint> for Slot<T> {}
+
+    impl StableKey<uint> for OtherKey {
+        fn stable_id(_: Option<OtherKey>) -> &'static str { "test.other" }
+    }
+
+    fn exercise<S: Storage>(mut map: TypeMap<S>) {
+        assert!(map.insert::<Key, Value>(Value));
+        assert!(map.insert::<OtherKey, uint>(1));
+        assert!(!map.insert::<OtherKey, uint>(2));
+        assert_eq!(map.len(), 2);
+        assert_eq!(*map.find::<Key, Value>().unwrap(), Value);
+        *map.find_mut::<OtherKey, uint>().unwrap() += 1;
+        assert_eq!(*map.find::<OtherKey, uint>().unwrap(), 3);
+
+        let ids: Vec<TypeId> = map.iter().map(|(id, _)| id).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&TypeId::of::<Key>()) && ids.contains(&TypeId::of::<OtherKey>()));
+
+        assert!(map.remove::<Key, Value>());
+        assert!(!map.contains::<Key, Value>());
+        map.check_invariants();
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test] fn test_storages() {
+        exercise(TypeMap::new());
+        exercise(TypeMap::with_storage(BTreeStorage::new()));
+        exercise(TypeMap::with_storage(VecStorage::new()));
+        exercise(TypeMap::with_storage(ArrayStorage::new()));
+    }
+
+    #[test] fn test_noop_storage() {
+        let mut map = TypeMap::with_storage(NoopStorage);
+        assert!(map.insert::<Key, Value>(Value));
+        assert!(map.insert::<Key, Value>(Value));
+        assert!(map.find::<Key, Value>().is_none());
+        assert!(!map.remove::<Key, Value>());
+        assert!(map.is_empty());
+        map.check_invariants();
+    }
+
+    #[test] fn test_array_storage_capacity() {
+        let mut map = TypeMap::with_storage(ArrayStorage::new());
+        assert!(map.try_insert::<Key, Value>(Value).is_ok());
+        map.insert::<Slot<u8>, uint>(1);
+        map.insert::<Slot<u16>, uint>(2);
+        map.insert::<Slot<u32>, uint>(3);
+        map.insert::<Slot<u64>, uint>(4);
+        map.insert::<Slot<i8>, uint>(5);
+        map.insert::<Slot<i16>, uint>(6);
+        map.insert::<Slot<i32>, uint>(7);
+        assert_eq!(map.len(), ARRAY_CAPACITY);
+        assert_eq!(map.try_insert::<OtherKey, uint>(1), Err(CapacityExceeded(ARRAY_CAPACITY)));
+        map.check_invariants();
+    }
+
+    #[test] fn test_array_storage_refuses_when_full() {
+        let mut registry = KeyRegistry::new();
+        registry.register_replicated::<OtherKey, uint>();
+        let mut source = TypeMap::new();
+        source.insert::<OtherKey, uint>(1);
+        let frame = source.take_changes(&registry);
+
+        let mut map = TypeMap::with_storage(ArrayStorage::new());
+        map.insert::<Key, Value>(Value);
+        map.insert::<Slot<u8>, uint>(1);
+        map.insert::<Slot<u16>, uint>(2);
+        map.insert::<Slot<u32>, uint>(3);
+        map.insert::<Slot<u64>, uint>(4);
+        map.insert::<Slot<i8>, uint>(5);
+        map.insert::<Slot<i16>, uint>(6);
+        map.insert::<Slot<i32>, uint>(7);
+        assert_eq!(map.apply_changes(&registry, frame.as_slice()), Ok(0));
+        assert!(!map.contains::<OtherKey, uint>());
+        map.check_invariants();
+    }
+
+    #[test] #[should_fail] fn test_array_storage_bad_len() {
+        let mut storage = ArrayStorage::new();
+        storage.swap(TypeId::of::<u8>(), box () as Box<Any + 'static>);
+        storage.len = 2;
+        storage.check_invariants();
+    }
+
+    #[test] #[should_fail] fn test_array_storage_full() {
+        let ids = [TypeId::of::<u8>(), TypeId::of::<u16>(), TypeId::of::<u32>(),
+                   TypeId::of::<u64>(), TypeId::of::<i8>(), TypeId::of::<i16>(),
+                   TypeId::of::<i32>(), TypeId::of::<i64>(), TypeId::of::<f32>()];
+        let mut storage = ArrayStorage::new();
+        for &id in ids.iter() {
+            storage.swap(id, box () as Box<Any + 'static>);
+        }
+    }
+}
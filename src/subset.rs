@@ -0,0 +1,144 @@
+//! Groups of entries, which can be looked up together or copied into a
+//! new map.
+
+use super::{TypeMap, Assoc, Storage};
+
+/// A group of keys with cloneable values, written as a tuple of
+/// `(Key, Value)` pairs such as `((SessionKey, Session), (UserKey, User))`.
+///
+/// Implemented for tuples of up to twelve pairs.
+pub trait KeyGroup {
+    /// Clone the values of the group's keys from one map into another.
+    fn clone_into<S: Storage>(_: Option<Self>, from: &TypeMap<S>, into: &mut TypeMap);
+}
+
+/// A group of keys whose values can be looked up in one call, written as
+/// a tuple of `(Key, Value)` pairs, giving a tuple `Out` of optional
+/// references to the values.
+///
+/// Implemented for tuples of up to twelve pairs.
+pub trait GetAll<'a, Out> {
+    /// Look up the values of the group's keys.
+    fn get_all<S: Storage>(_: Option<Self>, map: &'a TypeMap<S>) -> Out;
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Look up the values of a group of keys at once.
+    ///
+    /// ```ignore
+    /// let (user, session) = map.get_all::<((UserKey, User), (SessionKey, Session)), _>();
+    /// ```
+    pub fn get_all<'a, G: GetAll<'a, O>, O>(&'a self) -> O {
+        GetAll::get_all(None::<G>, self)
+    }
+
+    /// Create a new map holding clones of the values of a group of keys,
+    /// such as the parts of a request's context a background task needs.
+    ///
+    /// Keys of the group without a value are left out.
+    pub fn clone_subset<G: KeyGroup>(&self) -> TypeMap {
+        let mut subset = TypeMap::new();
+        KeyGroup::clone_into(None::<G>, self, &mut subset);
+        subset
+    }
+}
+
+macro_rules! tuple_group {
+    ($($key:ident => $value:ident),+) => (
+        impl<$($key: Assoc<$value>, $value: Clone + 'static),+> KeyGroup for ($(($key, $value),)+) {
+            fn clone_into<S: Storage>(_: Option<($(($key, $value),)+)>, from: &TypeMap<S>,
+                                      into: &mut TypeMap) {
+                $(match from.find::<$key, $value>() {
+                    Some(val) => { into.insert::<$key, $value>(val.clone()); },
+                    None => ()
+                })+
+            }
+        }
+    )
+}
+
+macro_rules! tuple_get_all {
+    ($($key:ident => $value:ident),+) => (
+        impl<'a, $($key: Assoc<$value>, $value: 'static),+> GetAll<'a, ($(Option<&'a $value>,)+)>
+                for ($(($key, $value),)+) {
+            fn get_all<S: Storage>(_: Option<($(($key, $value),)+)>, map: &'a TypeMap<S>)
+                                   -> ($(Option<&'a $value>,)+) {
+                ($(map.find::<$key, $value>(),)+)
+            }
+        }
+    )
+}
+
+tuple_group!(K1 => V1)
+tuple_group!(K1 => V1, K2 => V2)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3, K4 => V4)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+             K8 => V8)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+             K8 => V8, K9 => V9)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+             K8 => V8, K9 => V9, K10 => V10)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+             K8 => V8, K9 => V9, K10 => V10, K11 => V11)
+tuple_group!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+             K8 => V8, K9 => V9, K10 => V10, K11 => V11, K12 => V12)
+
+tuple_get_all!(K1 => V1)
+tuple_get_all!(K1 => V1, K2 => V2)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3, K4 => V4)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+               K8 => V8)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+               K8 => V8, K9 => V9)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+               K8 => V8, K9 => V9, K10 => V10)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+               K8 => V8, K9 => V9, K10 => V10, K11 => V11)
+tuple_get_all!(K1 => V1, K2 => V2, K3 => V3, K4 => V4, K5 => V5, K6 => V6, K7 => V7,
+               K8 => V8, K9 => V9, K10 => V10, K11 => V11, K12 => V12)
+
+#[cfg(test)]
+mod test {
+    use {TypeMap, Assoc};
+
+    struct Session;
+
+    struct User;
+
+    struct Body;
+
+    impl Assoc<uint> for Session {}
+    impl Assoc<String> for User {}
+    impl Assoc<Vec<u8>> for Body {}
+
+    #[test] fn test_clone_subset() {
+        let mut map = TypeMap::new();
+        map.insert::<Session, uint>(7);
+        map.insert::<Body, Vec<u8>>(vec![1, 2, 3]);
+
+        let subset = map.clone_subset::<((Session, uint), (User, String))>();
+        assert_eq!(subset.len(), 1);
+        assert_eq!(*subset.find::<Session, uint>().unwrap(), 7);
+        assert!(!subset.contains::<Body, Vec<u8>>());
+        assert!(map.contains::<Session, uint>());
+    }
+
+    #[test] fn test_get_all() {
+        let mut map = TypeMap::new();
+        map.insert::<Session, uint>(7);
+        map.insert::<Body, Vec<u8>>(vec![1]);
+
+        let (session, user, body) = map.get_all::<((Session, uint), (User, String), (Body, Vec<u8>)), _>();
+        assert_eq!(session, Some(&7));
+        assert_eq!(user, None);
+        assert_eq!(body, Some(&vec![1]));
+    }
+}
@@ -0,0 +1,69 @@
+//! Deferred removal of entries, for code which cannot remove them at the
+//! point it decides to, such as while iterating or holding borrows.
+
+use std::collections::HashSet;
+use std::mem;
+
+use super::{TypeMap, Assoc, Storage};
+
+impl<S: Storage> TypeMap<S> {
+    /// Mark the value of a key to be removed by the next `sweep`.
+    ///
+    /// This only needs a shared reference, so it can be called while other
+    /// values of the map are borrowed. The value stays in the map until the
+    /// sweep, even if it is replaced in between.
+    pub fn mark_for_removal<K: Assoc<V>, V: 'static>(&self) {
+        self.marked.borrow_mut().insert(self.slot::<K>());
+    }
+
+    /// Check if a key is marked for removal.
+    pub fn is_marked<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.marked.borrow().contains(&self.slot::<K>())
+    }
+
+    /// Remove the values of all keys marked for removal, and clear the
+    /// marks. Frozen values are not removed.
+    ///
+    /// Returns the number of values removed.
+    pub fn sweep(&mut self) -> uint {
+        let marked = mem::replace(&mut *self.marked.borrow_mut(), HashSet::new());
+        let mut removed = 0;
+        for id in marked.iter() {
+            if self.evict_id(*id).is_some() { removed += 1 }
+        }
+        self.debug_check();
+        removed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {TypeMap, Assoc};
+
+    struct Session;
+
+    struct Config;
+
+    impl Assoc<uint> for Session {}
+    impl Assoc<uint> for Config {}
+
+    #[test] fn test_sweep() {
+        let mut map = TypeMap::new();
+        map.insert::<Session, uint>(1);
+        map.insert::<Config, uint>(2);
+
+        {
+            let config = map.find::<Config, uint>().unwrap();
+            map.mark_for_removal::<Session, uint>();
+            assert_eq!(*config, 2);
+        }
+        assert!(map.is_marked::<Session, uint>());
+        assert!(map.contains::<Session, uint>());
+
+        assert_eq!(map.sweep(), 1);
+        assert!(!map.contains::<Session, uint>());
+        assert!(!map.is_marked::<Session, uint>());
+        assert_eq!(map.sweep(), 0);
+        assert!(map.contains::<Config, uint>());
+    }
+}
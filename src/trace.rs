@@ -0,0 +1,49 @@
+//! Instrumentation of map operations, enabled by the `trace` feature.
+//!
+//! Each traced operation emits a `debug!` event of the form
+//! `typemap op=<operation> key=<key type> ns=<duration>` when it finishes.
+
+#[cfg(feature = "trace")]
+use time;
+
+/// A guard timing a single map operation, logging it when dropped.
+#[cfg(feature = "trace")]
+pub struct Trace {
+    op: &'static str,
+    key: &'static str,
+    start: u64
+}
+
+/// A guard timing a single map operation, which does nothing.
+#[cfg(not(feature = "trace"))]
+pub struct Trace;
+
+#[cfg(feature = "trace")]
+impl Trace {
+    /// Start timing an operation on a key type.
+    pub fn start<K: 'static>(op: &'static str) -> Trace {
+        Trace { op: op, key: ::type_name::<K>(), start: time::precise_time_ns() }
+    }
+
+    /// Rename the operation being timed, e.g. when an insert replaces a value.
+    pub fn rename(&mut self, op: &'static str) {
+        self.op = op;
+    }
+}
+
+#[cfg(not(feature = "trace"))]
+impl Trace {
+    #[inline(always)]
+    pub fn start<K: 'static>(_: &'static str) -> Trace { Trace }
+
+    #[inline(always)]
+    pub fn rename(&mut self, _: &'static str) {}
+}
+
+#[cfg(feature = "trace")]
+impl Drop for Trace {
+    fn drop(&mut self) {
+        debug!("typemap op={} key={} ns={}", self.op, self.key,
+               time::precise_time_ns() - self.start);
+    }
+}
@@ -0,0 +1,204 @@
+//! Groups of mutations which are rolled back on failure.
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::intrinsics::TypeId;
+use std::mem;
+
+use downcast::UncheckedAnyDowncast;
+
+use super::{TypeMap, Assoc, Storage};
+
+/// A view of a map inside `TypeMap::scope`, through which mutations can
+/// be undone.
+///
+/// Mutations are applied to the map immediately, the way the map's own
+/// methods apply them, remembering the original value of every key they
+/// touch. Unless the scope commits, the original values are restored when
+/// the transaction is dropped.
+pub struct Transaction<'a, S: 'a> {
+    map: &'a mut TypeMap<S>,
+    undo: Vec<(TypeId, Option<Box<Any + 'static>>)>,
+    touched: HashSet<TypeId>,
+    committed: bool
+}
+
+impl<S: Storage> TypeMap<S> {
+    /// Run a closure which mutates the map through a Transaction.
+    ///
+    /// If the closure returns `Err` or panics, every mutation it made is
+    /// rolled back; otherwise its mutations are kept.
+    pub fn scope<R, E>(&mut self, f: |&mut Transaction<S>| -> Result<R, E>) -> Result<R, E> {
+        let mut transaction = Transaction {
+            map: self,
+            undo: Vec::new(),
+            touched: HashSet::new(),
+            committed: false
+        };
+
+        let result = f(&mut transaction);
+        transaction.committed = result.is_ok();
+        result
+    }
+}
+
+impl<'a, S: Storage> Transaction<'a, S> {
+    /// Insert a value into the map with a specified key type.
+    ///
+    /// Panics, like `TypeMap::insert`, if the key is frozen or the map is
+    /// full.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        self.map.record::<K>(|stats| stats.insert());
+        let id = self.map.slot::<K>();
+        self.map.check_insert::<K>(&id);
+        let val = self.map.free.boxed(val);
+        let old = match self.map.store_id(id, val) {
+            Ok(old) => old,
+            Err(_) => unreachable!()
+        };
+        self.map.expected.record::<V>(id);
+        self.map.debug_check();
+        let new = old.is_none();
+        self.keep::<V>(id, old);
+        new
+    }
+
+    /// Find a value in the map and get a reference to it.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        self.map.find::<K, V>()
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    ///
+    /// The value is cloned the first time it is mutated, so it can be
+    /// restored on rollback. Returns `None` if the key is frozen.
+    pub fn find_mut<K: Assoc<V>, V: Clone + 'static>(&mut self) -> Option<&mut V> {
+        let id = self.map.slot::<K>();
+        if self.map.is_frozen_id(&id) { return None }
+        if !self.touched.contains(&id) {
+            let original = self.map.data.find(&id).map(|v| {
+                let v = unsafe { v.downcast_ref_unchecked::<V>() };
+                box v.clone() as Box<Any + 'static>
+            });
+            self.save(id, original);
+        }
+        self.map.find_mut::<K, V>()
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.map.contains::<K, V>()
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed. Frozen values are not removed.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        let id = self.map.slot::<K>();
+        let old = self.map.take_id(id);
+        let removed = old.is_some();
+        if removed { self.map.record::<K>(|stats| stats.remove()) }
+        self.map.debug_check();
+        self.keep::<V>(id, old);
+        removed
+    }
+
+    // Remember a replaced or removed value for rollback if it is the
+    // original value of its key, and recycle it otherwise.
+    fn keep<V: 'static>(&mut self, id: TypeId, old: Option<Box<Any + 'static>>) {
+        if !self.touched.contains(&id) { return self.save(id, old) }
+        match old {
+            Some(old) => unsafe { self.map.free.release::<V>(old) },
+            None => ()
+        }
+    }
+
+    fn save(&mut self, id: TypeId, original: Option<Box<Any + 'static>>) {
+        if self.touched.insert(id) {
+            self.undo.push((id, original));
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, S: Storage> Drop for Transaction<'a, S> {
+    fn drop(&mut self) {
+        if self.committed { return }
+
+        let undo = mem::replace(&mut self.undo, Vec::new());
+        for (id, original) in undo.into_iter().rev() {
+            match original {
+                // Restoring the original entries never overfills the map.
+                Some(original) => match self.map.store_id(id, original) {
+                    Ok(Some(_)) => self.map.record_id(&id, |stats| stats.discard()),
+                    _ => ()
+                },
+                None => if self.map.take_id(id).is_some() { self.map.record_id(&id, |stats| stats.discard()) }
+            }
+        }
+        self.map.debug_check();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::intrinsics::TypeId;
+    use {TypeMap, Assoc};
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct OtherKey;
+
+    impl Assoc<uint> for Key {}
+    impl Assoc<uint> for OtherKey {}
+
+    #[test] fn test_commit() {
+        let mut map = TypeMap::new();
+        let result: Result<(), ()> = map.scope(|m| {
+            m.insert::<Key, uint>(1);
+            Ok(())
+        });
+        assert!(result.is_ok());
+        assert_eq!(*map.find::<Key, uint>().unwrap(), 1);
+    }
+
+    #[test] fn test_rollback_on_err() {
+        let mut map = TypeMap::new();
+        map.insert::<Key, uint>(1);
+        let result: Result<(), &str> = map.scope(|m| {
+            m.remove::<Key, uint>();
+            m.insert::<Key, uint>(2);
+            m.insert::<OtherKey, uint>(3);
+            *m.find_mut::<Key, uint>().unwrap() += 1;
+            Err("failed")
+        });
+        assert_eq!(result, Err("failed"));
+        assert_eq!(*map.find::<Key, uint>().unwrap(), 1);
+        assert!(!map.contains::<OtherKey, uint>());
+    }
+
+    #[test] fn test_stats() {
+        let mut map = TypeMap::with_stats();
+        let _: Result<(), ()> = map.scope(|m| {
+            m.insert::<Key, uint>(1);
+            m.find::<Key, uint>();
+            m.remove::<Key, uint>();
+            Ok(())
+        });
+        let report = map.stats().unwrap();
+        let stats = report.find(&TypeId::of::<Key>()).unwrap();
+        assert_eq!((stats.hits, stats.inserts, stats.removals), (1, 1, 1));
+    }
+
+    #[test] #[should_fail] fn test_max_entries() {
+        let mut map = TypeMap::new();
+        map.set_max_entries(Some(1));
+        map.insert::<Key, uint>(1);
+        let _: Result<(), ()> = map.scope(|m| {
+            m.insert::<OtherKey, uint>(2);
+            Ok(())
+        });
+    }
+}
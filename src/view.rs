@@ -0,0 +1,80 @@
+//! Views of stored values as trait objects, so one value can be found
+//! under each of the interfaces it implements without being inserted once
+//! for each.
+//!
+//! Views are registered per key with the `register_views!` macro, which
+//! generates the casts from the concrete value type.
+
+use std::any::Any;
+use std::intrinsics::TypeId;
+use std::mem;
+use std::raw::TraitObject;
+
+use super::{TypeMap, Assoc, Storage};
+
+/// A function viewing a value, whose concrete type it knows, as a trait
+/// object.
+///
+/// These are generated by `register_views!`.
+pub type ViewCast = fn(&(Any + 'static)) -> TraitObject;
+
+impl<S: Storage> TypeMap<S> {
+    /// Register a view of the values of a key as the trait object type `T`.
+    ///
+    /// This is unsafe because `cast` must take a value of type `V` and
+    /// return a trait object of type `T`; `register_views!` generates
+    /// such casts.
+    pub unsafe fn register_view<K: Assoc<V>, V: 'static, Sized? T: 'static>(&mut self, cast: ViewCast) {
+        let id = self.slot::<K>();
+        self.views.insert((id, TypeId::of::<&'static T>()), cast);
+    }
+
+    /// Find the value of a key and view it as the trait object type `T`.
+    ///
+    /// Returns `None` if the key has no value, or registered no view as `T`.
+    pub fn find_view<K: 'static, Sized? T: 'static>(&self) -> Option<&T> {
+        let id = self.slot::<K>();
+        let cast = match self.views.find(&(id, TypeId::of::<&'static T>())) {
+            Some(&cast) => cast,
+            None => return None
+        };
+        self.data.find(&id).map(|val| unsafe {
+            let object = cast(&**val);
+            mem::transmute_copy::<TraitObject, &T>(&object)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Show;
+    use {TypeMap, Assoc};
+
+    trait Database {
+        fn name(&self) -> &'static str;
+    }
+
+    #[deriving(Show)]
+    struct Postgres;
+
+    impl Database for Postgres {
+        fn name(&self) -> &'static str { "postgres" }
+    }
+
+    struct Pool;
+
+    impl Assoc<Postgres> for Pool {}
+
+    #[test] fn test_views() {
+        let mut map = TypeMap::new();
+        map.insert::<Pool, Postgres>(Postgres);
+        assert!(map.find_view::<Pool, Database + 'static>().is_none());
+
+        register_views!(map, Pool => Postgres: Database + 'static, Show + 'static);
+        assert_eq!(map.find_view::<Pool, Database + 'static>().unwrap().name(), "postgres");
+        assert_eq!(format!("{}", map.find_view::<Pool, Show + 'static>().unwrap()), "Postgres".to_string());
+
+        map.remove::<Pool, Postgres>();
+        assert!(map.find_view::<Pool, Database + 'static>().is_none());
+    }
+}
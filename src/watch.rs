@@ -0,0 +1,148 @@
+//! A TypeMap which notifies subscribers when values change.
+
+use std::collections::{hashmap, HashMap};
+use std::comm::{channel, Sender, Receiver};
+use std::intrinsics::TypeId;
+
+use super::{TypeMap, Assoc};
+
+/// A change made to the value of a key.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Change {
+    /// A value was inserted for a key which had none.
+    Inserted,
+    /// The value of a key was replaced.
+    Replaced,
+    /// The value of a key was removed.
+    Removed
+}
+
+/// A TypeMap which sends a `Change` to the subscribers of a key whenever
+/// its value is inserted, replaced, or removed.
+///
+/// Changes made in place through `find_mut` are not reported.
+pub struct WatchedTypeMap {
+    map: TypeMap,
+    subscribers: HashMap<TypeId, Vec<Sender<Change>>>
+}
+
+impl WatchedTypeMap {
+    /// Create a new, empty WatchedTypeMap.
+    pub fn new() -> WatchedTypeMap {
+        WatchedTypeMap {
+            map: TypeMap::new(),
+            subscribers: HashMap::new()
+        }
+    }
+
+    /// Subscribe to changes to the value of a key.
+    ///
+    /// Dropping the receiver unsubscribes.
+    pub fn subscribe<K: Assoc<V>, V: 'static>(&mut self) -> Receiver<Change> {
+        let (tx, rx) = channel();
+        match self.subscribers.entry(TypeId::of::<K>()) {
+            hashmap::Occupied(mut e) => e.get_mut().push(tx),
+            hashmap::Vacant(e) => { e.set(vec![tx]); }
+        }
+        rx
+    }
+
+    /// Insert a value into the map with a specified key type.
+    pub fn insert<K: Assoc<V>, V: 'static>(&mut self, val: V) -> bool {
+        let new = self.map.insert::<K, V>(val);
+        self.notify(TypeId::of::<K>(), if new { Inserted } else { Replaced });
+        new
+    }
+
+    /// Find a value in the map and get a reference to it.
+    pub fn find<K: Assoc<V>, V: 'static>(&self) -> Option<&V> {
+        self.map.find::<K, V>()
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    pub fn find_mut<K: Assoc<V>, V: 'static>(&mut self) -> Option<&mut V> {
+        self.map.find_mut::<K, V>()
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: Assoc<V>, V: 'static>(&self) -> bool {
+        self.map.contains::<K, V>()
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns `true` if a value was removed.
+    pub fn remove<K: Assoc<V>, V: 'static>(&mut self) -> bool {
+        let removed = self.map.remove::<K, V>();
+        if removed { self.notify(TypeId::of::<K>(), Removed) }
+        removed
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> uint {
+        self.map.len()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Remove all entries from the map, notifying the subscribers of each.
+    pub fn clear(&mut self) {
+        let removed: Vec<TypeId> = unsafe { self.map.data() }.keys().map(|&id| id).collect();
+        self.map.clear();
+        for id in removed.into_iter() { self.notify(id, Removed) }
+    }
+
+    fn notify(&mut self, id: TypeId, change: Change) {
+        let empty = match self.subscribers.find_mut(&id) {
+            Some(subscribers) => {
+                subscribers.retain(|tx| tx.send_opt(change.clone()).is_ok());
+                subscribers.is_empty()
+            },
+            None => return
+        };
+        if empty { self.subscribers.remove(&id); }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{WatchedTypeMap, Inserted, Replaced, Removed};
+    use Assoc;
+
+    #[deriving(Show, PartialEq)]
+    struct Key;
+
+    #[deriving(Show, PartialEq)]
+    struct OtherKey;
+
+    #[deriving(Show, PartialEq)]
+    struct Value;
+
+    impl Assoc<Value> for Key {}
+    impl Assoc<Value> for OtherKey {}
+
+    #[test] fn test_subscribe() {
+        let mut map = WatchedTypeMap::new();
+        let rx = map.subscribe::<Key, Value>();
+        map.insert::<Key, Value>(Value);
+        map.insert::<Key, Value>(Value);
+        map.insert::<OtherKey, Value>(Value);
+        map.remove::<Key, Value>();
+        map.remove::<Key, Value>();
+
+        assert_eq!(rx.recv(), Inserted);
+        assert_eq!(rx.recv(), Replaced);
+        assert_eq!(rx.recv(), Removed);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test] fn test_unsubscribe_on_drop() {
+        let mut map = WatchedTypeMap::new();
+        drop(map.subscribe::<Key, Value>());
+        map.insert::<Key, Value>(Value);
+        assert!(map.subscribers.is_empty());
+    }
+}